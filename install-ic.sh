@@ -5,9 +5,12 @@ dfx canister create --all --ic
 
 dfx build --ic
 
-dfx canister install fiduciary --ic
-
 export FIDUCIARY_ID=$(dfx canister id fiduciary --ic)
+export CUSTODY_ID=$(dfx canister id custody_wallet --ic)
+
+dfx canister install fiduciary --ic --argument="(opt record {
+  custody_ids = opt vec { principal \"${CUSTODY_ID}\" };
+})"
 
 dfx canister install custody_wallet --ic --argument="(record {
   bitcoin_network = variant { testnet };
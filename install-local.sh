@@ -6,8 +6,11 @@ dfx canister create --all
 dfx build
 
 export FIDUCIARY_ID=$(dfx canister id fiduciary)
+export CUSTODY_ID=$(dfx canister id custody_wallet)
 
-dfx canister install fiduciary
+dfx canister install fiduciary --argument="(opt record {
+  custody_ids = opt vec { principal \"${CUSTODY_ID}\" };
+})"
 
 dfx canister install custody_wallet --argument="(record {
   bitcoin_network = variant { regtest };
@@ -1,149 +1,2399 @@
 use multisig_common::{
     common,
-    types::{BitcoinNetwork, SendRequest, RawTransactionInfo},
+    types::{BitcoinNetwork, SendRequest, SendRequestReply, SendRequestError, SigningSession, WalletVerification, CacheMetrics, InputSigningError, Inheritance, Allowlist, AllowlistEvent, PauseState, Proposal, SharedWalletId, SharedWalletInfo, SendRequestProposal, Role, AdminAction, AdminState, AuditEntry, WalletSummary, KeyNameValidation, MainnetReadiness, Pairing, BatchedWithdrawal, WithdrawalStatus, Ledger, LedgerEntry, LedgerBalance, LedgerReconciliation, Account, TransferArg, TransferError, MetadataValue, StandardRecord, WalletDescriptors, ECDSAPublicKeyReply},
 };
+use bitcoin::psbt::Psbt;
 use ic_cdk::api;
 use ic_cdk_macros::{init, post_upgrade, pre_upgrade, update, query};
 use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
 
 thread_local! {
-    // The bitcoin network to connect to.
+    // The ECDSA key names set at init, upgrade or by the controllers, by network,
+    // in place of the default ones.
+    static KEY_NAMES: RefCell<HashMap<BitcoinNetwork, String>> = RefCell::default();
+
+    // The results of the test calls made with the key names to the ECDSA API.
+    static KEY_NAME_VALIDATIONS: RefCell<Vec<KeyNameValidation>> = RefCell::default();
+
+    // The result of the check of the subnets, once the bitcoin mainnet is enabled.
+    static MAINNET_READINESS: RefCell<Option<MainnetReadiness>> = RefCell::default();
+
+    // The pairings with the fiduciary canisters of the networks, by fiduciary canister.
+    static PAIRINGS: RefCell<HashMap<candid::Principal, Pairing>> = RefCell::default();
+
+    // The custody wallets, by bitcoin network, each with its own key name and fiduciary
+    // canister. A network is enabled at init or with `update_configuration`.
     //
     // When developing locally this should be `Regtest`.
-    // When deploying to the IC this should be `Testnet`.
-    // `Mainnet` is currently unsupported.
-    static NETWORK: Cell<BitcoinNetwork> = Cell::new(BitcoinNetwork::Testnet);
+    // When deploying to the IC this should be `Testnet`, or `Mainnet` once the
+    // canisters are installed on different subnets, see `check_network_ready`.
+    static CUSTODY_WALLETS: RefCell<BTreeMap<BitcoinNetwork, common::CustodyData>> = RefCell::default();
+
+    // The signing sessions of the send requests, by request ID.
+    static SIGNING_SESSIONS: RefCell<BTreeMap<u64, SigningSession>> = RefCell::default();
+
+    // The ID of the next send request.
+    static NEXT_REQUEST_ID: Cell<u64> = Cell::new(0);
+
+    // The IDs of the send requests currently being signed.
+    static SIGNING_IN_PROGRESS: RefCell<HashSet<u64>> = RefCell::default();
+
+    // The heirs of the wallets, by network and owner.
+    static INHERITANCES: RefCell<BTreeMap<BitcoinNetwork, HashMap<candid::Principal, Inheritance>>> = RefCell::default();
+
+    // The allowlists of destination addresses of the wallets, by network and owner.
+    static ALLOWLISTS: RefCell<BTreeMap<BitcoinNetwork, HashMap<candid::Principal, Allowlist>>> = RefCell::default();
+
+    // The log of the changes made to the allowlists, by network.
+    static ALLOWLIST_EVENTS: RefCell<BTreeMap<BitcoinNetwork, Vec<AllowlistEvent>>> = RefCell::default();
+
+    // The withdrawals paused by the operators and controllers.
+    static PAUSE_STATE: RefCell<PauseState> = RefCell::default();
+
+    // The operators and the audit log of the admin actions.
+    static ADMIN_STATE: RefCell<AdminState> = RefCell::default();
+
+    // The withdrawals queued by the owners of the wallets, by withdrawal ID. The queued
+    // withdrawals of each network are sent in batches, see `process_withdrawal_batch`.
+    static WITHDRAWALS: RefCell<BTreeMap<u64, BatchedWithdrawal>> = RefCell::default();
+
+    // The ID of the next withdrawal.
+    static NEXT_WITHDRAWAL_ID: Cell<u64> = Cell::new(0);
+
+    // The networks whose batch of withdrawals is being processed, with the time it started.
+    static BATCHES_IN_PROGRESS: RefCell<HashMap<BitcoinNetwork, u64>> = RefCell::default();
+
+    // The internal ledgers of the transfers between users, by network. The adjustments of
+    // the balances are settled on chain by the batches of withdrawals.
+    static LEDGERS: RefCell<BTreeMap<BitcoinNetwork, Ledger>> = RefCell::default();
+
+    // The balances on chain of the user wallets as last fetched, by network and owner, for
+    // the queries of the token interface. They are refreshed periodically for the token
+    // network, see `schedule_onchain_balance_refresh`, and kept across upgrades.
+    static ONCHAIN_BALANCES: RefCell<BTreeMap<(BitcoinNetwork, candid::Principal), u64>> = RefCell::default();
+    static TOKEN_NETWORK: RefCell<Option<BitcoinNetwork>> = RefCell::default();
+}
+
+// Interval between two batches of the queued withdrawals of a network, i.e. roughly one block.
+const WITHDRAWAL_BATCH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Interval between two refreshes of the balances on chain of the wallets of the token
+// network, i.e. roughly one block.
+const ONCHAIN_BALANCE_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Time after which a batch still in progress is considered interrupted, e.g. by a trap,
+// so that the withdrawals still queued are batched again.
+const WITHDRAWAL_BATCH_TIMEOUT_NS: u64 = 60 * 60 * 1_000_000_000;
+
+// The number of decimals of the token interface, i.e. of satoshis in a bitcoin.
+const TOKEN_DECIMALS: u8 = 8;
+
+// The window in which the transfers of the token interface are deduplicated, and the
+// drift permitted between the clocks of the clients and of this canister.
+const TRANSACTION_WINDOW_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT_NS: u64 = 2 * 60 * 1_000_000_000;
+
+// The codes of the generic errors of the token interface.
+const FIDUCIARY_ERROR_CODE: u64 = 1;
+const SUBACCOUNT_ERROR_CODE: u64 = 2;
+const INVALID_TRANSFER_ERROR_CODE: u64 = 3;
+const PAUSED_ERROR_CODE: u64 = 4;
+const NETWORK_ERROR_CODE: u64 = 5;
+
+#[derive(Clone, Debug, candid::Deserialize, candid::CandidType)]
+pub struct InitArguments {
+    pub bitcoin_network: BitcoinNetwork,
+    pub fiduciary_id: candid::Principal,
+    pub ecdsa_key_names: Option<Vec<(BitcoinNetwork, String)>>,
+    pub max_amount_per_request: Option<u64>,
+}
+
+#[derive(Clone, Debug, candid::Deserialize, candid::CandidType)]
+pub struct Configuration {
+    pub bitcoin_network: BitcoinNetwork,
+    pub key_name: String,
+    pub fiduciary_id: candid::Principal,
+    pub max_amount_per_request: Option<u64>,
+}
+
+#[init]
+pub fn init(args: InitArguments) {
+    set_key_names(args.ecdsa_key_names.unwrap_or_default());
+    apply_configuration(Configuration {
+        bitcoin_network: args.bitcoin_network,
+        key_name: get_key_name(args.bitcoin_network),
+        fiduciary_id: args.fiduciary_id,
+        max_amount_per_request: args.max_amount_per_request,
+    });
+    schedule_withdrawal_batches();
+    schedule_onchain_balance_refresh();
+}
+
+// Enable the network of the given configuration, with the given key name and fiduciary
+// canister and an empty custody wallet. The key name is then validated with a test
+// call to the ECDSA API. The bitcoin mainnet must also pass its interlocks, see
+// `check_network_ready`.
+fn apply_configuration(configuration: Configuration) {
+
+    if configuration.bitcoin_network == BitcoinNetwork::Mainnet {
+        common::check_mainnet_configuration(&configuration.key_name, configuration.max_amount_per_request);
+        check_mainnet_subnets(configuration.fiduciary_id);
+    }
+
+    validate_key_name(configuration.bitcoin_network, configuration.key_name.clone());
+    PAIRINGS.with(|pairings| pairings.borrow_mut().remove(&configuration.fiduciary_id));
+    ic_cdk_timers::set_timer(Duration::ZERO, move || ic_cdk::spawn(async move {
+        pair_with_fiduciary(configuration.fiduciary_id).await;
+    }));
+
+    let mut custody_wallet = common::CustodyData::new(
+        configuration.bitcoin_network,
+        configuration.key_name.clone(),
+        configuration.fiduciary_id
+    );
+    custody_wallet.max_amount_per_request = configuration.max_amount_per_request;
+
+    KEY_NAMES.with(|key_names| {
+        key_names.borrow_mut().insert(configuration.bitcoin_network, configuration.key_name);
+    });
+
+    // The token is backed by the first network enabled, see `icrc1_name`.
+    TOKEN_NETWORK.with(|network| {
+        network.borrow_mut().get_or_insert(configuration.bitcoin_network);
+    });
+
+    let previous_wallets = CUSTODY_WALLETS.with(|wallets| {
+        wallets.borrow().get(&configuration.bitcoin_network).map(common::wallet_descriptors)
+    });
+
+    set_custody_data(custody_wallet);
+
+    if let Some(descriptors) = previous_wallets {
+        keep_wallets(configuration.bitcoin_network, descriptors);
+    }
+}
+
+// Keep the wallets of the given descriptors on the given network, which must still have
+// the key name and fiduciary canister they were derived with: their addresses hold the
+// funds, and would change with other keys. The configuration of a network without
+// wallets can change freely.
+fn keep_wallets(bitcoin_network: BitcoinNetwork, descriptors: WalletDescriptors) {
+    CUSTODY_WALLETS.with(|wallets| {
+        let mut wallets = wallets.borrow_mut();
+        let wallet = wallets.get_mut(&bitcoin_network)
+            .unwrap_or_else(|| panic!("The network {:?} is not enabled.", bitcoin_network));
+        if wallet.key_name != descriptors.key_name || wallet.fiduciary_canister != descriptors.fiduciary_id {
+            if !descriptors.user_wallets.is_empty() || !descriptors.shared_wallets.is_empty() {
+                panic!(
+                    "The key name and fiduciary canister of the network {:?} cannot change while it has wallets, derived with the key name {} and the fiduciary canister {}.",
+                    bitcoin_network, descriptors.key_name, descriptors.fiduciary_id
+                );
+            }
+            return;
+        }
+        common::restore_wallets(wallet, descriptors);
+    });
+}
+
+
+/// Returns the bitcoin networks enabled on this canister.
+#[query]
+pub async fn get_networks() -> Vec<BitcoinNetwork> {
+    CUSTODY_WALLETS.with(|wallets| wallets.borrow().keys().cloned().collect())
+}
+
+// Get a copy of the custody wallet of the given network.
+fn get_custody_data(bitcoin_network: BitcoinNetwork) -> common::CustodyData {
+    let mut custody_data = CUSTODY_WALLETS.with(|wallets| wallets.borrow().get(&bitcoin_network).cloned())
+        .unwrap_or_else(|| panic!("The network {:?} is not enabled.", bitcoin_network));
+    // The version of the protocol is the one negotiated by the last pairing, so that the
+    // custody data kept across calls to other canisters never holds a stale one.
+    custody_data.fiduciary_protocol_version = PAIRINGS.with(|pairings| {
+        pairings.borrow().get(&custody_data.fiduciary_canister).and_then(|pairing| pairing.negotiated_version)
+    })
+    .unwrap_or(common::MIN_PROTOCOL_VERSION);
+    custody_data
+}
+
+// Check that the given network is enabled.
+fn check_network(bitcoin_network: BitcoinNetwork) {
+    if !CUSTODY_WALLETS.with(|wallets| wallets.borrow().contains_key(&bitcoin_network)) {
+        panic!("The network {:?} is not enabled.", bitcoin_network);
+    }
+}
+
+// Check that the given network is enabled and paired with its fiduciary canister, and
+// that the bitcoin mainnet passed its interlocks: its key name was validated, and the
+// fiduciary canister runs on another subnet than this canister.
+fn check_network_ready(bitcoin_network: BitcoinNetwork) {
+    let fiduciary_id = get_custody_data(bitcoin_network).fiduciary_canister;
+    match PAIRINGS.with(|pairings| pairings.borrow().get(&fiduciary_id).cloned()) {
+        Some(Pairing { error: None, .. }) => {},
+        Some(Pairing { error: Some(error), .. }) => panic!("The network {:?} is not paired: {}", bitcoin_network, error),
+        None => panic!("The network {:?} is not paired: the fiduciary canister {} is being paired.", bitcoin_network, fiduciary_id),
+    }
+    if bitcoin_network != BitcoinNetwork::Mainnet {
+        return;
+    }
+    let key_name_error = KEY_NAME_VALIDATIONS.with(|validations| {
+        validations.borrow().iter()
+            .find(|validation| validation.bitcoin_network == bitcoin_network)
+            .map(|validation| validation.error.clone())
+    });
+    match key_name_error {
+        Some(None) => {},
+        Some(Some(error)) => panic!("The bitcoin mainnet is not ready: {}", error),
+        None => panic!("The bitcoin mainnet is not ready: its key name is being validated."),
+    }
+    match MAINNET_READINESS.with(|readiness| readiness.borrow().clone()) {
+        Some(MainnetReadiness { error: None, .. }) => {},
+        Some(MainnetReadiness { error: Some(error), .. }) => panic!("The bitcoin mainnet is not ready: {}", error),
+        None => panic!("The bitcoin mainnet is not ready: the subnets are being checked."),
+    }
+}
+
+/// Returns the result of the check of the subnets of this canister and of the fiduciary
+/// canister, made once the bitcoin mainnet is enabled.
+#[query]
+pub fn get_mainnet_readiness() -> Option<MainnetReadiness> {
+    MAINNET_READINESS.with(|readiness| readiness.borrow().clone())
+}
+
+// Check that the given fiduciary canister runs on another subnet than this canister, and
+// record the result. Init and post-upgrade cannot call other canisters, so the call is
+// made from a timer.
+fn check_mainnet_subnets(fiduciary_id: candid::Principal) {
+    MAINNET_READINESS.with(|readiness| readiness.replace(None));
+    ic_cdk_timers::set_timer(Duration::ZERO, move || ic_cdk::spawn(async move {
+        let readiness = common::check_mainnet_subnets(fiduciary_id).await;
+        if let Some(error) = &readiness.error {
+            ic_cdk::print(error);
+        }
+        MAINNET_READINESS.with(|state| state.replace(Some(readiness)));
+    }));
+}
+
+/// Returns the pairings of this canister with the fiduciary canisters of the networks,
+/// along with the protocol version spoken by each canister.
+#[query]
+pub fn get_pairing() -> Vec<Pairing> {
+    PAIRINGS.with(|pairings| pairings.borrow().values().cloned().collect())
+}
+
+/// Pairs this canister again with the fiduciary canister of the given network, e.g. once
+/// the fiduciary canister registered this canister. Operators and controllers.
+#[update]
+pub async fn pair_fiduciary(bitcoin_network: BitcoinNetwork) -> Pairing {
+    let fiduciary_id = get_custody_data(bitcoin_network).fiduciary_canister;
+    log_admin_action(Role::Operator, AdminAction::PairingRequested { canister_id: fiduciary_id });
+    pair_with_fiduciary(fiduciary_id).await
+}
+
+// Pair this canister with the given fiduciary canister, and record the result.
+async fn pair_with_fiduciary(fiduciary_id: candid::Principal) -> Pairing {
+    let pairing = common::pair_with_fiduciary(fiduciary_id).await;
+    if let Some(error) = &pairing.error {
+        ic_cdk::print(error);
+    }
+    PAIRINGS.with(|pairings| {
+        pairings.borrow_mut().insert(fiduciary_id, pairing.clone());
+    });
+    pairing
+}
+
+// Keep the given custody wallet for its network, in place of the previous one.
+fn set_custody_data(custody_data: common::CustodyData) {
+    CUSTODY_WALLETS.with(|wallets| {
+        wallets.borrow_mut().insert(custody_data.network, custody_data);
+    });
+}
+
+// Get a copy of the custody wallet of the given network, to update it across calls to
+// other canisters and merge it back with `merge_custody_data`. The cache metrics of the
+// copy start from zero, so that they only count its own hits and misses.
+fn get_custody_data_to_update(bitcoin_network: BitcoinNetwork) -> common::CustodyData {
+    let mut custody_data = get_custody_data(bitcoin_network);
+    custody_data.cache_metrics = CacheMetrics::default();
+    custody_data
+}
+
+// Merge the given copy of the custody wallet of its network, updated across calls to
+// other canisters, into the one of the network, which may have changed in the meantime.
+fn merge_custody_data(custody_data: common::CustodyData) {
+    CUSTODY_WALLETS.with(|wallets| {
+        if let Some(wallet) = wallets.borrow_mut().get_mut(&custody_data.network) {
+            wallet.merge(custody_data);
+        }
+    });
+}
+
+// Get the root public key of the custody wallet of the given network, fetching it
+// from the ECDSA API if it is not cached yet.
+async fn get_root_public_key(bitcoin_network: BitcoinNetwork) -> ECDSAPublicKeyReply {
+    let mut custody_data = get_custody_data_to_update(bitcoin_network);
+    let root_public_key = common::root_public_key(&mut custody_data).await;
+    merge_custody_data(custody_data);
+    root_public_key
+}
+
+#[query]
+pub async fn get_ecdsa_key_name(bitcoin_network: BitcoinNetwork) -> String {
+    get_key_name(bitcoin_network)
+}
+
+/// Returns the results of the test calls made to the ECDSA API with the key name
+/// of the network, after the installation, the upgrades and the configuration updates.
+#[query]
+pub fn get_key_name_validations() -> Vec<KeyNameValidation> {
+    KEY_NAME_VALIDATIONS.with(|validations| validations.borrow().clone())
+}
+
+// Set the given key names in place of the default ones.
+fn set_key_names(key_names: Vec<(BitcoinNetwork, String)>) {
+    KEY_NAMES.with(|state| {
+        let mut state = state.borrow_mut();
+        for (bitcoin_network, key_name) in key_names {
+            if key_name.is_empty() {
+                panic!("The ECDSA key name of {:?} cannot be empty.", bitcoin_network);
+            }
+            state.insert(bitcoin_network, key_name);
+        }
+    });
+}
+
+// Make a test call to the ECDSA API with the given key name and record the result.
+// Init and post-upgrade cannot call other canisters, so the call is made from a timer.
+// The root public key obtained is cached for the wallets of the network.
+fn validate_key_name(bitcoin_network: BitcoinNetwork, key_name: String) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || ic_cdk::spawn(async move {
+        let result = common::validate_key_name(key_name.clone()).await;
+        let error = match result {
+            Ok(root_public_key) => {
+                CUSTODY_WALLETS.with(|wallets| {
+                    if let Some(wallet) = wallets.borrow_mut().get_mut(&bitcoin_network) {
+                        if wallet.key_name == key_name {
+                            wallet.root_public_key = Some(root_public_key);
+                        }
+                    }
+                });
+                None
+            },
+            Err(error) => {
+                ic_cdk::print(&error);
+                Some(error)
+            },
+        };
+        KEY_NAME_VALIDATIONS.with(|validations| {
+            let mut validations = validations.borrow_mut();
+            validations.retain(|validation| validation.bitcoin_network != bitcoin_network);
+            validations.push(KeyNameValidation { bitcoin_network, key_name, error });
+        });
+    }));
+}
+
+/// Returns the balance of the given bitcoin address, which must be valid for the given network.
+#[update]
+pub async fn get_balance(bitcoin_network: BitcoinNetwork, address: String) -> u64 {
+    check_network(bitcoin_network);
+    common::get_balance(bitcoin_network, address).await
+}
+
+#[update]
+pub async fn get_wallet_address(bitcoin_network: BitcoinNetwork) -> String {
+    check_network_ready(bitcoin_network);
+    let principal = &api::caller();
+    let mut custody_wallet = get_custody_data_to_update(bitcoin_network);
+    let address = common::get_or_create_wallet(&mut custody_wallet, *principal, None, None, None).await;
+    merge_custody_data(custody_wallet);
+    address.to_string()
+}
+
+/// Returns the address of a 2-of-3 multisig wallet made of the custody key, the
+/// fiduciary key and the given compressed public key held by the caller, so that
+/// the caller can spend its funds with the signature of only one of the canisters.
+#[update]
+pub async fn get_self_custody_wallet_address(bitcoin_network: BitcoinNetwork, user_public_key: Vec<u8>) -> String {
+    check_network_ready(bitcoin_network);
+    let principal = &api::caller();
+    let mut custody_wallet = get_custody_data_to_update(bitcoin_network);
+    let address = common::get_or_create_wallet(&mut custody_wallet, *principal, Some(user_public_key), None, None).await;
+    merge_custody_data(custody_wallet);
+    address.to_string()
+}
+
+/// Returns the address of a 2-of-2 multisig wallet made of the custody key and the
+/// fiduciary key, with a recovery path: the given compressed public key held by the
+/// caller can spend alone the funds that have not been moved for `recovery_delay`
+/// blocks. See `init_refresh_request` to reset the timer.
+#[update]
+pub async fn get_recovery_wallet_address(bitcoin_network: BitcoinNetwork, user_public_key: Vec<u8>, recovery_delay: u16) -> String {
+    check_network_ready(bitcoin_network);
+    let principal = &api::caller();
+    let mut custody_wallet = get_custody_data_to_update(bitcoin_network);
+    let address = common::get_or_create_wallet(&mut custody_wallet, *principal, Some(user_public_key), Some(recovery_delay), None).await;
+    merge_custody_data(custody_wallet);
+    address.to_string()
+}
+
+/// Returns the address of a wallet defined by the given miniscript policy, where
+/// the keys are named `custody`, `fiduciary` and `user` (the given compressed public
+/// key held by the caller, if any), e.g. `or(and(pk(custody),pk(fiduciary)),and(pk(user),older(25920)))`.
+/// The policy must use both the custody and fiduciary keys.
+#[update]
+pub async fn get_policy_wallet_address(bitcoin_network: BitcoinNetwork, policy: String, user_public_key: Option<Vec<u8>>) -> String {
+    check_network_ready(bitcoin_network);
+    let principal = &api::caller();
+    let mut custody_wallet = get_custody_data_to_update(bitcoin_network);
+    let address = common::get_or_create_wallet(&mut custody_wallet, *principal, user_public_key, None, Some(policy)).await;
+    merge_custody_data(custody_wallet);
+    address.to_string()
+}
+
+/// Verifies the caller's wallet against both canisters, to detect any
+/// misconfiguration of the key names before funds are deposited. The custody key
+/// is derived with the key name currently configured for the network, so a wallet
+/// created with another key name does not pass.
+#[update]
+pub async fn verify_wallet(bitcoin_network: BitcoinNetwork) -> WalletVerification {
+    check_network_ready(bitcoin_network);
+    let principal = &api::caller();
+    let custody_data = get_custody_data(bitcoin_network);
+    common::verify_wallet(&custody_data, *principal, get_key_name(bitcoin_network)).await
+}
+
+/// Checks that the public key derived locally for the given derivation path
+/// matches the one returned by the management canister.
+#[update]
+pub async fn check_key_derivation(bitcoin_network: BitcoinNetwork, derivation_path: Vec<Vec<u8>>) -> bool {
+    let root_public_key = get_root_public_key(bitcoin_network).await;
+    common::check_key_derivation(get_key_name(bitcoin_network), &root_public_key, derivation_path).await
+}
+
+/// Initiates a send request from the caller's wallet and inserts the first signature.
+/// If a shared wallet is given, the send request is instead a proposal that is only
+/// signed once enough members approved it, see `approve_send_request`.
+#[update]
+pub async fn init_send_request(bitcoin_network: BitcoinNetwork, send_request: SendRequest) -> Result<SendRequestReply, SendRequestError> {
+    
+    check_network_ready(bitcoin_network);
+    let principal = &api::caller();
+
+    if let Some(shared_wallet) = send_request.shared_wallet.clone() {
+        return propose_send_request(bitcoin_network, *principal, shared_wallet, send_request).await;
+    }
+
+    let mut custody_data = get_custody_data_to_update(bitcoin_network);
+
+    // Build the transaction.
+    let allowlist = get_allowlist_of(bitcoin_network, *principal);
+    let transaction_info = common::build_unsigned_transaction(
+        &mut custody_data,
+        *principal,
+        send_request.destination_address, 
+        send_request.amount_in_satoshi,
+        allowlist.as_ref())
+    .await;
+
+    // Keep the updated caches and wallets.
+    merge_custody_data(custody_data);
+
+    open_send_request(bitcoin_network, *principal, None, None, &transaction_info, send_request.export_psbt.unwrap_or(false)).await
+}
+
+/// Creates a shared wallet named after the given name, owned by the caller and the
+/// given members, and returns its address. Send requests from the wallet must be
+/// approved by the given threshold of members, the proposer included, before the
+/// custody wallet signs them. The members must also be set on the fiduciary canister.
+#[update]
+pub async fn create_shared_wallet(bitcoin_network: BitcoinNetwork, name: String, members: Vec<candid::Principal>, threshold: u64) -> String {
+    check_network_ready(bitcoin_network);
+    let principal = &api::caller();
+    let mut custody_wallet = get_custody_data_to_update(bitcoin_network);
+    let address = common::create_shared_wallet(&mut custody_wallet, *principal, name, members, threshold).await;
+    merge_custody_data(custody_wallet);
+    address.to_string()
+}
+
+/// Returns the address, members and threshold of the given shared wallet.
+/// Only available to the members of the wallet.
+#[query]
+pub fn get_shared_wallet(bitcoin_network: BitcoinNetwork, id: SharedWalletId) -> SharedWalletInfo {
+    let shared_wallet = get_shared_wallet_of_member(bitcoin_network, &id, api::caller());
+    SharedWalletInfo {
+        address: shared_wallet.wallet.address.to_string(),
+        members: shared_wallet.members,
+        threshold: shared_wallet.threshold,
+    }
+}
+
+/// Returns the send requests of the given shared wallet that are not closed yet.
+/// Only available to the members of the wallet.
+#[query]
+pub fn get_shared_wallet_proposals(bitcoin_network: BitcoinNetwork, id: SharedWalletId) -> Vec<SendRequestProposal> {
+    get_shared_wallet_of_member(bitcoin_network, &id, api::caller());
+    SIGNING_SESSIONS.with(|sessions| {
+        sessions.borrow().iter()
+            .filter_map(|(request_id, session)| match &session.proposal {
+                Some(proposal) if session.bitcoin_network == bitcoin_network && proposal.wallet == id => Some(SendRequestProposal {
+                    request_id: *request_id,
+                    raw_transaction_info: session.raw_transaction_info.clone(),
+                    approvals: proposal.approvals.clone(),
+                    rejections: proposal.rejections.clone(),
+                }),
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+/// Approves the given send request of a shared wallet. Once enough members
+/// approved it, the first signature is inserted.
+#[update]
+pub async fn approve_send_request(request_id: u64) -> Result<SendRequestReply, SendRequestError> {
+    vote_send_request(request_id, api::caller(), true);
+    sign_send_request(request_id).await
+}
+
+/// Rejects the given send request of a shared wallet. Once it cannot get enough
+/// approvals anymore, it is closed.
+#[update]
+pub fn reject_send_request(request_id: u64) {
+    vote_send_request(request_id, api::caller(), false);
+}
+
+/// Pauses all the withdrawals, i.e. the signature of send requests. Operators and controllers.
+#[update]
+pub fn pause_withdrawals() {
+    log_admin_action(Role::Operator, AdminAction::WithdrawalsPaused);
+    PAUSE_STATE.with(|state| state.borrow_mut().paused = true);
+}
+
+/// Resumes the withdrawals paused with `pause_withdrawals`. Controllers only.
+#[update]
+pub fn resume_withdrawals() {
+    log_admin_action(Role::Controller, AdminAction::WithdrawalsResumed);
+    PAUSE_STATE.with(|state| state.borrow_mut().paused = false);
+}
+
+/// Pauses or resumes the withdrawals of the wallet with the given derivation path
+/// (i.e. the principal of the owner for user wallets). Operators can pause a wallet,
+/// only controllers can resume it.
+#[update]
+pub fn set_wallet_paused(derivation_path: Vec<Vec<u8>>, paused: bool) {
+    let required_role = if paused { Role::Operator } else { Role::Controller };
+    log_admin_action(required_role, AdminAction::WalletPaused { derivation_path: derivation_path.clone(), paused });
+    PAUSE_STATE.with(|state| common::set_wallet_paused(&mut state.borrow_mut(), derivation_path, paused));
+}
+
+/// Returns the withdrawals currently paused.
+#[query]
+pub fn get_pause_state() -> PauseState {
+    PAUSE_STATE.with(|state| state.borrow().clone())
+}
+
+/// Adds the given principal to the operators of this canister. Controllers only.
+#[update]
+pub fn add_operator(operator: candid::Principal) {
+    log_admin_action(Role::Controller, AdminAction::OperatorAdded { operator });
+    ADMIN_STATE.with(|state| common::add_operator(&mut state.borrow_mut(), operator));
+}
+
+/// Removes the given principal from the operators of this canister. Controllers only.
+#[update]
+pub fn remove_operator(operator: candid::Principal) {
+    log_admin_action(Role::Controller, AdminAction::OperatorRemoved { operator });
+    ADMIN_STATE.with(|state| common::remove_operator(&mut state.borrow_mut(), &operator));
+}
+
+/// Returns the operators of this canister. Operators and controllers.
+#[query]
+pub fn get_operators() -> Vec<candid::Principal> {
+    ADMIN_STATE.with(|state| {
+        let state = state.borrow();
+        common::check_role(&state, &api::caller(), Role::Operator);
+        state.operators.clone()
+    })
+}
+
+/// Returns the role of the caller on this canister, if any.
+#[query]
+pub fn get_role() -> Option<Role> {
+    ADMIN_STATE.with(|state| common::get_role(&state.borrow(), &api::caller()))
+}
+
+/// Returns the audit log of the admin actions. Operators and controllers.
+#[query]
+pub fn get_audit_log() -> Vec<AuditEntry> {
+    ADMIN_STATE.with(|state| {
+        let state = state.borrow();
+        common::check_role(&state, &api::caller(), Role::Operator);
+        state.audit_log.clone()
+    })
+}
+
+/// Returns the key name and fiduciary canister of each network enabled on this canister.
+#[query]
+pub fn get_configurations() -> Vec<Configuration> {
+    CUSTODY_WALLETS.with(|wallets| {
+        wallets.borrow().values()
+            .map(|wallet| Configuration {
+                bitcoin_network: wallet.network,
+                key_name: wallet.key_name.clone(),
+                fiduciary_id: wallet.fiduciary_canister,
+                max_amount_per_request: wallet.max_amount_per_request,
+            })
+            .collect()
+    })
+}
+
+/// Enables the given network, or updates its key name and fiduciary canister. Controllers only.
+/// The key name and fiduciary canister of a network cannot change once it has wallets,
+/// since the addresses of the wallets are derived from them.
+#[update]
+pub fn update_configuration(configuration: Configuration) {
+    log_admin_action(Role::Controller, AdminAction::ConfigurationUpdated {
+        bitcoin_network: configuration.bitcoin_network,
+        key_name: configuration.key_name.clone(),
+        fiduciary_id: Some(configuration.fiduciary_id),
+        max_amount_per_request: configuration.max_amount_per_request,
+    });
+    if !SIGNING_IN_PROGRESS.with(|in_progress| in_progress.borrow().is_empty()) {
+        panic!("Send requests are being signed.");
+    }
+    apply_configuration(configuration);
+}
+
+/// Returns the derivation path, address and pause state of the user and shared
+/// wallets. Operators and controllers.
+#[query]
+pub fn get_wallets(bitcoin_network: BitcoinNetwork) -> Vec<WalletSummary> {
+    ADMIN_STATE.with(|state| common::check_role(&state.borrow(), &api::caller(), Role::Operator));
+    let pause_state = PAUSE_STATE.with(|state| state.borrow().clone());
+    let custody_data = get_custody_data(bitcoin_network);
+    custody_data.user_wallets.values()
+        .chain(custody_data.shared_wallets.values().map(|shared_wallet| &shared_wallet.wallet))
+        .map(|wallet| WalletSummary {
+            derivation_path: wallet.derivation_path.clone(),
+            address: wallet.address.to_string(),
+            paused: pause_state.paused || pause_state.paused_wallets.contains(&wallet.derivation_path),
+        })
+        .collect()
+}
+
+// Check that the caller has at least the given role, then record the given action
+// in the audit log.
+fn log_admin_action(required_role: Role, action: AdminAction) {
+    ADMIN_STATE.with(|state| {
+        common::record_admin_action(&mut state.borrow_mut(), api::caller(), required_role, action);
+    });
+}
+
+/// Enables the allowlist of destination addresses of the caller's wallet. Once enabled,
+/// send requests can only be made to the addresses of the allowlist, which become
+/// active the given cooling-off period after they are added. If the allowlist is
+/// already enabled, the cooling-off period can only be increased.
+#[update]
+pub fn enable_allowlist(bitcoin_network: BitcoinNetwork, cooling_off_period_seconds: u64) {
+    check_network(bitcoin_network);
+    let event = ALLOWLISTS.with(|allowlists| {
+        common::enable_allowlist(allowlists.borrow_mut().entry(bitcoin_network).or_default(), api::caller(), cooling_off_period_seconds, api::time())
+    });
+    log_allowlist_event(bitcoin_network, event);
+}
+
+/// Adds the given destination address to the allowlist of the caller's wallet.
+/// It becomes active after the cooling-off period of the allowlist.
+#[update]
+pub fn add_allowed_address(bitcoin_network: BitcoinNetwork, address: String) {
+    check_network(bitcoin_network);
+    let event = ALLOWLISTS.with(|allowlists| {
+        common::add_allowed_address(allowlists.borrow_mut().entry(bitcoin_network).or_default(), api::caller(), address, bitcoin_network, api::time())
+    });
+    log_allowlist_event(bitcoin_network, event);
+}
+
+/// Removes the given destination address from the allowlist of the caller's wallet.
+#[update]
+pub fn remove_allowed_address(bitcoin_network: BitcoinNetwork, address: String) {
+    check_network(bitcoin_network);
+    let event = ALLOWLISTS.with(|allowlists| {
+        common::remove_allowed_address(allowlists.borrow_mut().entry(bitcoin_network).or_default(), api::caller(), address, bitcoin_network, api::time())
+    });
+    log_allowlist_event(bitcoin_network, event);
+}
+
+/// Disables the allowlist of the caller's wallet, after its cooling-off period.
+/// Calling `enable_allowlist` in the meantime cancels it.
+#[update]
+pub fn disable_allowlist(bitcoin_network: BitcoinNetwork) {
+    check_network(bitcoin_network);
+    let event = ALLOWLISTS.with(|allowlists| {
+        common::disable_allowlist(allowlists.borrow_mut().entry(bitcoin_network).or_default(), api::caller(), api::time())
+    });
+    log_allowlist_event(bitcoin_network, event);
+}
+
+/// Returns the allowlist of destination addresses of the caller's wallet.
+#[query]
+pub fn get_allowlist(bitcoin_network: BitcoinNetwork) -> Option<Allowlist> {
+    get_allowlist_of(bitcoin_network, api::caller())
+}
+
+/// Returns the changes made to the allowlist of the caller's wallet.
+#[query]
+pub fn get_allowlist_events(bitcoin_network: BitcoinNetwork) -> Vec<AllowlistEvent> {
+    let principal = api::caller();
+    ALLOWLIST_EVENTS.with(|events| {
+        events.borrow().get(&bitcoin_network).into_iter().flatten()
+            .filter(|event| event.owner == principal)
+            .cloned()
+            .collect()
+    })
+}
+
+// Get the allowlist of the given owner on the given network, if any.
+fn get_allowlist_of(bitcoin_network: BitcoinNetwork, owner: candid::Principal) -> Option<Allowlist> {
+    ALLOWLISTS.with(|allowlists| {
+        allowlists.borrow().get(&bitcoin_network).and_then(|allowlists| allowlists.get(&owner).cloned())
+    })
+}
+
+// Append the given event to the log of the allowlists of the given network.
+fn log_allowlist_event(bitcoin_network: BitcoinNetwork, event: AllowlistEvent) {
+    ic_cdk::print(format!("Allowlist event on {:?}: {:?}", bitcoin_network, event));
+    ALLOWLIST_EVENTS.with(|events| {
+        events.borrow_mut().entry(bitcoin_network).or_default().push(event);
+    });
+}
+
+/// Sets the heir of the caller's wallet, who can withdraw from it with
+/// `init_inheritance_request` once the caller has not called `check_in` for the
+/// given period. The same heir must be set on the fiduciary canister.
+#[update]
+pub fn set_heir(bitcoin_network: BitcoinNetwork, heir: candid::Principal, inactivity_period_seconds: u64) {
+    check_network(bitcoin_network);
+    INHERITANCES.with(|inheritances| {
+        common::set_heir(inheritances.borrow_mut().entry(bitcoin_network).or_default(), api::caller(), heir, inactivity_period_seconds, api::time());
+    });
+}
+
+/// Removes the heir of the caller's wallet.
+#[update]
+pub fn remove_heir(bitcoin_network: BitcoinNetwork) {
+    INHERITANCES.with(|inheritances| {
+        if let Some(inheritances) = inheritances.borrow_mut().get_mut(&bitcoin_network) {
+            inheritances.remove(&api::caller());
+        }
+    });
+}
+
+/// Records that the caller is still active, which postpones the time from
+/// which its heir can withdraw.
+#[update]
+pub fn check_in(bitcoin_network: BitcoinNetwork) {
+    INHERITANCES.with(|inheritances| {
+        common::check_in(inheritances.borrow_mut().entry(bitcoin_network).or_default(), api::caller(), api::time());
+    });
+}
+
+/// Returns the heir of the given owner, with its last check-in.
+#[query]
+pub fn get_inheritance(bitcoin_network: BitcoinNetwork, owner: candid::Principal) -> Option<Inheritance> {
+    INHERITANCES.with(|inheritances| {
+        inheritances.borrow().get(&bitcoin_network).and_then(|inheritances| inheritances.get(&owner).cloned())
+    })
+}
+
+// Check that the given heir can withdraw from the wallet of the given owner
+// on the given network.
+fn check_heir(bitcoin_network: BitcoinNetwork, owner: candid::Principal, heir: candid::Principal) {
+    INHERITANCES.with(|inheritances| {
+        let inheritances = inheritances.borrow();
+        let empty = HashMap::new();
+        common::check_heir(inheritances.get(&bitcoin_network).unwrap_or(&empty), owner, heir, api::time());
+    });
+}
+
+/// Initiates a send request from the wallet of the given owner, on behalf of its
+/// heir (i.e. the caller), once the owner has not checked in for its inactivity period.
+/// The transaction is then finalized with `finalize_inheritance_request` on the
+/// fiduciary canister.
+#[update]
+pub async fn init_inheritance_request(bitcoin_network: BitcoinNetwork, owner: candid::Principal, send_request: SendRequest) -> Result<SendRequestReply, SendRequestError> {
+
+    check_network_ready(bitcoin_network);
+    let principal = &api::caller();
+    check_heir(bitcoin_network, owner, *principal);
+
+    let mut custody_data = get_custody_data_to_update(bitcoin_network);
+
+    // Build the transaction from the wallet of the owner.
+    let allowlist = get_allowlist_of(bitcoin_network, owner);
+    let transaction_info = common::build_unsigned_transaction(
+        &mut custody_data,
+        owner,
+        send_request.destination_address,
+        send_request.amount_in_satoshi,
+        allowlist.as_ref())
+    .await;
+
+    // Keep the updated caches and wallets.
+    merge_custody_data(custody_data);
+
+    open_send_request(bitcoin_network, owner, Some(*principal), None, &transaction_info, send_request.export_psbt.unwrap_or(false)).await
+}
+
+/// Moves all the funds of the caller's wallet back to the same wallet, which resets
+/// the timer of its recovery path. The request is then handled like a send request.
+#[update]
+pub async fn init_refresh_request(bitcoin_network: BitcoinNetwork) -> Result<SendRequestReply, SendRequestError> {
+
+    check_network_ready(bitcoin_network);
+    let principal = &api::caller();
+    let mut custody_data = get_custody_data_to_update(bitcoin_network);
+
+    // Build the transaction.
+    let transaction_info = common::build_refresh_transaction(
+        &mut custody_data,
+        *principal)
+    .await;
+
+    // Keep the updated caches and wallets.
+    merge_custody_data(custody_data);
+
+    open_send_request(bitcoin_network, *principal, None, None, &transaction_info, false).await
+}
+
+/// Resumes the signature of the given send request, only signing the inputs
+/// that failed to be signed before. If all the inputs are already signed, the
+/// signed transaction is returned again, e.g. to retry the fiduciary canister.
+#[update]
+pub async fn resume_send_request(request_id: u64) -> Result<SendRequestReply, SendRequestError> {
+    check_send_request_owner(request_id, api::caller());
+    sign_send_request(request_id).await
+}
+
+/// Closes the given send request, discarding its signing session.
+#[update]
+pub fn close_send_request(request_id: u64) {
+    check_send_request_owner(request_id, api::caller());
+    if SIGNING_IN_PROGRESS.with(|in_progress| in_progress.borrow().contains(&request_id)) {
+        panic!("The send request {} is being signed.", request_id);
+    }
+    SIGNING_SESSIONS.with(|sessions| {
+        sessions.borrow_mut().remove(&request_id);
+    });
+}
+
+/// Builds the transaction of the given send request and returns it as an unsigned
+/// PSBT (BIP174), for the caller to sign it with its own key. Only available for
+/// 2-of-3 wallets, see `get_self_custody_wallet_address`.
+#[update]
+pub async fn build_send_request_psbt(bitcoin_network: BitcoinNetwork, send_request: SendRequest) -> Vec<u8> {
+
+    check_network_ready(bitcoin_network);
+    let principal = &api::caller();
+    let mut custody_data = get_custody_data_to_update(bitcoin_network);
+
+    match custody_data.user_wallets.get(principal) {
+        Some(wallet) if wallet.user_public_key.is_some() => {},
+        _ => panic!("No self-custody wallet found for the principal {}", principal),
+    }
+
+    // Build the transaction.
+    let allowlist = get_allowlist_of(bitcoin_network, *principal);
+    let transaction_info = common::build_unsigned_transaction(
+        &mut custody_data,
+        *principal,
+        send_request.destination_address,
+        send_request.amount_in_satoshi,
+        allowlist.as_ref())
+    .await;
+
+    // Keep the updated caches and wallets.
+    merge_custody_data(custody_data);
+
+    transaction_info.to_psbt().serialize()
+}
+
+/// Builds a transaction that moves all the funds of the caller's wallet that can be
+/// spent through its recovery path to the given address, and returns it as an unsigned
+/// PSBT (BIP174). The caller signs and finalizes it with its own key, without any
+/// canister. See `get_recovery_wallet_address`.
+#[update]
+pub async fn build_recovery_psbt(bitcoin_network: BitcoinNetwork, destination_address: String) -> Vec<u8> {
+
+    check_network_ready(bitcoin_network);
+    let principal = &api::caller();
+    let mut custody_data = get_custody_data_to_update(bitcoin_network);
+
+    // Build the transaction.
+    let transaction_info = common::build_recovery_transaction(
+        &mut custody_data,
+        *principal,
+        destination_address)
+    .await;
+
+    // Keep the updated caches and wallets.
+    merge_custody_data(custody_data);
+
+    transaction_info.to_psbt().serialize()
+}
+
+/// Co-signs the given PSBT (BIP174) with the custody key and sends the transaction.
+/// The PSBT must spend from the caller's wallet and contain the partial signatures
+/// of another signer (e.g. the key held by the caller) for all its inputs.
+#[update]
+pub async fn finalize_send_request_psbt(bitcoin_network: BitcoinNetwork, psbt: Vec<u8>) -> Result<String, Vec<InputSigningError>> {
+
+    let principal = &api::caller();
+    let custody_data = get_custody_data(bitcoin_network);
+
+    let user_wallet = match custody_data.user_wallets.get(principal) {
+        Some(wallet) => wallet,
+        None => panic!("No wallet found for the principal {}", principal),
+    };
+
+    PAUSE_STATE.with(|state| common::check_not_paused(&state.borrow(), &user_wallet.derivation_path));
+    check_network_ready(bitcoin_network);
+
+    let psbt = Psbt::deserialize(&psbt).expect("Invalid PSBT.");
 
-    // The ECDSA key name.
-    static KEY_NAME: RefCell<String> = RefCell::new(String::from(""));
+    if psbt.inputs.iter().any(|input| input.witness_script.as_ref() != Some(&user_wallet.witness_script)) {
+        panic!("The PSBT must only spend from the wallet of the caller.");
+    }
 
-    // The fiduciary canister.
-    static FIDUCIARY_ID: RefCell<Option<candid::Principal>> = RefCell::new(None);
+    // The PSBT is held to the same limits as the send requests built by this canister.
+    let (unsigned_transaction_info, _) = common::TransactionInfo::from_psbt(&psbt);
+    let allowlist = get_allowlist_of(bitcoin_network, *principal);
+    common::check_psbt_outputs(&unsigned_transaction_info, custody_data.max_amount_per_request, allowlist.as_ref(), bitcoin_network, api::time());
 
-    // The custody wallet.
-    static CUSTODY_WALLET: RefCell<common::CustodyData> = RefCell::default();
-}
+    // The caller cannot spend what it owes on the internal ledger.
+    common::check_ledger_debt(bitcoin_network, &unsigned_transaction_info, get_ledger_debt(bitcoin_network, *principal))
+        .await
+        .unwrap_or_else(|error| panic!("{}", error));
 
-#[derive(Clone, Debug, candid::Deserialize, candid::CandidType)]
-pub struct InitArguments {
-    pub bitcoin_network: BitcoinNetwork,
-    pub fiduciary_id: candid::Principal,
-}
+    // The custody key is derived from the root public key, which is not cached after an
+    // upgrade until the wallets are created again or the key name is validated.
+    let root_public_key = get_root_public_key(bitcoin_network).await;
+    let custody_public_key = common::derive_public_key(&root_public_key, &user_wallet.derivation_path);
 
-#[init]
-pub fn init(args: InitArguments) {
+    // Insert the custody signature along the one of the co-signer.
+    let transaction_info = common::cosign_psbt(
+        &psbt,
+        &custody_data.key_name,
+        &user_wallet.derivation_path,
+        &custody_public_key)
+    .await?;
 
-    let key = get_key_name(args.bitcoin_network);
+    // Send the transaction.
+    common::send_transaction(custody_data.network, &transaction_info).await?;
 
-    let custody_wallet = common::CustodyData::new(
-        args.bitcoin_network,
-        key.clone(),
-        args.fiduciary_id.clone()
-    );
+    // Return the transaction id.
+    Ok(transaction_info.transaction().txid().to_string())
+}
 
-    NETWORK.with(|n| 
-        n.set(args.bitcoin_network)
-    );
+/// Queues a withdrawal of the given amount from the caller's wallet to the given address,
+/// and returns its ID. The queued withdrawals are sent periodically in batches, each one
+/// a single transaction spending from the wallets of several users, in which each
+/// withdrawal pays a share of the fee proportional to its amount. The withdrawal must
+/// also be authorized with `authorize_withdrawal` on the fiduciary canister.
+#[update]
+pub fn queue_withdrawal(bitcoin_network: BitcoinNetwork, destination_address: String, amount_in_satoshi: u64) -> u64 {
 
-    KEY_NAME.with(|key_name| {
-        key_name.replace(key);
+    let principal = api::caller();
+    check_network_ready(bitcoin_network);
+    PAUSE_STATE.with(|state| common::check_not_paused(&state.borrow(), &vec![principal.as_slice().to_vec()]));
+
+    let allowlist = get_allowlist_of(bitcoin_network, principal);
+    common::check_withdrawal(
+        &get_custody_data(bitcoin_network),
+        principal,
+        &destination_address,
+        amount_in_satoshi,
+        allowlist.as_ref());
+
+    let id = NEXT_WITHDRAWAL_ID.with(|id| {
+        let withdrawal_id = id.get();
+        id.set(withdrawal_id + 1);
+        withdrawal_id
     });
 
-    FIDUCIARY_ID.with(|id| {
-        id.replace(Some(args.fiduciary_id));
+    WITHDRAWALS.with(|withdrawals| {
+        withdrawals.borrow_mut().insert(id, BatchedWithdrawal {
+            id,
+            bitcoin_network,
+            owner: principal,
+            destination_address,
+            amount: amount_in_satoshi,
+            queued_at: api::time(),
+            status: WithdrawalStatus::Queued,
+        });
     });
 
-    CUSTODY_WALLET.with(|wallet| {
-        wallet.replace(custody_wallet);
+    id
+}
+
+/// Cancels the given withdrawal of the caller, as long as it is queued.
+#[update]
+pub fn cancel_withdrawal(id: u64) {
+    let principal = api::caller();
+    WITHDRAWALS.with(|withdrawals| {
+        let mut withdrawals = withdrawals.borrow_mut();
+        match withdrawals.get(&id) {
+            Some(withdrawal) if withdrawal.owner == principal => {
+                if withdrawal.status != WithdrawalStatus::Queued {
+                    panic!("The withdrawal {} is not queued anymore.", id);
+                }
+            },
+            _ => panic!("No withdrawal found with the ID {} for the principal {}", id, principal),
+        }
+        withdrawals.remove(&id);
     });
 }
 
+/// Returns the withdrawals of the caller, with their status.
 #[query]
-pub async fn get_network() -> BitcoinNetwork {
-    NETWORK.with(|n| n.get())
+pub fn get_withdrawals() -> Vec<BatchedWithdrawal> {
+    let principal = api::caller();
+    WITHDRAWALS.with(|withdrawals| {
+        withdrawals.borrow().values().filter(|withdrawal| withdrawal.owner == principal).cloned().collect()
+    })
 }
 
+/// Returns the withdrawals of the given network that are queued or being processed.
+/// Operators and controllers.
 #[query]
-pub async fn get_ecdsa_key_name(bitcoin_network: BitcoinNetwork) -> String {
-    String::from(match bitcoin_network {
-        // For local development, we use a special test key with dfx.
-        BitcoinNetwork::Regtest => "dfx_test_key",
-        // On the IC we're using a test ECDSA key.
-        BitcoinNetwork::Mainnet | BitcoinNetwork::Testnet => "test_key_1",
+pub fn get_withdrawal_queue(bitcoin_network: BitcoinNetwork) -> Vec<BatchedWithdrawal> {
+    ADMIN_STATE.with(|state| common::check_role(&state.borrow(), &api::caller(), Role::Operator));
+    WITHDRAWALS.with(|withdrawals| {
+        withdrawals.borrow().values()
+            .filter(|withdrawal| withdrawal.bitcoin_network == bitcoin_network)
+            .filter(|withdrawal| matches!(withdrawal.status, WithdrawalStatus::Queued | WithdrawalStatus::Processing))
+            .cloned()
+            .collect()
     })
 }
 
-/// Returns the balance of the given bitcoin address.
+// Process the queued withdrawals of each network periodically. Each batch is processed
+// from its own timer, so that the failure of a network does not prevent the others.
+fn schedule_withdrawal_batches() {
+    ic_cdk_timers::set_timer_interval(WITHDRAWAL_BATCH_INTERVAL, || {
+        let networks: HashSet<BitcoinNetwork> = WITHDRAWALS.with(|withdrawals| {
+            withdrawals.borrow().values()
+                .filter(|withdrawal| withdrawal.status == WithdrawalStatus::Queued)
+                .map(|withdrawal| withdrawal.bitcoin_network)
+                .collect()
+        });
+        for bitcoin_network in networks {
+            ic_cdk_timers::set_timer(Duration::ZERO, move || ic_cdk::spawn(process_withdrawal_batch(bitcoin_network)));
+        }
+    });
+}
+
+// Send the queued withdrawals of the given network in a single transaction, unless a
+// batch of the network is already in progress. The withdrawals of paused wallets stay
+// queued, and those that cannot be funded fail. The inputs are signed with the derivation
+// path of the wallet they spend from, then the fiduciary canister inserts the last
+// signatures and sends the transaction.
+async fn process_withdrawal_batch(bitcoin_network: BitcoinNetwork) {
+
+    check_network_ready(bitcoin_network);
+
+    let now = api::time();
+    let in_progress = BATCHES_IN_PROGRESS.with(|batches| {
+        batches.borrow().get(&bitcoin_network)
+            .map_or(false, |started_at| now.saturating_sub(*started_at) < WITHDRAWAL_BATCH_TIMEOUT_NS)
+    });
+    if in_progress {
+        return;
+    }
+
+    let pause_state = PAUSE_STATE.with(|state| state.borrow().clone());
+    let mut withdrawals: Vec<BatchedWithdrawal> = WITHDRAWALS.with(|withdrawals| {
+        withdrawals.borrow().values()
+            .filter(|withdrawal| withdrawal.bitcoin_network == bitcoin_network && withdrawal.status == WithdrawalStatus::Queued)
+            .filter(|withdrawal| {
+                let derivation_path = vec![withdrawal.owner.as_slice().to_vec()];
+                !pause_state.paused && !pause_state.paused_wallets.contains(&derivation_path)
+            })
+            .cloned()
+            .collect()
+    });
+    if withdrawals.is_empty() {
+        return;
+    }
+
+    BATCHES_IN_PROGRESS.with(|batches| batches.borrow_mut().insert(bitcoin_network, now));
+
+    // The credits on the internal ledger are settled by the wallets in debt that are not paused.
+    let adjustments: BTreeMap<candid::Principal, i64> = LEDGERS.with(|ledgers| {
+        ledgers.borrow().get(&bitcoin_network)
+            .map(|ledger| ledger.adjustments.iter()
+                .filter(|(principal, adjustment)| {
+                    let derivation_path = vec![principal.as_slice().to_vec()];
+                    **adjustment > 0 || !pause_state.paused_wallets.contains(&derivation_path)
+                })
+                .map(|(principal, adjustment)| (*principal, *adjustment))
+                .collect())
+            .unwrap_or_default()
+    });
+
+    // Build the transaction, without the withdrawals that cannot be funded.
+    let mut custody_data = get_custody_data_to_update(bitcoin_network);
+    let (transaction_info, settlements) = loop {
+        if withdrawals.is_empty() {
+            end_withdrawal_batch(bitcoin_network);
+            return;
+        }
+        match common::build_batch_transaction(&mut custody_data, &withdrawals, &adjustments).await {
+            Ok(batch_transaction) => break batch_transaction,
+            Err((id, error)) => {
+                ic_cdk::print(&format!("Withdrawal {} failed: {}", id, error));
+                set_withdrawal_status(id, WithdrawalStatus::Failed { error });
+                withdrawals.retain(|withdrawal| withdrawal.id != id);
+            },
+        }
+    };
+
+    // Keep the updated caches and wallets.
+    merge_custody_data(custody_data);
+
+    // The withdrawals cancelled in the meantime are left out by the next batch.
+    let cancelled = WITHDRAWALS.with(|state| {
+        let state = state.borrow();
+        withdrawals.iter().any(|withdrawal| state.get(&withdrawal.id).map(|withdrawal| &withdrawal.status) != Some(&WithdrawalStatus::Queued))
+    });
+    if cancelled {
+        end_withdrawal_batch(bitcoin_network);
+        return;
+    }
+    for withdrawal in withdrawals.iter_mut() {
+        withdrawal.status = WithdrawalStatus::Processing;
+        set_withdrawal_status(withdrawal.id, WithdrawalStatus::Processing);
+    }
+
+    // Insert the first signatures, with the derivation path of the wallet of each input.
+    let derivation_paths: Vec<Vec<Vec<u8>>> = transaction_info.inputs()
+        .iter()
+        .map(|input| input.derivation_path.clone().expect("The inputs of a batch have a derivation path."))
+        .collect();
+    let mut signatures = vec![None; transaction_info.inputs().len()];
+    let failed_inputs = common::sign_inputs(
+        &transaction_info,
+        &get_key_name(bitcoin_network),
+        &derivation_paths,
+        &mut signatures)
+    .await;
+
+    if !failed_inputs.is_empty() {
+        // Nothing was sent, the withdrawals are batched again.
+        ic_cdk::print(&format!("Failed to sign the batch of withdrawals: {:?}", failed_inputs));
+        for withdrawal in &withdrawals {
+            set_withdrawal_status(withdrawal.id, WithdrawalStatus::Queued);
+        }
+        end_withdrawal_batch(bitcoin_network);
+        return;
+    }
+
+    let transaction_info = common::insert_signatures(
+        &transaction_info,
+        signatures.into_iter().map(Option::unwrap).collect(),
+        common::MultisigIndex::First);
+
+    // The fee paid by each withdrawal, from the fee of the transaction.
+    let spent: u64 = transaction_info.inputs().iter().map(|input| input.amount.to_sat()).sum();
+    let sent: u64 = transaction_info.transaction().output.iter().map(|output| output.value.to_sat()).sum();
+    let amounts: Vec<u64> = withdrawals.iter().map(|withdrawal| withdrawal.amount).collect();
+    let fees = common::split_fee(spent - sent, &amounts);
+
+    let custody_data = get_custody_data(bitcoin_network);
+    let result = common::send_batch_to_fiduciary(&custody_data, withdrawals.clone(), settlements.clone(), &transaction_info).await;
+
+    // The settlements paid by the transaction cancel the adjustments of the balances.
+    if let Ok(transaction_id) = &result {
+        LEDGERS.with(|ledgers| {
+            let mut ledgers = ledgers.borrow_mut();
+            if let Err(error) = common::book_settlements(ledgers.entry(bitcoin_network).or_default(), &settlements, transaction_id) {
+                ic_cdk::print(format!("Failed to book the settlements of the batch {}: {}", transaction_id, error));
+            }
+        });
+    }
+
+    for (withdrawal, fee) in withdrawals.iter().zip(fees) {
+        set_withdrawal_status(withdrawal.id, match &result {
+            Ok(transaction_id) => WithdrawalStatus::Sent { transaction_id: transaction_id.clone(), fee },
+            Err(error) => WithdrawalStatus::Failed { error: error.clone() },
+        });
+    }
+    if let Err(error) = result {
+        ic_cdk::print(format!("Failed to send the batch of withdrawals: {}", error));
+    }
+
+    end_withdrawal_batch(bitcoin_network);
+}
+
+// Set the status of the given withdrawal, if it was not cancelled.
+fn set_withdrawal_status(id: u64, status: WithdrawalStatus) {
+    WITHDRAWALS.with(|withdrawals| {
+        if let Some(withdrawal) = withdrawals.borrow_mut().get_mut(&id) {
+            withdrawal.status = status;
+        }
+    });
+}
+
+// Record the end of the batch of withdrawals of the given network.
+fn end_withdrawal_batch(bitcoin_network: BitcoinNetwork) {
+    BATCHES_IN_PROGRESS.with(|batches| batches.borrow_mut().remove(&bitcoin_network));
+}
+
+/// Transfers the given amount from the caller's balance to the given principal on the
+/// internal ledger, without any transaction: the balances are only adjusted, and the
+/// adjustments are settled on chain when the receiver withdraws, in the same batch of
+/// withdrawals. The caller's balance is the balance of its wallet, adjusted by the
+/// transfers, minus its withdrawals not sent yet. The caller's wallet must be a 2-of-2
+/// multisig, whose funds both canisters can move to settle its debt. The transfer must
+/// also be authorized with `authorize_transfer` on the fiduciary canister, or fit in
+/// the allowance set with `set_transfer_allowance`.
+#[update]
+pub async fn transfer(bitcoin_network: BitcoinNetwork, to: candid::Principal, amount_in_satoshi: u64) -> Result<LedgerEntry, String> {
+    transfer_balance(bitcoin_network, api::caller(), to, amount_in_satoshi, None, None).await
+        .map_err(|error| match error {
+            TransferError::InsufficientFunds { balance } =>
+                format!("Insufficient balance: {}, trying to transfer {} satoshi", balance, amount_in_satoshi),
+            TransferError::TemporarilyUnavailable => String::from("A batch of withdrawals is in progress, retry later."),
+            TransferError::GenericError { message, .. } => message,
+            error => format!("{:?}", error),
+        })
+}
+
+// Book a transfer of the given amount from the balance of the given sender to the given
+// principal on the internal ledger of the given network, see `transfer`. A transfer with
+// a creation time is only booked once within the transaction window, see `icrc1_transfer`.
+async fn transfer_balance(
+    bitcoin_network: BitcoinNetwork,
+    from: candid::Principal,
+    to: candid::Principal,
+    amount: u64,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> Result<LedgerEntry, TransferError> {
+
+    check_network_ready(bitcoin_network);
+    if let Some(error) = PAUSE_STATE.with(|state| common::pause_error(&state.borrow(), &vec![from.as_slice().to_vec()])) {
+        return Err(transfer_error(PAUSED_ERROR_CODE, error));
+    }
+
+    if to == from {
+        return Err(transfer_error(INVALID_TRANSFER_ERROR_CODE, String::from("The sender and the receiver of a transfer must differ.")));
+    }
+    if amount == 0 || common::ledger_amount(amount).is_err() {
+        return Err(transfer_error(INVALID_TRANSFER_ERROR_CODE, format!("Invalid amount: {} satoshi.", amount)));
+    }
+
+    let custody_data = get_custody_data(bitcoin_network);
+    let user_wallet = match custody_data.user_wallets.get(&from) {
+        Some(wallet) => wallet.clone(),
+        None => return Err(TransferError::InsufficientFunds { balance: candid::Nat::from(0_u64) }),
+    };
+    if user_wallet.user_public_key.is_some() || user_wallet.policy.is_some() {
+        return Err(transfer_error(INVALID_TRANSFER_ERROR_CODE, String::from("Only the 2-of-2 multisig wallets can transfer on the internal ledger.")));
+    }
+    check_transfer_time(created_at_time, api::time())?;
+    check_no_withdrawal_batch(bitcoin_network)?;
+
+    let onchain_balance = refresh_onchain_balance(bitcoin_network, from, user_wallet.address.to_string()).await;
+
+    // A batch may have started in the meantime, with settlements computed from the ledger.
+    check_no_withdrawal_batch(bitcoin_network)?;
+    if created_at_time.is_some() {
+        let duplicate = LEDGERS.with(|ledgers| {
+            ledgers.borrow().get(&bitcoin_network).and_then(|ledger| {
+                ledger.entries.iter()
+                    .find(|entry| entry.from == from && entry.to == to && entry.amount == amount
+                        && entry.memo == memo && entry.created_at_time == created_at_time)
+                    .map(|entry| entry.id)
+            })
+        });
+        if let Some(id) = duplicate {
+            return Err(TransferError::Duplicate { duplicate_of: candid::Nat::from(id) });
+        }
+    }
+    let adjustment = get_ledger_adjustment(bitcoin_network, from);
+    let pending_withdrawals = get_pending_withdrawals(bitcoin_network, from);
+    let balance = common::ledger_balance(onchain_balance, adjustment, pending_withdrawals).unwrap_or_default();
+    if amount > balance {
+        return Err(TransferError::InsufficientFunds { balance: candid::Nat::from(balance) });
+    }
+
+    // The transfer is booked before calling the fiduciary canister, so that it counts
+    // against any concurrent transfer, and reverted if the fiduciary canister refuses it.
+    let entry = LEDGERS.with(|ledgers| {
+        let mut ledgers = ledgers.borrow_mut();
+        let ledger = ledgers.entry(bitcoin_network).or_default();
+        common::book_transfer(ledger, from, to, amount)?;
+        let entry = ledger.entries.last_mut().unwrap();
+        entry.memo = memo;
+        entry.created_at_time = created_at_time;
+        Ok(entry.clone())
+    }).map_err(|error| transfer_error(INVALID_TRANSFER_ERROR_CODE, error))?;
+    if let Err(error) = common::book_fiduciary_transfer(&custody_data, from, to, amount).await {
+        LEDGERS.with(|ledgers| {
+            if let Some(ledger) = ledgers.borrow_mut().get_mut(&bitcoin_network) {
+                common::unbook_transfer(ledger, entry.id);
+            }
+        });
+        return Err(transfer_error(FIDUCIARY_ERROR_CODE, error));
+    }
+
+    Ok(entry)
+}
+
+// Get a generic transfer error with the given code and message.
+fn transfer_error(error_code: u64, message: String) -> TransferError {
+    TransferError::GenericError { error_code: candid::Nat::from(error_code), message }
+}
+
+/// Returns the balance of the caller on the internal ledger, see `transfer`.
 #[update]
-pub async fn get_balance(address: String) -> u64 {
-    let network = NETWORK.with(|n| n.get());
-    common::get_balance(network, address).await
+pub async fn get_ledger_balance(bitcoin_network: BitcoinNetwork) -> LedgerBalance {
+    check_network(bitcoin_network);
+    get_ledger_balance_of(bitcoin_network, api::caller()).await
+}
+
+/// Returns the transfers of the caller on the internal ledger, sent and received,
+/// including the settlements paid on chain.
+#[query]
+pub fn get_ledger_entries(bitcoin_network: BitcoinNetwork) -> Vec<LedgerEntry> {
+    let principal = api::caller();
+    LEDGERS.with(|ledgers| {
+        ledgers.borrow().get(&bitcoin_network)
+            .map(|ledger| ledger.entries.iter()
+                .filter(|entry| entry.from == principal || entry.to == principal)
+                .cloned()
+                .collect())
+            .unwrap_or_default()
+    })
 }
 
+/// Reconciles the internal ledger of the given network with the balances of the wallets
+/// on chain: the adjustments of the balances must sum to zero, no principal may owe more
+/// than it holds, and the wallets of the principals whose balance is adjusted must be
+/// loaded to be settled. Operators and controllers.
 #[update]
-pub async fn get_wallet_address() -> String {
-    let principal = &api::caller();
-    let mut custody_wallet = CUSTODY_WALLET.with(|w| w.borrow().clone());
-    let address = common::get_or_create_wallet(&mut custody_wallet, principal.clone()).await;
-    CUSTODY_WALLET.with(|wallet| {
-        wallet.replace(custody_wallet);
+pub async fn get_ledger_reconciliation(bitcoin_network: BitcoinNetwork) -> LedgerReconciliation {
+    ADMIN_STATE.with(|state| common::check_role(&state.borrow(), &api::caller(), Role::Operator));
+    check_network(bitcoin_network);
+
+    let ledger = LEDGERS.with(|ledgers| ledgers.borrow().get(&bitcoin_network).cloned().unwrap_or_default());
+    let mut principals: Vec<candid::Principal> = get_custody_data(bitcoin_network).user_wallets.keys().cloned().collect();
+    principals.extend(ledger.adjustments.keys().cloned());
+    principals.sort();
+    principals.dedup();
+
+    let mut errors = vec![];
+    let net_adjustment: i128 = ledger.adjustments.values().map(|adjustment| *adjustment as i128).sum();
+    if net_adjustment != 0 {
+        errors.push(format!("The adjustments of the balances sum to {} satoshi instead of zero.", net_adjustment));
+    }
+
+    let mut balances = vec![];
+    for principal in principals {
+        let balance = get_ledger_balance_of(bitcoin_network, principal).await;
+        match (&balance.onchain_balance, &balance.balance) {
+            (None, _) if balance.adjustment != 0 =>
+                errors.push(format!("The wallet of {} is not loaded, its balance cannot be settled.", principal)),
+            (Some(_), None) =>
+                errors.push(format!("{} owes more than its wallet holds.", principal)),
+            _ => {},
+        }
+        balances.push(balance);
+    }
+
+    LedgerReconciliation {
+        bitcoin_network,
+        timestamp: api::time(),
+        onchain_total: balances.iter().filter_map(|balance| balance.onchain_balance).sum(),
+        ledger_total: balances.iter().filter_map(|balance| balance.balance).sum(),
+        balances,
+        errors,
+    }
+}
+
+// Get the balance of the given principal on the internal ledger of the given network.
+// Without a wallet loaded, only the adjustment of its balance is known.
+async fn get_ledger_balance_of(bitcoin_network: BitcoinNetwork, owner: candid::Principal) -> LedgerBalance {
+    let address = get_custody_data(bitcoin_network).user_wallets.get(&owner)
+        .map(|wallet| wallet.address.to_string());
+    let onchain_balance = match &address {
+        Some(address) => Some(refresh_onchain_balance(bitcoin_network, owner, address.clone()).await),
+        None => None,
+    };
+    let adjustment = get_ledger_adjustment(bitcoin_network, owner);
+    let pending_withdrawals = get_pending_withdrawals(bitcoin_network, owner);
+    LedgerBalance {
+        owner,
+        address,
+        onchain_balance,
+        adjustment,
+        balance: onchain_balance.and_then(|onchain_balance| common::ledger_balance(onchain_balance, adjustment, pending_withdrawals)),
+        pending_withdrawals,
+    }
+}
+
+// Get the adjustment of the balance of the given principal on the internal ledger.
+fn get_ledger_adjustment(bitcoin_network: BitcoinNetwork, principal: candid::Principal) -> i64 {
+    LEDGERS.with(|ledgers| {
+        ledgers.borrow().get(&bitcoin_network)
+            .map_or(0, |ledger| common::ledger_adjustment(ledger, &principal))
+    })
+}
+
+// Get the debt of the given principal on the internal ledger, i.e. the funds of its wallet
+// that it cannot spend.
+fn get_ledger_debt(bitcoin_network: BitcoinNetwork, principal: candid::Principal) -> u64 {
+    get_ledger_adjustment(bitcoin_network, principal).min(0).unsigned_abs()
+}
+
+// Get the total amount of the withdrawals of the given principal not sent yet.
+fn get_pending_withdrawals(bitcoin_network: BitcoinNetwork, owner: candid::Principal) -> u64 {
+    WITHDRAWALS.with(|withdrawals| {
+        withdrawals.borrow().values()
+            .filter(|withdrawal| withdrawal.bitcoin_network == bitcoin_network && withdrawal.owner == owner)
+            .filter(|withdrawal| matches!(withdrawal.status, WithdrawalStatus::Queued | WithdrawalStatus::Processing))
+            .map(|withdrawal| withdrawal.amount)
+            .sum()
+    })
+}
+
+// Refresh the balances on chain of the user wallets of the token network periodically,
+// so that the queries of the token interface do not depend on other calls to fetch
+// them. Each balance is fetched from its own timer, so that a failure only skips it.
+fn schedule_onchain_balance_refresh() {
+    ic_cdk_timers::set_timer_interval(ONCHAIN_BALANCE_REFRESH_INTERVAL, || {
+        let bitcoin_network = match get_token_network() {
+            Some(bitcoin_network) => bitcoin_network,
+            None => return,
+        };
+        let addresses: Vec<(candid::Principal, String)> = CUSTODY_WALLETS.with(|wallets| {
+            wallets.borrow().get(&bitcoin_network)
+                .map(|custody_data| custody_data.user_wallets.iter()
+                    .map(|(owner, wallet)| (*owner, wallet.address.to_string()))
+                    .collect())
+                .unwrap_or_default()
+        });
+        for (owner, address) in addresses {
+            ic_cdk_timers::set_timer(Duration::ZERO, move || ic_cdk::spawn(async move {
+                refresh_onchain_balance(bitcoin_network, owner, address).await;
+            }));
+        }
     });
-    address.to_string()
 }
 
+// Get the balance of the wallet of the given owner, at the given address, on chain, and
+// keep it for the queries of the token interface, see `icrc1_balance_of`.
+async fn refresh_onchain_balance(bitcoin_network: BitcoinNetwork, owner: candid::Principal, address: String) -> u64 {
+    let onchain_balance = common::get_balance(bitcoin_network, address).await;
+    ONCHAIN_BALANCES.with(|balances| balances.borrow_mut().insert((bitcoin_network, owner), onchain_balance));
+    onchain_balance
+}
+
+// Check that no batch of withdrawals of the given network is in progress, since its
+// settlements are computed from the internal ledger.
+fn check_no_withdrawal_batch(bitcoin_network: BitcoinNetwork) -> Result<(), TransferError> {
+    if BATCHES_IN_PROGRESS.with(|batches| batches.borrow().contains_key(&bitcoin_network)) {
+        return Err(TransferError::TemporarilyUnavailable);
+    }
+    Ok(())
+}
+
+// Check that the given creation time of a transfer, if any, is within the transaction
+// window at the given time, so that the transfer can be deduplicated.
+fn check_transfer_time(created_at_time: Option<u64>, now: u64) -> Result<(), TransferError> {
+    match created_at_time {
+        Some(created_at_time) if created_at_time.saturating_add(TRANSACTION_WINDOW_NS + PERMITTED_DRIFT_NS) < now =>
+            Err(TransferError::TooOld),
+        Some(created_at_time) if created_at_time > now.saturating_add(PERMITTED_DRIFT_NS) =>
+            Err(TransferError::CreatedInFuture { ledger_time: now }),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the name of the token backed by the balances of the wallets of the token
+/// network, i.e. the first network enabled on this canister, which is kept afterwards.
+/// A token is a satoshi, transferred on the internal ledger, see `transfer`. Until a
+/// network is enabled, the name and symbol are the ones of the bitcoin mainnet, and
+/// the token has no supply.
+#[query]
+pub fn icrc1_name() -> String {
+    String::from(match get_token_network().unwrap_or(BitcoinNetwork::Mainnet) {
+        BitcoinNetwork::Mainnet => "Multisig Bitcoin",
+        BitcoinNetwork::Testnet => "Multisig Testnet Bitcoin",
+        BitcoinNetwork::Regtest => "Multisig Regtest Bitcoin",
+    })
+}
+
+/// Returns the symbol of the token, see `icrc1_name`.
+#[query]
+pub fn icrc1_symbol() -> String {
+    String::from(match get_token_network().unwrap_or(BitcoinNetwork::Mainnet) {
+        BitcoinNetwork::Mainnet => "msBTC",
+        BitcoinNetwork::Testnet => "msTESTBTC",
+        BitcoinNetwork::Regtest => "msREGTESTBTC",
+    })
+}
+
+/// Returns the number of decimals of the token, i.e. of satoshis in a bitcoin.
+#[query]
+pub fn icrc1_decimals() -> u8 {
+    TOKEN_DECIMALS
+}
+
+/// Returns the fee of a transfer, which is free on the internal ledger.
+#[query]
+pub fn icrc1_fee() -> candid::Nat {
+    candid::Nat::from(0u64)
+}
+
+/// Returns the metadata of the token.
+#[query]
+pub fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
+    vec![
+        (String::from("icrc1:name"), MetadataValue::Text(icrc1_name())),
+        (String::from("icrc1:symbol"), MetadataValue::Text(icrc1_symbol())),
+        (String::from("icrc1:decimals"), MetadataValue::Nat(candid::Nat::from(TOKEN_DECIMALS))),
+        (String::from("icrc1:fee"), MetadataValue::Nat(icrc1_fee())),
+    ]
+}
+
+/// Returns the total supply of the token, i.e. the balances on chain of the wallets, as
+/// last fetched, minus the withdrawals not sent yet. The balances are refreshed every
+/// 10 minutes, and by `get_ledger_balance` and the transfers. The transfers do not
+/// change the supply.
+#[query]
+pub fn icrc1_total_supply() -> candid::Nat {
+    let bitcoin_network = match get_token_network() {
+        Some(bitcoin_network) => bitcoin_network,
+        None => return candid::Nat::from(0u64),
+    };
+    let onchain_total: u64 = ONCHAIN_BALANCES.with(|balances| {
+        balances.borrow().iter()
+            .filter(|((network, _), _)| *network == bitcoin_network)
+            .map(|(_, balance)| balance)
+            .sum()
+    });
+    let pending_withdrawals: u64 = WITHDRAWALS.with(|withdrawals| {
+        withdrawals.borrow().values()
+            .filter(|withdrawal| withdrawal.bitcoin_network == bitcoin_network)
+            .filter(|withdrawal| matches!(withdrawal.status, WithdrawalStatus::Queued | WithdrawalStatus::Processing))
+            .map(|withdrawal| withdrawal.amount)
+            .sum()
+    });
+    candid::Nat::from(onchain_total.saturating_sub(pending_withdrawals))
+}
+
+/// Returns the minting account of the token, which has none: the tokens are minted by
+/// deposits to the wallets and burnt by withdrawals, see `queue_withdrawal`.
+#[query]
+pub fn icrc1_minting_account() -> Option<Account> {
+    None
+}
+
+/// Returns the balance of the given account on the internal ledger, see
+/// `get_ledger_balance`, from the balance on chain of its wallet as last fetched, i.e.
+/// at most 10 minutes ago, or by `get_ledger_balance` or a transfer. Each principal has
+/// a single account, with the default subaccount.
+#[query]
+pub fn icrc1_balance_of(account: Account) -> candid::Nat {
+    let bitcoin_network = match get_token_network() {
+        Some(bitcoin_network) if is_default_subaccount(&account.subaccount) => bitcoin_network,
+        _ => return candid::Nat::from(0u64),
+    };
+    let onchain_balance = ONCHAIN_BALANCES.with(|balances| {
+        balances.borrow().get(&(bitcoin_network, account.owner)).cloned().unwrap_or_default()
+    });
+    let adjustment = get_ledger_adjustment(bitcoin_network, account.owner);
+    let pending_withdrawals = get_pending_withdrawals(bitcoin_network, account.owner);
+    candid::Nat::from(common::ledger_balance(onchain_balance, adjustment, pending_withdrawals).unwrap_or_default())
+}
+
+/// Transfers the given amount of tokens from the caller's account to the given account on
+/// the internal ledger, see `transfer`, and returns the ID of the ledger entry. The
+/// transfers with a creation time are deduplicated within a window of 24 hours.
+/// Withdrawals to bitcoin addresses are queued with `queue_withdrawal` instead.
 #[update]
-pub async fn init_send_request(send_request: SendRequest) -> RawTransactionInfo {
-    
-    let principal = &api::caller();
-    let custody_data = CUSTODY_WALLET.with(|w| w.borrow().clone());        
+pub async fn icrc1_transfer(arg: TransferArg) -> Result<candid::Nat, TransferError> {
+    if !is_default_subaccount(&arg.from_subaccount) || !is_default_subaccount(&arg.to.subaccount) {
+        return Err(transfer_error(SUBACCOUNT_ERROR_CODE, String::from("Only the default subaccounts are supported.")));
+    }
+    if arg.fee.as_ref().map_or(false, |fee| *fee != icrc1_fee()) {
+        return Err(TransferError::BadFee { expected_fee: icrc1_fee() });
+    }
+    let amount = match u64::try_from(arg.amount.0.clone()) {
+        Ok(amount) => amount,
+        Err(_) => return Err(transfer_error(INVALID_TRANSFER_ERROR_CODE, format!("Invalid amount: {} satoshi.", arg.amount))),
+    };
+    let bitcoin_network = match get_token_network() {
+        Some(bitcoin_network) => bitcoin_network,
+        None => return Err(transfer_error(NETWORK_ERROR_CODE, String::from("No network enabled."))),
+    };
+    transfer_balance(bitcoin_network, api::caller(), arg.to.owner, amount, arg.memo, arg.created_at_time).await
+        .map(|entry| candid::Nat::from(entry.id))
+}
+
+/// Returns the standards supported by this canister.
+#[query]
+pub fn icrc1_supported_standards() -> Vec<StandardRecord> {
+    vec![StandardRecord {
+        name: String::from("ICRC-1"),
+        url: String::from("https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1"),
+    }]
+}
+
+// Get the network whose balances back the token, if any is enabled, see `icrc1_name`.
+fn get_token_network() -> Option<BitcoinNetwork> {
+    TOKEN_NETWORK.with(|network| *network.borrow())
+}
+
+// Check that the given subaccount is the default one, i.e. none or only zeros.
+fn is_default_subaccount(subaccount: &Option<Vec<u8>>) -> bool {
+    subaccount.as_ref().map_or(true, |subaccount| subaccount.iter().all(|byte| *byte == 0))
+}
+
+// Create a send request from the given shared wallet, approved by its proposer.
+async fn propose_send_request(
+    bitcoin_network: BitcoinNetwork,
+    proposer: candid::Principal,
+    shared_wallet: SharedWalletId,
+    send_request: SendRequest,
+) -> Result<SendRequestReply, SendRequestError> {
+
+    get_shared_wallet_of_member(bitcoin_network, &shared_wallet, proposer);
+
+    let mut custody_data = get_custody_data_to_update(bitcoin_network);
 
     // Build the transaction.
-    let mut transaction_info = common::build_unsigned_transaction(
-        &custody_data,
-        principal.clone(),
-        send_request.destination_address, 
+    let transaction_info = common::build_shared_wallet_transaction(
+        &mut custody_data,
+        &shared_wallet,
+        send_request.destination_address,
         send_request.amount_in_satoshi)
     .await;
 
-    let bitcoin_network = NETWORK.with(|n| n.get());
-    let key_name = get_key_name(bitcoin_network);
+    // Keep the updated caches and wallets.
+    merge_custody_data(custody_data);
+
+    let proposal = Proposal {
+        wallet: shared_wallet,
+        approvals: vec![proposer],
+        rejections: vec![],
+    };
+
+    open_send_request(bitcoin_network, proposer, None, Some(proposal), &transaction_info, send_request.export_psbt.unwrap_or(false)).await
+}
+
+// Record the vote of the given member on the given send request of a shared wallet.
+// A rejected send request is closed once it cannot get enough approvals anymore.
+fn vote_send_request(request_id: u64, member: candid::Principal, approve: bool) {
+
+    let session = SIGNING_SESSIONS.with(|sessions| sessions.borrow().get(&request_id).cloned())
+        .unwrap_or_else(|| panic!("No send request found with the ID {}", request_id));
+    let proposal = session.proposal
+        .unwrap_or_else(|| panic!("The send request {} is not from a shared wallet.", request_id));
+
+    let shared_wallet = get_shared_wallet_of_member(session.bitcoin_network, &proposal.wallet, member);
+
+    if proposal.approvals.contains(&member) || proposal.rejections.contains(&member) {
+        panic!("The principal {} already voted on the send request {}.", member, request_id);
+    }
+    if proposal.approvals.len() as u64 >= shared_wallet.threshold {
+        panic!("The send request {} is already approved.", request_id);
+    }
+
+    let rejections = proposal.rejections.len() as u64 + if approve { 0 } else { 1 };
+    let rejected = shared_wallet.members.len() as u64 - rejections < shared_wallet.threshold;
+
+    SIGNING_SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        if rejected {
+            sessions.remove(&request_id);
+        } else if let Some(proposal) = sessions.get_mut(&request_id).and_then(|session| session.proposal.as_mut()) {
+            match approve {
+                true => proposal.approvals.push(member),
+                false => proposal.rejections.push(member),
+            }
+        }
+    });
+}
+
+// Get the given shared wallet, checking that the given principal is one of its members.
+fn get_shared_wallet_of_member(bitcoin_network: BitcoinNetwork, id: &SharedWalletId, member: candid::Principal) -> common::SharedWallet {
+    let shared_wallet = get_custody_data(bitcoin_network).shared_wallets.get(id).cloned()
+        .unwrap_or_else(|| panic!("No shared wallet named {} found for the principal {}", id.name, id.creator));
+    if !shared_wallet.members.contains(&member) {
+        panic!("The principal {} is not a member of the shared wallet {}.", member, id.name);
+    }
+    shared_wallet
+}
+
+// Open a signing session for the given transaction and insert the first signature.
+// The requester is the principal driving the request if it is not the owner of the wallet.
+// If a proposal is given, the first signature is only inserted once it is approved.
+async fn open_send_request(
+    bitcoin_network: BitcoinNetwork,
+    owner: candid::Principal,
+    requester: Option<candid::Principal>,
+    proposal: Option<Proposal>,
+    transaction_info: &common::TransactionInfo,
+    export_psbt: bool,
+) -> Result<SendRequestReply, SendRequestError> {
+
+    // The owner of a user wallet cannot spend what it owes on the internal ledger.
+    if proposal.is_none() {
+        common::check_ledger_debt(bitcoin_network, transaction_info, get_ledger_debt(bitcoin_network, owner))
+            .await
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    let request_id = NEXT_REQUEST_ID.with(|id| {
+        let request_id = id.get();
+        id.set(request_id + 1);
+        request_id
+    });
+
+    SIGNING_SESSIONS.with(|sessions| {
+        sessions.borrow_mut().insert(request_id, SigningSession {
+            bitcoin_network,
+            owner,
+            raw_transaction_info: transaction_info.to_raw(),
+            signatures: vec![None; transaction_info.inputs().len()],
+            export_psbt,
+            requester,
+            proposal,
+        });
+    });
 
     // Insert the first signature.
-    transaction_info = common::sign_transaction(
+    sign_send_request(request_id).await
+}
+
+// Sign the inputs of the given send request that are not signed yet.
+// The signatures obtained are kept in the signing session even if some inputs
+// fail to be signed, so that a retry continues from where it stopped.
+async fn sign_send_request(request_id: u64) -> Result<SendRequestReply, SendRequestError> {
+
+    let session = SIGNING_SESSIONS.with(|sessions| sessions.borrow().get(&request_id).cloned())
+        .unwrap_or_else(|| panic!("No send request found with the ID {}", request_id));
+
+    // An heir can only withdraw as long as the owner remains inactive.
+    if let Some(requester) = session.requester {
+        check_heir(session.bitcoin_network, session.owner, requester);
+    }
+
+    // A proposal is only signed once enough members approved it.
+    let derivation_path = match &session.proposal {
+        Some(proposal) => {
+            let shared_wallet = get_custody_data(session.bitcoin_network).shared_wallets.get(&proposal.wallet).cloned()
+                .unwrap_or_else(|| panic!("No shared wallet named {} found for the principal {}", proposal.wallet.name, proposal.wallet.creator));
+            let pending_approvals = shared_wallet.threshold.saturating_sub(proposal.approvals.len() as u64);
+            if pending_approvals > 0 {
+                return Ok(SendRequestReply {
+                    request_id,
+                    raw_transaction_info: session.raw_transaction_info,
+                    psbt: None,
+                    pending_approvals: Some(pending_approvals),
+                });
+            }
+            common::shared_wallet_derivation_path(&proposal.wallet)
+        },
+        None => vec![session.owner.as_slice().to_vec()],
+    };
+
+    PAUSE_STATE.with(|state| common::check_not_paused(&state.borrow(), &derivation_path));
+    check_network_ready(session.bitcoin_network);
+
+    if !SIGNING_IN_PROGRESS.with(|in_progress| in_progress.borrow_mut().insert(request_id)) {
+        panic!("The send request {} is already being signed.", request_id);
+    }
+
+    let transaction_info = common::TransactionInfo::from_raw(session.raw_transaction_info);
+    transaction_info.check_derivation_path(&derivation_path);
+
+    let key_name = get_key_name(session.bitcoin_network);
+
+    let mut signatures = session.signatures;
+
+    let failed_inputs = common::sign_inputs(
         &transaction_info,
         &key_name,
-        &vec![principal.as_slice().to_vec()],
-        common::MultisigIndex::First)
+        &vec![derivation_path; transaction_info.inputs().len()],
+        &mut signatures)
     .await;
 
+    // Store the signatures obtained.
+    SIGNING_SESSIONS.with(|sessions| {
+        if let Some(session) = sessions.borrow_mut().get_mut(&request_id) {
+            session.signatures = signatures.clone();
+        }
+    });
+
+    SIGNING_IN_PROGRESS.with(|in_progress| {
+        in_progress.borrow_mut().remove(&request_id);
+    });
+
+    if !failed_inputs.is_empty() {
+        return Err(SendRequestError {
+            request_id,
+            failed_inputs,
+        });
+    }
+
+    let signatures: Vec<Vec<u8>> = signatures.into_iter().map(Option::unwrap).collect();
+
+    // Export the PSBT with the first signature as partial signatures, if requested.
+    let psbt = match session.export_psbt {
+        true => {
+            let mut psbt = transaction_info.to_psbt();
+            transaction_info.add_partial_signatures(&mut psbt, 0, &signatures);
+            Some(psbt.serialize())
+        },
+        false => None,
+    };
+
+    // Insert the first signature.
+    let transaction_info = common::insert_signatures(
+        &transaction_info,
+        signatures,
+        common::MultisigIndex::First);
+
     // Return the raw transaction info.
-    transaction_info.to_raw()
+    Ok(SendRequestReply {
+        request_id,
+        raw_transaction_info: transaction_info.to_raw(),
+        psbt,
+        pending_approvals: None,
+    })
+}
+
+// Check that the given principal is the owner of the given send request, or
+// one of the members of the shared wallet it is sent from.
+fn check_send_request_owner(request_id: u64, principal: candid::Principal) {
+    let session = SIGNING_SESSIONS.with(|sessions| sessions.borrow().get(&request_id).cloned())
+        .unwrap_or_else(|| panic!("No send request found with the ID {}", request_id));
+    match session.proposal {
+        Some(proposal) => {
+            get_shared_wallet_of_member(session.bitcoin_network, &proposal.wallet, principal);
+        },
+        None => {
+            if session.requester.unwrap_or(session.owner) != principal {
+                panic!("The send request {} does not belong to the caller.", request_id);
+            }
+        },
+    }
+}
+
+/// Returns the hits and misses of the fiduciary public keys and fee percentiles caches.
+#[query]
+pub fn get_cache_metrics(bitcoin_network: BitcoinNetwork) -> CacheMetrics {
+    get_custody_data(bitcoin_network).cache_metrics
 }
 
+// Get the key name of the given network, as set at init, upgrade or by the controllers,
+// or else the default one.
 fn get_key_name(bitcoin_network: BitcoinNetwork) -> String {
+    if let Some(key_name) = KEY_NAMES.with(|key_names| key_names.borrow().get(&bitcoin_network).cloned()) {
+        return key_name;
+    }
     String::from(match bitcoin_network {
         // For local development, we use a special test key with dfx.
         BitcoinNetwork::Regtest => "dfx_test_key",
-        // On the IC we're using a test ECDSA key.
-        BitcoinNetwork::Mainnet | BitcoinNetwork::Testnet => "test_key_1",
+        // On the IC we're using a test ECDSA key for testnet.
+        BitcoinNetwork::Testnet => "test_key_1",
+        // The mainnet requires the production ECDSA key.
+        BitcoinNetwork::Mainnet => common::PRODUCTION_KEY_NAME,
     })
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    let bitcoin_network = NETWORK.with(|n| n.get());
-    let fiduciary_id = FIDUCIARY_ID.with(|id| id.borrow().clone().unwrap());
-    ic_cdk::storage::stable_save((bitcoin_network, fiduciary_id,)).expect("Saving bitcoin network and fiduciary ID to stable store must succeed.");
+    // The network enabled first is also saved as in the versions with a single network.
+    let networks = CUSTODY_WALLETS.with(|wallets| {
+        wallets.borrow().values()
+            .map(|wallet| (
+                Configuration {
+                    bitcoin_network: wallet.network,
+                    key_name: wallet.key_name.clone(),
+                    fiduciary_id: wallet.fiduciary_canister,
+                    max_amount_per_request: wallet.max_amount_per_request,
+                },
+                wallet.fiduciary_public_keys.clone(),
+            ))
+            .collect::<Vec<_>>()
+    });
+    let (first_network, fiduciary_public_keys) = networks.first().cloned().expect("No network enabled.");
+    let signing_sessions = SIGNING_SESSIONS.with(|sessions| sessions.borrow().clone());
+    let next_request_id = NEXT_REQUEST_ID.with(|id| id.get());
+    let inheritances = INHERITANCES.with(|inheritances| inheritances.borrow().clone());
+    let allowlists = ALLOWLISTS.with(|allowlists| allowlists.borrow().clone());
+    let allowlist_events = ALLOWLIST_EVENTS.with(|events| events.borrow().clone());
+    let pause_state = PAUSE_STATE.with(|state| state.borrow().clone());
+    let key_names = KEY_NAMES.with(|key_names| key_names.borrow().clone());
+    let admin_state = ADMIN_STATE.with(|state| state.borrow().clone());
+    let withdrawals = WITHDRAWALS.with(|withdrawals| withdrawals.borrow().clone());
+    let next_withdrawal_id = NEXT_WITHDRAWAL_ID.with(|id| id.get());
+    let ledgers = LEDGERS.with(|ledgers| ledgers.borrow().clone());
+    let wallets: BTreeMap<BitcoinNetwork, WalletDescriptors> = CUSTODY_WALLETS.with(|wallets| {
+        wallets.borrow().iter()
+            .map(|(bitcoin_network, wallet)| (*bitcoin_network, common::wallet_descriptors(wallet)))
+            .collect()
+    });
+    let token_network = get_token_network();
+    let onchain_balances = ONCHAIN_BALANCES.with(|balances| balances.borrow().clone());
+    // The inheritances and allowlists of each network follow the ones shared by all networks.
+    let no_inheritances: Option<HashMap<candid::Principal, Inheritance>> = None;
+    let no_allowlists: Option<HashMap<candid::Principal, Allowlist>> = None;
+    let no_allowlist_events: Option<Vec<AllowlistEvent>> = None;
+    ic_cdk::storage::stable_save((first_network.bitcoin_network, first_network.fiduciary_id, Some(fiduciary_public_keys), Some(signing_sessions), Some(next_request_id), no_inheritances, no_allowlists, no_allowlist_events, Some(pause_state), Some(key_names), Some(admin_state), Some(networks), Some(withdrawals), Some(next_withdrawal_id), Some(ledgers), Some((wallets, token_network, onchain_balances, Some((inheritances, allowlists, allowlist_events)))),))
+        .expect("Saving networks, fiduciary public keys, signing sessions, inheritances, allowlists, pause state, key names, admin state, withdrawals, ledgers, wallets, token network and balances on chain to stable store must succeed.");
 }
 
+/// The key names of the given arguments, if any, replace the ones of the previous
+/// version. The other arguments are ignored, see `update_configuration`. The signing
+/// sessions of the versions with a single network are not restored.
 #[post_upgrade]
-async fn post_upgrade() {
-    let (bitcoin_network, fiduciary_id) = ic_cdk::storage::stable_restore::<(BitcoinNetwork, candid::Principal)>()
-        .expect("Failed to read bitcoin network and fiduciary ID from stable memory.");
+async fn post_upgrade(args: Option<InitArguments>) {
+    let (bitcoin_network, fiduciary_id, fiduciary_public_keys, signing_sessions, next_request_id, inheritances, allowlists, allowlist_events, pause_state, key_names, admin_state, networks, withdrawals, next_withdrawal_id, ledgers, wallets) = ic_cdk::storage::stable_restore::<(
+        BitcoinNetwork,
+        candid::Principal,
+        Option<HashMap<Vec<Vec<u8>>, Vec<u8>>>,
+        Option<BTreeMap<u64, SigningSession>>,
+        Option<u64>,
+        Option<HashMap<candid::Principal, Inheritance>>,
+        Option<HashMap<candid::Principal, Allowlist>>,
+        Option<Vec<AllowlistEvent>>,
+        Option<PauseState>,
+        Option<HashMap<BitcoinNetwork, String>>,
+        Option<AdminState>,
+        Option<Vec<(Configuration, HashMap<Vec<Vec<u8>>, Vec<u8>>)>>,
+        Option<BTreeMap<u64, BatchedWithdrawal>>,
+        Option<u64>,
+        Option<BTreeMap<BitcoinNetwork, Ledger>>,
+        Option<(
+            BTreeMap<BitcoinNetwork, WalletDescriptors>,
+            Option<BitcoinNetwork>,
+            BTreeMap<(BitcoinNetwork, candid::Principal), u64>,
+            Option<(
+                BTreeMap<BitcoinNetwork, HashMap<candid::Principal, Inheritance>>,
+                BTreeMap<BitcoinNetwork, HashMap<candid::Principal, Allowlist>>,
+                BTreeMap<BitcoinNetwork, Vec<AllowlistEvent>>,
+            )>,
+        )>,
+    )>()
+        .expect("Failed to read networks, fiduciary public keys, signing sessions, inheritances, allowlists, pause state, key names, admin state, withdrawals, ledgers, wallets, token network and balances on chain from stable memory.");
+    let (wallets, token_network, onchain_balances, network_states) = match wallets {
+        Some((wallets, token_network, onchain_balances, network_states)) => (Some(wallets), token_network, onchain_balances, network_states),
+        None => (None, None, BTreeMap::new(), None),
+    };
+
+    KEY_NAMES.with(|state| {
+        state.replace(key_names.unwrap_or_default());
+    });
+    if let Some(args) = args {
+        set_key_names(args.ecdsa_key_names.unwrap_or_default());
+    }
 
-    init({
-        InitArguments {
+    // The versions with a single network only saved the first one.
+    let networks = networks.unwrap_or_else(|| vec![(
+        Configuration {
             bitcoin_network,
+            key_name: get_key_name(bitcoin_network),
             fiduciary_id,
-        }});
+            max_amount_per_request: None,
+        },
+        fiduciary_public_keys.unwrap_or_default(),
+    )]);
+
+    let bitcoin_networks: Vec<BitcoinNetwork> = networks.iter()
+        .map(|(configuration, _)| configuration.bitcoin_network)
+        .collect();
+    for (configuration, fiduciary_public_keys) in networks {
+        let bitcoin_network = configuration.bitcoin_network;
+        apply_configuration(Configuration {
+            key_name: get_key_name(bitcoin_network),
+            ..configuration
+        });
+
+        // The fiduciary public keys are kept across upgrades, but are only valid for the same fiduciary.
+        CUSTODY_WALLETS.with(|wallets| {
+            if let Some(wallet) = wallets.borrow_mut().get_mut(&bitcoin_network) {
+                wallet.fiduciary_public_keys = fiduciary_public_keys;
+            }
+        });
+
+        // The versions before the wallets were saved derive them again on request.
+        if let Some(descriptors) = wallets.as_ref().and_then(|wallets| wallets.get(&bitcoin_network)) {
+            keep_wallets(bitcoin_network, descriptors.clone());
+        }
+    }
+
+    SIGNING_SESSIONS.with(|sessions| {
+        sessions.replace(signing_sessions.unwrap_or_default());
+    });
+
+    NEXT_REQUEST_ID.with(|id| {
+        id.set(next_request_id.unwrap_or_default());
+    });
+
+    // The versions before the inheritances and allowlists were kept by network shared
+    // them across all the networks, so each network enabled starts from them.
+    let (inheritances, allowlists, allowlist_events) = network_states.unwrap_or_else(|| {
+        let inheritances = inheritances.unwrap_or_default();
+        let allowlists = allowlists.unwrap_or_default();
+        let allowlist_events = allowlist_events.unwrap_or_default();
+        (
+            bitcoin_networks.iter().map(|network| (*network, inheritances.clone())).collect(),
+            bitcoin_networks.iter().map(|network| (*network, allowlists.clone())).collect(),
+            bitcoin_networks.iter().map(|network| (*network, allowlist_events.clone())).collect(),
+        )
+    });
+
+    INHERITANCES.with(|state| {
+        state.replace(inheritances);
+    });
+
+    ALLOWLISTS.with(|state| {
+        state.replace(allowlists);
+    });
+
+    ALLOWLIST_EVENTS.with(|state| {
+        state.replace(allowlist_events);
+    });
+
+    PAUSE_STATE.with(|state| {
+        state.replace(pause_state.unwrap_or_default());
+    });
+
+    ADMIN_STATE.with(|state| {
+        state.replace(admin_state.unwrap_or_default());
+    });
+
+    WITHDRAWALS.with(|state| {
+        state.replace(withdrawals.unwrap_or_default());
+    });
+
+    NEXT_WITHDRAWAL_ID.with(|id| {
+        id.set(next_withdrawal_id.unwrap_or_default());
+    });
+
+    LEDGERS.with(|state| {
+        state.replace(ledgers.unwrap_or_default());
+    });
+
+    // The versions without a token network kept are backed by the first network enabled
+    // again above, i.e. the first one saved, which backed their token.
+    if token_network.is_some() {
+        TOKEN_NETWORK.with(|network| network.replace(token_network));
+    }
+
+    ONCHAIN_BALANCES.with(|balances| {
+        balances.replace(onchain_balances);
+    });
+
+    schedule_withdrawal_batches();
+    schedule_onchain_balance_refresh();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Open a signing session of an empty transaction owned by the given principal,
+    // and driven by the given requester if any.
+    fn open_test_session(owner: candid::Principal, requester: Option<candid::Principal>) -> u64 {
+        let request_id = NEXT_REQUEST_ID.with(|id| id.get());
+        NEXT_REQUEST_ID.with(|id| id.set(request_id + 1));
+        SIGNING_SESSIONS.with(|sessions| {
+            sessions.borrow_mut().insert(request_id, SigningSession {
+                owner,
+                raw_transaction_info: multisig_common::types::RawTransactionInfo {
+                    transaction: vec![],
+                    witness_script: vec![],
+                    sig_hashes: vec![],
+                    input_amounts: vec![],
+                    protocol_version: None,
+                    inputs: None,
+                },
+                signatures: vec![],
+                export_psbt: false,
+                requester,
+                proposal: None,
+                bitcoin_network: BitcoinNetwork::Regtest,
+            });
+        });
+        request_id
+    }
+
+    #[test]
+    fn only_the_owner_resumes_a_send_request() {
+        let owner = candid::Principal::from_slice(&[1]);
+        let request_id = open_test_session(owner, None);
+        check_send_request_owner(request_id, owner);
+        assert!(std::panic::catch_unwind(|| check_send_request_owner(request_id, candid::Principal::from_slice(&[2]))).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "No send request found")]
+    fn rejects_an_unknown_send_request() {
+        check_send_request_owner(open_test_session(candid::Principal::from_slice(&[1]), None) + 1, candid::Principal::from_slice(&[1]));
+    }
+
+    #[test]
+    fn only_the_heir_resumes_its_inheritance_request() {
+        let owner = candid::Principal::from_slice(&[1]);
+        let heir = candid::Principal::from_slice(&[2]);
+        let request_id = open_test_session(owner, Some(heir));
+        check_send_request_owner(request_id, heir);
+        assert!(std::panic::catch_unwind(|| check_send_request_owner(request_id, owner)).is_err());
+    }
+
+    // The custody wallet of the given network, if enabled, or else an empty one.
+    fn test_custody_data(bitcoin_network: BitcoinNetwork) -> common::CustodyData {
+        CUSTODY_WALLETS.with(|wallets| wallets.borrow().get(&bitcoin_network).cloned())
+            .unwrap_or_else(|| common::CustodyData::new(bitcoin_network, get_key_name(bitcoin_network), candid::Principal::from_slice(&[9])))
+    }
+
+    // Add a shared wallet of the given members, created by the first one, with the given threshold.
+    fn insert_test_shared_wallet(members: &[candid::Principal], threshold: u64) -> SharedWalletId {
+        let id = SharedWalletId { creator: members[0], name: String::from("test") };
+        let wallet = common::UserWallet {
+            witness_script: bitcoin::ScriptBuf::new(),
+            address: bitcoin::Address::p2wsh(&bitcoin::ScriptBuf::new(), bitcoin::Network::Regtest),
+            derivation_path: common::shared_wallet_derivation_path(&id),
+            user_public_key: None,
+            recovery_delay: None,
+            policy: None,
+        };
+        let mut custody_data = test_custody_data(BitcoinNetwork::Regtest);
+        custody_data.shared_wallets.insert(id.clone(), common::SharedWallet {
+            wallet,
+            members: members.to_vec(),
+            threshold,
+        });
+        set_custody_data(custody_data);
+        id
+    }
+
+    // Open a send request of the given shared wallet, approved by its proposer.
+    fn open_test_proposal(id: &SharedWalletId, proposer: candid::Principal) -> u64 {
+        let request_id = open_test_session(proposer, None);
+        SIGNING_SESSIONS.with(|sessions| {
+            sessions.borrow_mut().get_mut(&request_id).unwrap().proposal = Some(Proposal {
+                wallet: id.clone(),
+                approvals: vec![proposer],
+                rejections: vec![],
+            });
+        });
+        request_id
+    }
+
+    fn get_test_proposal(request_id: u64) -> Option<Proposal> {
+        SIGNING_SESSIONS.with(|sessions| sessions.borrow().get(&request_id).and_then(|session| session.proposal.clone()))
+    }
+
+    #[test]
+    fn approves_a_proposal_up_to_the_threshold() {
+        let members: Vec<candid::Principal> = (1..=3).map(|byte| candid::Principal::from_slice(&[byte])).collect();
+        let id = insert_test_shared_wallet(&members, 2);
+        let request_id = open_test_proposal(&id, members[0]);
+
+        vote_send_request(request_id, members[1], true);
+        assert_eq!(get_test_proposal(request_id).unwrap().approvals, vec![members[0], members[1]]);
+        assert!(std::panic::catch_unwind(|| vote_send_request(request_id, members[2], true)).is_err());
+
+        // Every member can follow the proposal, but not the other principals.
+        check_send_request_owner(request_id, members[2]);
+        assert!(std::panic::catch_unwind(|| check_send_request_owner(request_id, candid::Principal::from_slice(&[4]))).is_err());
+    }
+
+    #[test]
+    fn closes_a_proposal_that_cannot_be_approved_anymore() {
+        let members: Vec<candid::Principal> = (1..=4).map(|byte| candid::Principal::from_slice(&[byte])).collect();
+        let id = insert_test_shared_wallet(&members, 3);
+        let request_id = open_test_proposal(&id, members[0]);
+
+        vote_send_request(request_id, members[1], false);
+        assert_eq!(get_test_proposal(request_id).unwrap().rejections, vec![members[1]]);
+        vote_send_request(request_id, members[2], false);
+        assert!(get_test_proposal(request_id).is_none());
+    }
+
+    #[test]
+    fn members_vote_once_on_a_proposal() {
+        let members: Vec<candid::Principal> = (1..=3).map(|byte| candid::Principal::from_slice(&[byte])).collect();
+        let id = insert_test_shared_wallet(&members, 3);
+        let request_id = open_test_proposal(&id, members[0]);
+
+        assert!(std::panic::catch_unwind(|| vote_send_request(request_id, members[0], true)).is_err());
+        assert!(std::panic::catch_unwind(|| vote_send_request(request_id, candid::Principal::from_slice(&[4]), true)).is_err());
+        vote_send_request(request_id, members[1], false);
+        assert!(std::panic::catch_unwind(|| vote_send_request(request_id, members[1], true)).is_err());
+    }
+
+    #[test]
+    fn replaces_the_default_key_names_by_network() {
+        assert_eq!(get_key_name(BitcoinNetwork::Regtest), "dfx_test_key");
+        set_key_names(vec![(BitcoinNetwork::Testnet, String::from("key_1"))]);
+        assert_eq!(get_key_name(BitcoinNetwork::Testnet), "key_1");
+        assert_eq!(get_key_name(BitcoinNetwork::Mainnet), common::PRODUCTION_KEY_NAME);
+        assert!(std::panic::catch_unwind(|| set_key_names(vec![(BitcoinNetwork::Mainnet, String::new())])).is_err());
+        assert_eq!(get_key_name(BitcoinNetwork::Mainnet), common::PRODUCTION_KEY_NAME);
+    }
+
+    #[test]
+    fn keeps_a_custody_wallet_by_network() {
+        assert!(std::panic::catch_unwind(|| check_network(BitcoinNetwork::Testnet)).is_err());
+        set_custody_data(test_custody_data(BitcoinNetwork::Regtest));
+        set_custody_data(test_custody_data(BitcoinNetwork::Testnet));
+        check_network(BitcoinNetwork::Testnet);
+        assert!(std::panic::catch_unwind(|| get_custody_data(BitcoinNetwork::Mainnet)).is_err());
+
+        assert_eq!(get_custody_data(BitcoinNetwork::Testnet).network, BitcoinNetwork::Testnet);
+        assert_eq!(get_custody_data(BitcoinNetwork::Testnet).key_name, "test_key_1");
+        assert_eq!(CUSTODY_WALLETS.with(|wallets| wallets.borrow().len()), 2);
+    }
+
+    #[test]
+    fn mainnet_is_ready_once_its_key_name_and_subnets_are_checked() {
+        set_custody_data(test_custody_data(BitcoinNetwork::Testnet));
+        set_custody_data(test_custody_data(BitcoinNetwork::Mainnet));
+        insert_test_pairing(None);
+        check_network_ready(BitcoinNetwork::Testnet);
+        assert!(std::panic::catch_unwind(|| check_network_ready(BitcoinNetwork::Mainnet)).is_err());
+
+        KEY_NAME_VALIDATIONS.with(|validations| validations.borrow_mut().push(KeyNameValidation {
+            bitcoin_network: BitcoinNetwork::Mainnet,
+            key_name: String::from(common::PRODUCTION_KEY_NAME),
+            error: None,
+        }));
+        assert!(std::panic::catch_unwind(|| check_network_ready(BitcoinNetwork::Mainnet)).is_err());
+
+        let readiness = |error: Option<String>| MainnetReadiness {
+            custody_subnet: Some(candid::Principal::from_slice(&[1])),
+            fiduciary_subnet: Some(candid::Principal::from_slice(&[2])),
+            error,
+        };
+        MAINNET_READINESS.with(|state| state.replace(Some(readiness(Some(String::from("same subnet"))))));
+        assert!(std::panic::catch_unwind(|| check_network_ready(BitcoinNetwork::Mainnet)).is_err());
+        MAINNET_READINESS.with(|state| state.replace(Some(readiness(None))));
+        check_network_ready(BitcoinNetwork::Mainnet);
+    }
+
+    // Record the pairing with the fiduciary canister of the test custody wallets, failed with the given error if any.
+    fn insert_test_pairing(error: Option<String>) {
+        let fiduciary_id = candid::Principal::from_slice(&[9]);
+        PAIRINGS.with(|pairings| pairings.borrow_mut().insert(fiduciary_id, Pairing {
+            peer: fiduciary_id,
+            protocol_version: common::PROTOCOL_VERSION,
+            peer_protocol_version: Some(common::PROTOCOL_VERSION),
+            negotiated_version: error.is_none().then_some(common::PROTOCOL_VERSION),
+            paired_at: error.is_none().then_some(0),
+            error,
+        }));
+    }
+
+    #[test]
+    fn network_is_ready_once_paired_with_its_fiduciary() {
+        set_custody_data(test_custody_data(BitcoinNetwork::Testnet));
+        assert!(std::panic::catch_unwind(|| check_network_ready(BitcoinNetwork::Testnet)).is_err());
+        insert_test_pairing(Some(String::from("The fiduciary canister speaks the protocol version 2 instead of 1.")));
+        assert!(std::panic::catch_unwind(|| check_network_ready(BitcoinNetwork::Testnet)).is_err());
+        insert_test_pairing(None);
+        check_network_ready(BitcoinNetwork::Testnet);
+    }
+
+    #[test]
+    fn speaks_the_protocol_version_negotiated_with_the_fiduciary() {
+        set_custody_data(test_custody_data(BitcoinNetwork::Testnet));
+        assert_eq!(get_custody_data(BitcoinNetwork::Testnet).fiduciary_protocol_version, common::MIN_PROTOCOL_VERSION);
+        insert_test_pairing(None);
+        assert_eq!(get_custody_data(BitcoinNetwork::Testnet).fiduciary_protocol_version, common::PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn rejects_the_transfers_outside_the_transaction_window() {
+        let now = 10 * TRANSACTION_WINDOW_NS;
+        assert!(check_transfer_time(None, now).is_ok());
+        assert!(check_transfer_time(Some(now - TRANSACTION_WINDOW_NS), now).is_ok());
+        assert!(check_transfer_time(Some(now + PERMITTED_DRIFT_NS), now).is_ok());
+        assert!(matches!(check_transfer_time(Some(now - TRANSACTION_WINDOW_NS - PERMITTED_DRIFT_NS - 1), now), Err(TransferError::TooOld)));
+        assert!(matches!(
+            check_transfer_time(Some(now + PERMITTED_DRIFT_NS + 1), now),
+            Err(TransferError::CreatedInFuture { ledger_time }) if ledger_time == now
+        ));
+    }
+
+    #[test]
+    fn only_the_default_subaccount_holds_the_balance_on_the_ledger() {
+        let owner = candid::Principal::from_slice(&[1]);
+        // The token has no supply until a network is enabled.
+        assert_eq!(icrc1_symbol(), "msBTC");
+        assert_eq!(icrc1_balance_of(Account { owner, subaccount: None }), candid::Nat::from(0u64));
+
+        set_custody_data(test_custody_data(BitcoinNetwork::Regtest));
+        TOKEN_NETWORK.with(|network| network.replace(Some(BitcoinNetwork::Regtest)));
+        ONCHAIN_BALANCES.with(|balances| balances.borrow_mut().insert((BitcoinNetwork::Regtest, owner), 100_000));
+        LEDGERS.with(|ledgers| {
+            ledgers.borrow_mut().entry(BitcoinNetwork::Regtest).or_default().adjustments.insert(owner, -30_000);
+        });
+        WITHDRAWALS.with(|withdrawals| withdrawals.borrow_mut().insert(0, BatchedWithdrawal {
+            id: 0,
+            bitcoin_network: BitcoinNetwork::Regtest,
+            owner,
+            destination_address: String::new(),
+            amount: 20_000,
+            queued_at: 0,
+            status: WithdrawalStatus::Queued,
+        }));
+
+        // The transfers move the balances on the ledger, but not the total supply.
+        assert_eq!(icrc1_balance_of(Account { owner, subaccount: None }), candid::Nat::from(50_000u64));
+        assert_eq!(icrc1_balance_of(Account { owner, subaccount: Some(vec![0; 32]) }), candid::Nat::from(50_000u64));
+        assert_eq!(icrc1_balance_of(Account { owner, subaccount: Some(vec![1; 32]) }), candid::Nat::from(0u64));
+        assert_eq!(icrc1_total_supply(), candid::Nat::from(80_000u64));
+    }
+
+    #[test]
+    fn keeps_the_key_name_and_fiduciary_of_a_network_with_wallets() {
+        let id = insert_test_shared_wallet(&[candid::Principal::from_slice(&[1])], 1);
+        let custody_data = test_custody_data(BitcoinNetwork::Regtest);
+        let descriptors = common::wallet_descriptors(&custody_data);
+        let fiduciary_id = custody_data.fiduciary_canister;
+
+        for (key_name, fiduciary_id) in [(String::from("other_key"), fiduciary_id), (custody_data.key_name.clone(), candid::Principal::from_slice(&[10]))] {
+            set_custody_data(common::CustodyData::new(BitcoinNetwork::Regtest, key_name, fiduciary_id));
+            let descriptors = descriptors.clone();
+            assert!(std::panic::catch_unwind(|| keep_wallets(BitcoinNetwork::Regtest, descriptors)).is_err());
+        }
+
+        // The wallets are restored with the same key name and fiduciary canister.
+        set_custody_data(common::CustodyData::new(BitcoinNetwork::Regtest, custody_data.key_name, fiduciary_id));
+        keep_wallets(BitcoinNetwork::Regtest, descriptors.clone());
+        assert!(test_custody_data(BitcoinNetwork::Regtest).shared_wallets.contains_key(&id));
+
+        // A network without wallets can change them.
+        set_custody_data(common::CustodyData::new(BitcoinNetwork::Regtest, String::from("other_key"), fiduciary_id));
+        keep_wallets(BitcoinNetwork::Regtest, WalletDescriptors { shared_wallets: vec![], ..descriptors });
+    }
+
+    #[test]
+    fn keeps_the_allowlists_of_each_network_apart() {
+        let owner = candid::Principal::from_slice(&[1]);
+        ALLOWLISTS.with(|allowlists| {
+            common::enable_allowlist(allowlists.borrow_mut().entry(BitcoinNetwork::Testnet).or_default(), owner, 3600, 0);
+        });
+        assert!(get_allowlist_of(BitcoinNetwork::Testnet, owner).is_some());
+        assert!(get_allowlist_of(BitcoinNetwork::Mainnet, owner).is_none());
+    }
 }
@@ -1,12 +1,112 @@
 use multisig_common::{
     common, 
-    types::{BitcoinNetwork, RawTransactionInfo},
+    types::{BitcoinNetwork, RawTransactionInfo, ECDSAPublicKeyReply, InputSigningError, Inheritance, SharedWalletId, PauseState, Role, AdminAction, AdminState, AuditEntry, KeyNameValidation, Pairing, PairingReply, ClientPolicy, CustodyClient, ProtocolVersions, FiduciaryRequest, FiduciaryResponse, FiduciaryRequestEnvelope, FiduciaryResponseEnvelope, BatchedWithdrawal, WithdrawalAuthorization, Settlement, Ledger, TransferAuthorization, TransferAllowance, LedgerEntry},
 };
+use bitcoin::psbt::Psbt;
 use ic_cdk::api;
-use ic_cdk_macros::{query, update, init};
+use ic_cdk_macros::{query, update, init, pre_upgrade, post_upgrade};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
 
+thread_local! {
+    // The root public keys and chain codes of this canister, by key name.
+    static ROOT_PUBLIC_KEYS: RefCell<HashMap<String, ECDSAPublicKeyReply>> = RefCell::default();
+
+    // The heirs of the wallets, by network and owner. They are kept independently from the
+    // custody wallet, so that this canister enforces the inactivity period itself.
+    static INHERITANCES: RefCell<BTreeMap<BitcoinNetwork, HashMap<candid::Principal, Inheritance>>> = RefCell::default();
+
+    // The members of the shared wallets. They are set by the creators of the wallets,
+    // so that this canister only signs the send requests finalized by a member.
+    static SHARED_WALLET_MEMBERS: RefCell<HashMap<SharedWalletId, Vec<candid::Principal>>> = RefCell::default();
+
+    // The second factors of the wallets, by owner. The send requests of these wallets
+    // are only signed once approved by the second factor.
+    static SECOND_FACTORS: RefCell<HashMap<candid::Principal, candid::Principal>> = RefCell::default();
+
+    // The send requests waiting for the approval of a second factor, by request ID.
+    static PENDING_SENDS: RefCell<BTreeMap<u64, PendingSend>> = RefCell::default();
+
+    // The ID of the next send request waiting for approval.
+    static NEXT_PENDING_SEND_ID: Cell<u64> = Cell::new(0);
+
+    // The withdrawals paused by the operators and controllers. It is independent from the
+    // pause state of the custody wallet, so that this canister stops co-signing on its own.
+    static PAUSE_STATE: RefCell<PauseState> = RefCell::default();
+
+    // The ECDSA key names set at init, upgrade or by the controllers, by network,
+    // in place of the default ones.
+    static KEY_NAMES: RefCell<HashMap<BitcoinNetwork, String>> = RefCell::default();
+
+    // The results of the test calls made with the key names to the ECDSA API.
+    static KEY_NAME_VALIDATIONS: RefCell<Vec<KeyNameValidation>> = RefCell::default();
+
+    // The operators and the audit log of the admin actions.
+    static ADMIN_STATE: RefCell<AdminState> = RefCell::default();
+
+    // The custody wallet canisters registered by the controllers, with their pairing.
+    // Only these canisters get the public keys of this canister.
+    static CUSTODY_CANISTERS: RefCell<BTreeMap<candid::Principal, Pairing>> = RefCell::default();
+
+    // The policies, quotas and statistics of the registered custody wallet canisters.
+    static CUSTODY_CLIENTS: RefCell<BTreeMap<candid::Principal, CustodyClient>> = RefCell::default();
+
+    // The withdrawals authorized by the owners of the wallets and not batched yet, by owner.
+    // They are kept independently from the queue of the custody wallet, so that this
+    // canister only co-signs the withdrawals of a batch that their owners requested.
+    static WITHDRAWAL_AUTHORIZATIONS: RefCell<HashMap<candid::Principal, Vec<WithdrawalAuthorization>>> = RefCell::default();
+
+    // The internal ledgers of the transfers between users, by custody wallet canister and
+    // network. They are kept independently from the ledgers of the custody wallets, so
+    // that this canister only co-signs the settlements of the debts it booked, and keeps
+    // the funds owed out of the other transactions.
+    static LEDGERS: RefCell<BTreeMap<(candid::Principal, BitcoinNetwork), Ledger>> = RefCell::default();
+
+    // The transfers authorized by the senders and not booked yet, by sender.
+    static TRANSFER_AUTHORIZATIONS: RefCell<HashMap<candid::Principal, Vec<TransferAuthorization>>> = RefCell::default();
+
+    // The amounts the senders allow to transfer to any principal, by sender, for the
+    // transfers not authorized one by one, e.g. through the token interface of the
+    // custody wallet.
+    static TRANSFER_ALLOWANCES: RefCell<HashMap<candid::Principal, Vec<TransferAllowance>>> = RefCell::default();
+}
+
+const NANOSECONDS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// The internal ledgers, the transfer authorizations and allowances, and the inheritances
+// by network, as saved across upgrades.
+type LedgerState = (
+    BTreeMap<(candid::Principal, BitcoinNetwork), Ledger>,
+    HashMap<candid::Principal, Vec<TransferAuthorization>>,
+    Option<HashMap<candid::Principal, Vec<TransferAllowance>>>,
+    Option<BTreeMap<BitcoinNetwork, HashMap<candid::Principal, Inheritance>>>,
+);
+
+#[derive(Clone, Debug, candid::Deserialize, candid::CandidType)]
+pub struct PendingSend {
+    pub owner: candid::Principal,
+    pub bitcoin_network: BitcoinNetwork,
+    pub custody_id: Option<candid::Principal>,
+    pub raw_transaction_info: RawTransactionInfo,
+}
+
+#[derive(Clone, Debug, candid::Deserialize, candid::CandidType)]
+pub struct InitArguments {
+    pub ecdsa_key_names: Option<Vec<(BitcoinNetwork, String)>>,
+    pub custody_ids: Option<Vec<candid::Principal>>,
+}
+
+/// The key names of the given arguments, if any, replace the default ones, and the
+/// custody wallet canisters given are registered.
 #[init]
-pub fn init() {
+pub fn init(args: Option<InitArguments>) {
+    if let Some(args) = args {
+        set_key_names(args.ecdsa_key_names.unwrap_or_default());
+        for canister_id in args.custody_ids.unwrap_or_default() {
+            register_custody_id(canister_id, true);
+        }
+    }
 }
 
 #[query]
@@ -14,43 +114,1518 @@ pub async fn get_ecdsa_key_name(bitcoin_network: BitcoinNetwork) -> String {
     get_key_name(bitcoin_network)
 }
 
+/// Returns the results of the test calls made to the ECDSA API with the key names
+/// set at init, upgrade or by the controllers.
+#[query]
+pub fn get_key_name_validations() -> Vec<KeyNameValidation> {
+    KEY_NAME_VALIDATIONS.with(|validations| validations.borrow().clone())
+}
+
+// Set the given key names in place of the default ones, and validate them.
+fn set_key_names(key_names: Vec<(BitcoinNetwork, String)>) {
+    for (bitcoin_network, key_name) in key_names {
+        check_key_name(bitcoin_network, &key_name);
+        KEY_NAMES.with(|state| {
+            state.borrow_mut().insert(bitcoin_network, key_name.clone());
+        });
+        validate_key_name(bitcoin_network, key_name);
+    }
+}
+
+// Check that the given key name can be used for the given network.
+fn check_key_name(bitcoin_network: BitcoinNetwork, key_name: &str) {
+    if key_name.is_empty() {
+        panic!("The ECDSA key name of {:?} cannot be empty.", bitcoin_network);
+    }
+    if bitcoin_network == BitcoinNetwork::Mainnet && key_name != common::PRODUCTION_KEY_NAME {
+        panic!("The ECDSA key name of the bitcoin mainnet must be {}, not {}.", common::PRODUCTION_KEY_NAME, key_name);
+    }
+    // The fiduciary keys of the wallets of the custody wallet canisters are derived
+    // from the key name, so their addresses would change along.
+    if key_name != get_key_name(bitcoin_network) && CUSTODY_CLIENTS.with(|clients| !clients.borrow().is_empty()) {
+        panic!("The ECDSA key name of {:?} cannot change once custody wallet canisters are registered.", bitcoin_network);
+    }
+}
+
+// Make a test call to the ECDSA API with the given key name and record the result.
+// Init and post-upgrade cannot call other canisters, so the call is made from a timer.
+// The root public key obtained is cached.
+fn validate_key_name(bitcoin_network: BitcoinNetwork, key_name: String) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || ic_cdk::spawn(async move {
+        let error = match common::validate_key_name(key_name.clone()).await {
+            Ok(root_public_key) => {
+                ROOT_PUBLIC_KEYS.with(|keys| {
+                    keys.borrow_mut().insert(key_name.clone(), root_public_key);
+                });
+                None
+            },
+            Err(error) => {
+                ic_cdk::print(&error);
+                Some(error)
+            },
+        };
+        KEY_NAME_VALIDATIONS.with(|validations| {
+            let mut validations = validations.borrow_mut();
+            validations.retain(|validation| validation.bitcoin_network != bitcoin_network);
+            validations.push(KeyNameValidation { bitcoin_network, key_name, error });
+        });
+    }));
+}
+
+/// Returns the public key of this canister for the given derivation path, within the
+/// namespace of the calling custody wallet canister. Registered custody wallet canisters only.
+/// Kept for the custody wallet canisters of the first version of the protocol, the next
+/// ones send their requests to `handle_request`.
 #[update]
 pub async fn public_key(network: BitcoinNetwork, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
-    common::ecdsa_public_key(
-        get_key_name(network),
-        derivation_path)
-    .await
+    client_public_key(api::caller(), network, derivation_path).await
 }
 
+/// Handles the given request of a custody wallet canister, in an envelope of a version
+/// of the protocol spoken by this canister. The response is in an envelope of the same
+/// version. Registered custody wallet canisters only.
 #[update]
-pub async fn finalize_send_request(bitcoin_network: BitcoinNetwork, raw_transaction_info: RawTransactionInfo) -> String {
+pub async fn handle_request(envelope: FiduciaryRequestEnvelope) -> FiduciaryResponseEnvelope {
+    let custody_id = api::caller();
+    get_custody_client(&custody_id);
+    let response = match common::check_protocol_version(envelope.protocol_version) {
+        Err(error) => Err(error),
+        Ok(()) => match envelope.request {
+            FiduciaryRequest::PublicKey { bitcoin_network, derivation_path } => Ok(FiduciaryResponse::PublicKey {
+                public_key: client_public_key(custody_id, bitcoin_network, derivation_path).await,
+            }),
+            FiduciaryRequest::EcdsaKeyName { bitcoin_network } => Ok(FiduciaryResponse::EcdsaKeyName {
+                key_name: get_key_name(bitcoin_network),
+            }),
+            FiduciaryRequest::SignBatch { bitcoin_network, withdrawals, raw_transaction_info, settlements } => {
+                sign_batch(custody_id, bitcoin_network, withdrawals, raw_transaction_info, settlements.unwrap_or_default()).await
+                    .map(|transaction_id| FiduciaryResponse::BatchSent { transaction_id })
+            },
+            FiduciaryRequest::BookTransfer { bitcoin_network, from, to, amount } => {
+                book_transfer(custody_id, bitcoin_network, from, to, amount)
+                    .map(|()| FiduciaryResponse::TransferBooked)
+            },
+        },
+    };
+    FiduciaryResponseEnvelope {
+        protocol_version: envelope.protocol_version,
+        response,
+    }
+}
+
+// Get the public key of this canister for the given derivation path of a wallet of the
+// given custody wallet canister.
+async fn client_public_key(custody_id: candid::Principal, network: BitcoinNetwork, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+    let derivation_path = client_derivation_path(&custody_id, derivation_path);
+    let root_public_key = get_root_public_key(get_key_name(network)).await;
+    update_custody_client(&custody_id, |client| client.stats.public_keys += 1);
+    common::derive_public_key(&root_public_key, &derivation_path)
+}
+
+/// Checks that the public key derived locally for the given derivation path
+/// matches the one returned by the management canister.
+#[update]
+pub async fn check_key_derivation(network: BitcoinNetwork, derivation_path: Vec<Vec<u8>>) -> bool {
+    let key_name = get_key_name(network);
+    let root_public_key = get_root_public_key(key_name.clone()).await;
+    common::check_key_derivation(key_name, &root_public_key, derivation_path).await
+}
+
+/// Finalizes a send request of the caller's wallet on the given custody wallet canister.
+#[update]
+pub async fn finalize_send_request(bitcoin_network: BitcoinNetwork, custody_id: candid::Principal, raw_transaction_info: RawTransactionInfo) -> Result<String, Vec<InputSigningError>> {
     
     let principal = &api::caller();
+    check_no_second_factor(principal);
+    check_not_paused(&vec![principal.as_slice().to_vec()]);
     let key_name = get_key_name(bitcoin_network);
     
     // Get the transaction info from the raw one.
     let mut transaction_info = common::TransactionInfo::from_raw(raw_transaction_info);
+    check_ledger_debt(custody_id, bitcoin_network, *principal, &transaction_info).await;
 
-    // Insert the second (and last) signature.
-    transaction_info = common::sign_transaction(
+    // Insert the second (and last) signature, and send the transaction.
+    transaction_info = sign_and_send_client_transaction(
+        custody_id,
+        bitcoin_network,
         &transaction_info,
         &key_name,
-        &vec![principal.as_slice().to_vec()],
+        vec![principal.as_slice().to_vec()])
+        .await?;
+
+    // Return the transaction id.
+    Ok(transaction_info.transaction().txid().to_string())
+}
+
+/// Authorizes the given custody wallet canister to include a withdrawal of the given
+/// amount from the caller's wallet to the given address in a batch of withdrawals,
+/// see `queue_withdrawal` on the custody wallet canister. Each authorization is used
+/// by a single withdrawal of the same network, address and amount. Not available to
+/// the wallets with a second factor.
+#[update]
+pub fn authorize_withdrawal(bitcoin_network: BitcoinNetwork, custody_id: candid::Principal, destination_address: String, amount: u64) {
+    let principal = api::caller();
+    check_no_second_factor(&principal);
+    get_custody_client(&custody_id);
+    WITHDRAWAL_AUTHORIZATIONS.with(|authorizations| {
+        authorizations.borrow_mut().entry(principal).or_default().push(WithdrawalAuthorization {
+            custody_id,
+            bitcoin_network,
+            destination_address,
+            amount,
+        });
+    });
+}
+
+/// Returns the withdrawals authorized by the caller and not batched yet.
+#[query]
+pub fn get_withdrawal_authorizations() -> Vec<WithdrawalAuthorization> {
+    WITHDRAWAL_AUTHORIZATIONS.with(|authorizations| {
+        authorizations.borrow().get(&api::caller()).cloned().unwrap_or_default()
+    })
+}
+
+/// Revokes all the withdrawals authorized by the caller and not batched yet.
+#[update]
+pub fn revoke_withdrawal_authorizations() {
+    WITHDRAWAL_AUTHORIZATIONS.with(|authorizations| {
+        authorizations.borrow_mut().remove(&api::caller());
+    });
+}
+
+// Check the given batch of withdrawals of the given custody wallet canister, each one
+// authorized by its owner, and the settlements of the internal ledger it pays, then
+// insert the second (and last) signature of each input with the key of its owner and
+// send the transaction.
+async fn sign_batch(
+    custody_id: candid::Principal,
+    bitcoin_network: BitcoinNetwork,
+    withdrawals: Vec<BatchedWithdrawal>,
+    raw_transaction_info: RawTransactionInfo,
+    settlements: Vec<Settlement>,
+) -> Result<String, String> {
+
+    let transaction_info = common::TransactionInfo::from_raw(raw_transaction_info);
+    let spendings = common::check_batch_transaction(&transaction_info, &withdrawals, &settlements, bitcoin_network)?;
+
+    for withdrawal in &withdrawals {
+        if withdrawal.bitcoin_network != bitcoin_network {
+            return Err(format!("The withdrawal {} is not on the network {:?}.", withdrawal.id, bitcoin_network));
+        }
+        check_no_second_factor(&withdrawal.owner);
+        check_not_paused(&vec![withdrawal.owner.as_slice().to_vec()]);
+    }
+    for settlement in &settlements {
+        check_not_paused(&vec![settlement.debtor.as_slice().to_vec()]);
+    }
+
+    // The wallets still in debt once settled must keep the funds they owe.
+    let adjustments = settle_ledger(custody_id, bitcoin_network, &settlements)?;
+    for (principal, witness_script, spent) in &spendings {
+        let debt = adjustments.get(principal).map_or(0, |adjustment| adjustment.min(&0).unsigned_abs());
+        if let Some(witness_script) = witness_script {
+            common::check_wallet_debt(bitcoin_network, witness_script, *spent, debt).await?;
+        }
+    }
+
+    // The ledger may have changed in the meantime.
+    settle_ledger(custody_id, bitcoin_network, &settlements)?;
+
+    let amount = transaction_info.sent_amount().to_sat();
+    reserve_client_quota(&custody_id, amount, api::time());
+
+    // Use the authorizations while the batch is signed, so that they are only used once.
+    if let Err(error) = take_withdrawal_authorizations(custody_id, &withdrawals) {
+        record_client_signature(&custody_id, amount, false, api::time());
+        return Err(error);
+    }
+
+    // Book the settlements while the batch is signed as well, the ID of the transaction
+    // being known before its witnesses. They are unbooked if the batch is not sent.
+    let transaction_id = transaction_info.transaction().txid().to_string();
+    let result = LEDGERS.with(|ledgers| {
+        let mut ledgers = ledgers.borrow_mut();
+        common::book_settlements(ledgers.entry((custody_id, bitcoin_network)).or_default(), &settlements, &transaction_id)
+    });
+    let settlement_entries = match result {
+        Ok(settlement_entries) => settlement_entries,
+        Err(error) => {
+            cancel_batch(custody_id, bitcoin_network, &withdrawals, &[]);
+            record_client_signature(&custody_id, amount, false, api::time());
+            return Err(error);
+        },
+    };
+
+    let key_name = get_key_name(bitcoin_network);
+
+    // The inputs were checked to spend from the wallets of the owners of the withdrawals.
+    let derivation_paths: Vec<Vec<Vec<u8>>> = transaction_info.inputs()
+        .iter()
+        .map(|input| client_derivation_path(&custody_id, input.derivation_path.clone().unwrap()))
+        .collect();
+
+    let mut sec1_signatures = vec![None; transaction_info.inputs().len()];
+
+    let failed_inputs = common::sign_inputs(
+        &transaction_info,
+        &key_name,
+        &derivation_paths,
+        &mut sec1_signatures)
+    .await;
+
+    record_client_signature(&custody_id, amount, failed_inputs.is_empty(), api::time());
+
+    if !failed_inputs.is_empty() {
+        cancel_batch(custody_id, bitcoin_network, &withdrawals, &settlement_entries);
+        return Err(format!("Failed to sign the inputs of the batch: {:?}", failed_inputs));
+    }
+
+    // Insert the second (and last) signature.
+    let transaction_info = common::insert_signatures(
+        &transaction_info,
+        sec1_signatures.into_iter().map(Option::unwrap).collect(),
+        common::MultisigIndex::Last);
+
+    // Send the transaction, without trapping so that the ledger stays in line with the
+    // one of the custody wallet canister, which only books the settlements once sent.
+    if let Err(error) = common::try_send_transaction(bitcoin_network, &transaction_info).await {
+        cancel_batch(custody_id, bitcoin_network, &withdrawals, &settlement_entries);
+        return Err(error);
+    }
+
+    // Return the transaction id.
+    Ok(transaction_info.transaction().txid().to_string())
+}
+
+// Cancel the given batch of withdrawals of the given custody wallet canister, which
+// was not sent: give the authorizations back, so that the withdrawals can be batched
+// again, and unbook the given entries of the settlements it paid.
+fn cancel_batch(custody_id: candid::Principal, bitcoin_network: BitcoinNetwork, withdrawals: &[BatchedWithdrawal], settlement_entries: &[LedgerEntry]) {
+    restore_withdrawal_authorizations(custody_id, withdrawals);
+    LEDGERS.with(|ledgers| {
+        if let Some(ledger) = ledgers.borrow_mut().get_mut(&(custody_id, bitcoin_network)) {
+            for entry in settlement_entries {
+                common::unbook_transfer(ledger, entry.id);
+            }
+        }
+    });
+}
+
+// Get the adjustments of the balances on the ledger of the given custody wallet canister
+// and network once the given settlements are paid, checking that they only pay debts.
+fn settle_ledger(custody_id: candid::Principal, bitcoin_network: BitcoinNetwork, settlements: &[Settlement]) -> Result<BTreeMap<candid::Principal, i64>, String> {
+    let mut adjustments = LEDGERS.with(|ledgers| {
+        ledgers.borrow().get(&(custody_id, bitcoin_network))
+            .map(|ledger| ledger.adjustments.clone())
+            .unwrap_or_default()
+    });
+    common::apply_settlements(&mut adjustments, settlements)?;
+    Ok(adjustments)
+}
+
+/// Authorizes the given custody wallet canister to book a transfer of the given amount
+/// from the caller's balance to the given principal on its internal ledger, see
+/// `transfer` on the custody wallet canister. Each authorization is used by a single
+/// transfer of the same network, receiver and amount. Not available to the wallets
+/// with a second factor.
+#[update]
+pub fn authorize_transfer(bitcoin_network: BitcoinNetwork, custody_id: candid::Principal, to: candid::Principal, amount: u64) {
+    let principal = api::caller();
+    check_no_second_factor(&principal);
+    get_custody_client(&custody_id);
+    TRANSFER_AUTHORIZATIONS.with(|authorizations| {
+        authorizations.borrow_mut().entry(principal).or_default().push(TransferAuthorization {
+            custody_id,
+            bitcoin_network,
+            to,
+            amount,
+        });
+    });
+}
+
+/// Returns the transfers authorized by the caller and not booked yet.
+#[query]
+pub fn get_transfer_authorizations() -> Vec<TransferAuthorization> {
+    TRANSFER_AUTHORIZATIONS.with(|authorizations| {
+        authorizations.borrow().get(&api::caller()).cloned().unwrap_or_default()
+    })
+}
+
+/// Revokes all the transfers authorized by the caller and not booked yet.
+#[update]
+pub fn revoke_transfer_authorizations() {
+    TRANSFER_AUTHORIZATIONS.with(|authorizations| {
+        authorizations.borrow_mut().remove(&api::caller());
+    });
+}
+
+/// Allows the given custody wallet canister to book transfers from the caller's balance
+/// to any principal on its internal ledger, up to the given amount in total, without
+/// authorizing each one with `authorize_transfer`, e.g. for the transfers made through
+/// its token interface. The allowance replaces the previous one of the same custody
+/// wallet canister and network, and is removed with a zero amount. Not available to the
+/// wallets with a second factor.
+#[update]
+pub fn set_transfer_allowance(bitcoin_network: BitcoinNetwork, custody_id: candid::Principal, amount: u64) {
+    let principal = api::caller();
+    check_no_second_factor(&principal);
+    get_custody_client(&custody_id);
+    TRANSFER_ALLOWANCES.with(|allowances| {
+        let mut allowances = allowances.borrow_mut();
+        let sender_allowances = allowances.entry(principal).or_default();
+        sender_allowances.retain(|allowance| allowance.custody_id != custody_id || allowance.bitcoin_network != bitcoin_network);
+        if amount > 0 {
+            sender_allowances.push(TransferAllowance { custody_id, bitcoin_network, amount });
+        }
+        if sender_allowances.is_empty() {
+            allowances.remove(&principal);
+        }
+    });
+}
+
+/// Returns the remaining transfer allowances of the caller.
+#[query]
+pub fn get_transfer_allowances() -> Vec<TransferAllowance> {
+    TRANSFER_ALLOWANCES.with(|allowances| {
+        allowances.borrow().get(&api::caller()).cloned().unwrap_or_default()
+    })
+}
+
+/// Returns the adjustment of the balance of the given owner on the internal ledger of
+/// the given custody wallet canister, i.e. what it received minus what it sent through
+/// the transfers not settled on chain yet. A negative adjustment is owed by the wallet
+/// of the owner, which this canister does not let it spend.
+#[query]
+pub fn get_ledger_adjustment(bitcoin_network: BitcoinNetwork, custody_id: candid::Principal, owner: candid::Principal) -> i64 {
+    get_ledger_debt_adjustment(custody_id, bitcoin_network, owner)
+}
+
+// Book the given transfer on the ledger of the given custody wallet canister, using an
+// authorization of its sender, or else its allowance.
+fn book_transfer(custody_id: candid::Principal, bitcoin_network: BitcoinNetwork, from: candid::Principal, to: candid::Principal, amount: u64) -> Result<(), String> {
+    check_no_second_factor(&from);
+    check_not_paused(&vec![from.as_slice().to_vec()]);
+    common::ledger_amount(amount)?;
+    let authorization = TransferAuthorization { custody_id, bitcoin_network, to, amount };
+    let authorized = TRANSFER_AUTHORIZATIONS.with(|authorizations| {
+        let mut authorizations = authorizations.borrow_mut();
+        let sender_authorizations = authorizations.entry(from).or_default();
+        let position = sender_authorizations.iter().position(|sender_authorization| *sender_authorization == authorization);
+        if let Some(position) = position {
+            sender_authorizations.remove(position);
+        }
+        if sender_authorizations.is_empty() {
+            authorizations.remove(&from);
+        }
+        position.is_some()
+    });
+    if !authorized {
+        take_transfer_allowance(custody_id, bitcoin_network, from, amount)
+            .map_err(|()| format!("The transfer of {} satoshi to {} is not authorized by its sender {}.", amount, to, from))?;
+    }
+    LEDGERS.with(|ledgers| {
+        common::book_transfer(ledgers.borrow_mut().entry((custody_id, bitcoin_network)).or_default(), from, to, amount)
+    })?;
+    Ok(())
+}
+
+// Deduct the given amount from the allowance of the given sender for the given custody
+// wallet canister and network, if it is large enough.
+fn take_transfer_allowance(custody_id: candid::Principal, bitcoin_network: BitcoinNetwork, from: candid::Principal, amount: u64) -> Result<(), ()> {
+    TRANSFER_ALLOWANCES.with(|allowances| {
+        let mut allowances = allowances.borrow_mut();
+        let allowance = allowances.get_mut(&from)
+            .and_then(|sender_allowances| sender_allowances.iter_mut()
+                .find(|allowance| allowance.custody_id == custody_id && allowance.bitcoin_network == bitcoin_network))
+            .ok_or(())?;
+        allowance.amount = allowance.amount.checked_sub(amount).ok_or(())?;
+        Ok(())
+    })
+}
+
+// Get the adjustment of the balance of the given owner on the ledger of the given custody
+// wallet canister and network.
+fn get_ledger_debt_adjustment(custody_id: candid::Principal, bitcoin_network: BitcoinNetwork, owner: candid::Principal) -> i64 {
+    LEDGERS.with(|ledgers| {
+        ledgers.borrow().get(&(custody_id, bitcoin_network))
+            .map_or(0, |ledger| common::ledger_adjustment(ledger, &owner))
+    })
+}
+
+// Check that the given owner still holds what it owes on the ledger of the given custody
+// wallet canister once the given transaction is sent from its wallet. The wallets of the
+// canisters not namespaced are the same, so their owner must hold what it owes on all
+// their ledgers.
+async fn check_ledger_debt(custody_id: candid::Principal, bitcoin_network: BitcoinNetwork, owner: candid::Principal, transaction_info: &common::TransactionInfo) {
+    let debt = namespace_custody_ids(&custody_id).into_iter()
+        .map(|custody_id| get_ledger_debt_adjustment(custody_id, bitcoin_network, owner).min(0).unsigned_abs())
+        .sum();
+    common::check_ledger_debt(bitcoin_network, transaction_info, debt)
+        .await
+        .unwrap_or_else(|error| panic!("{}", error));
+}
+
+// Get the authorization of the given withdrawal of the given custody wallet canister.
+fn withdrawal_authorization(custody_id: candid::Principal, withdrawal: &BatchedWithdrawal) -> WithdrawalAuthorization {
+    WithdrawalAuthorization {
+        custody_id,
+        bitcoin_network: withdrawal.bitcoin_network,
+        destination_address: withdrawal.destination_address.clone(),
+        amount: withdrawal.amount,
+    }
+}
+
+// Remove an authorization of each of the given withdrawals of the given custody wallet
+// canister. If any withdrawal is not authorized, no authorization is removed.
+fn take_withdrawal_authorizations(custody_id: candid::Principal, withdrawals: &[BatchedWithdrawal]) -> Result<(), String> {
+    WITHDRAWAL_AUTHORIZATIONS.with(|authorizations| {
+        let mut authorizations = authorizations.borrow_mut();
+        let mut remaining = authorizations.clone();
+        for withdrawal in withdrawals {
+            let authorization = withdrawal_authorization(custody_id, withdrawal);
+            let owner_authorizations = remaining.entry(withdrawal.owner).or_default();
+            match owner_authorizations.iter().position(|owner_authorization| *owner_authorization == authorization) {
+                Some(position) => {
+                    owner_authorizations.remove(position);
+                },
+                None => return Err(format!("The withdrawal {} is not authorized by its owner {}.", withdrawal.id, withdrawal.owner)),
+            }
+        }
+        remaining.retain(|_, owner_authorizations| !owner_authorizations.is_empty());
+        *authorizations = remaining;
+        Ok(())
+    })
+}
+
+// Give back the authorizations of the given withdrawals of the given custody wallet canister.
+fn restore_withdrawal_authorizations(custody_id: candid::Principal, withdrawals: &[BatchedWithdrawal]) {
+    WITHDRAWAL_AUTHORIZATIONS.with(|authorizations| {
+        let mut authorizations = authorizations.borrow_mut();
+        for withdrawal in withdrawals {
+            authorizations.entry(withdrawal.owner).or_default().push(withdrawal_authorization(custody_id, withdrawal));
+        }
+    });
+}
+
+/// Registers the given principal (e.g. the identity of another device) as the second
+/// factor of the caller's wallet. The send requests of the wallet must then be made
+/// with `request_send` and approved by the second factor with `approve_send`.
+/// A second factor can only be replaced or removed by itself, see `remove_second_factor`.
+#[update]
+pub fn set_second_factor(second_factor: candid::Principal) {
+    insert_second_factor(api::caller(), second_factor);
+}
+
+// Register the given second factor for the wallet of the given owner.
+fn insert_second_factor(principal: candid::Principal, second_factor: candid::Principal) {
+    if candid::Principal::anonymous() == second_factor {
+        panic!("The second factor cannot be anonymous.");
+    }
+    if principal == second_factor {
+        panic!("The second factor cannot be the owner of the wallet.");
+    }
+    SECOND_FACTORS.with(|second_factors| {
+        let mut second_factors = second_factors.borrow_mut();
+        if second_factors.contains_key(&principal) {
+            panic!("The principal {} already has a second factor.", principal);
+        }
+        second_factors.insert(principal, second_factor);
+    });
+}
+
+/// Removes the second factor of the given owner. Only the second factor can remove
+/// itself, so that an attacker who took over the owner's identity cannot.
+#[update]
+pub fn remove_second_factor(owner: candid::Principal) {
+    let principal = api::caller();
+    check_second_factor(owner, principal);
+    SECOND_FACTORS.with(|second_factors| {
+        second_factors.borrow_mut().remove(&owner);
+    });
+}
+
+/// Returns the second factor of the given owner, if any.
+#[query]
+pub fn get_second_factor(owner: candid::Principal) -> Option<candid::Principal> {
+    SECOND_FACTORS.with(|second_factors| second_factors.borrow().get(&owner).cloned())
+}
+
+/// Submits a send request of the caller's wallet, signed by the custody wallet, for
+/// the approval of its second factor. Returns the ID of the send request to approve.
+#[update]
+pub fn request_send(bitcoin_network: BitcoinNetwork, custody_id: candid::Principal, raw_transaction_info: RawTransactionInfo) -> u64 {
+    insert_pending_send(api::caller(), bitcoin_network, custody_id, raw_transaction_info)
+}
+
+// Add a send request of the wallet of the given owner, waiting for the approval of its second factor.
+fn insert_pending_send(principal: candid::Principal, bitcoin_network: BitcoinNetwork, custody_id: candid::Principal, raw_transaction_info: RawTransactionInfo) -> u64 {
+    if get_second_factor(principal).is_none() {
+        panic!("The principal {} has no second factor, use finalize_send_request.", principal);
+    }
+    get_custody_client(&custody_id);
+    let request_id = NEXT_PENDING_SEND_ID.with(|id| {
+        let request_id = id.get();
+        id.set(request_id + 1);
+        request_id
+    });
+    PENDING_SENDS.with(|pending_sends| {
+        pending_sends.borrow_mut().insert(request_id, PendingSend {
+            owner: principal,
+            bitcoin_network,
+            custody_id: Some(custody_id),
+            raw_transaction_info,
+        });
+    });
+    request_id
+}
+
+/// Returns the send requests of the given owner waiting for the approval of its
+/// second factor, by request ID.
+#[query]
+pub fn get_pending_sends(owner: candid::Principal) -> Vec<(u64, PendingSend)> {
+    PENDING_SENDS.with(|pending_sends| {
+        pending_sends.borrow().iter()
+            .filter(|(_, pending_send)| pending_send.owner == owner)
+            .map(|(request_id, pending_send)| (*request_id, pending_send.clone()))
+            .collect()
+    })
+}
+
+/// Cancels the given send request waiting for approval. Can be called by the
+/// owner of the wallet or its second factor.
+#[update]
+pub fn cancel_send(request_id: u64) {
+    let principal = api::caller();
+    let pending_send = get_pending_send(request_id);
+    if pending_send.owner != principal && get_second_factor(pending_send.owner) != Some(principal) {
+        panic!("The send request {} does not belong to the caller.", request_id);
+    }
+    PENDING_SENDS.with(|pending_sends| {
+        pending_sends.borrow_mut().remove(&request_id);
+    });
+}
+
+/// Approves the given send request as the second factor of its wallet, which inserts
+/// the second (and last) signature and sends the transaction.
+#[update]
+pub async fn approve_send(request_id: u64) -> Result<String, Vec<InputSigningError>> {
+
+    let principal = api::caller();
+    let pending_send = get_pending_send(request_id);
+    check_second_factor(pending_send.owner, principal);
+    check_not_paused(&vec![pending_send.owner.as_slice().to_vec()]);
+    if let Some(custody_id) = pending_send.custody_id {
+        let transaction_info = common::TransactionInfo::from_raw(pending_send.raw_transaction_info.clone());
+        check_ledger_debt(custody_id, pending_send.bitcoin_network, pending_send.owner, &transaction_info).await;
+    }
+
+    // Remove the send request while it is signed, so that it is only sent once.
+    PENDING_SENDS.with(|pending_sends| {
+        pending_sends.borrow_mut().remove(&request_id);
+    });
+
+    let key_name = get_key_name(pending_send.bitcoin_network);
+
+    // Get the transaction info from the raw one.
+    let transaction_info = common::TransactionInfo::from_raw(pending_send.raw_transaction_info.clone());
+
+    // Insert the second (and last) signature, with the key of the owner. The send
+    // requests made before the namespacing of the derivation paths have no custody
+    // wallet canister, and are signed with the raw derivation path. The transaction is
+    // sent along, so that a transaction rejected by the network can be approved again.
+    let derivation_path = vec![pending_send.owner.as_slice().to_vec()];
+    let result = match pending_send.custody_id {
+        Some(custody_id) => sign_and_send_client_transaction(custody_id, pending_send.bitcoin_network, &transaction_info, &key_name, derivation_path).await,
+        None => {
+            transaction_info.check_derivation_path(&derivation_path);
+            match common::sign_transaction(&transaction_info, &key_name, &derivation_path, common::MultisigIndex::Last).await {
+                Ok(transaction_info) => common::send_transaction(pending_send.bitcoin_network, &transaction_info).await
+                    .map(|_| transaction_info),
+                Err(failed_inputs) => Err(failed_inputs),
+            }
+        },
+    };
+    let transaction_info = match result {
+        Ok(transaction_info) => transaction_info,
+        Err(failed_inputs) => {
+            // Keep the send request, so that it can be approved again.
+            PENDING_SENDS.with(|pending_sends| {
+                pending_sends.borrow_mut().insert(request_id, pending_send);
+            });
+            return Err(failed_inputs);
+        },
+    };
+
+    // Return the transaction id.
+    Ok(transaction_info.transaction().txid().to_string())
+}
+
+/// Pauses all the withdrawals, i.e. the signature of send requests. Operators and controllers.
+#[update]
+pub fn pause_withdrawals() {
+    log_admin_action(Role::Operator, AdminAction::WithdrawalsPaused);
+    PAUSE_STATE.with(|state| state.borrow_mut().paused = true);
+}
+
+/// Resumes the withdrawals paused with `pause_withdrawals`. Controllers only.
+#[update]
+pub fn resume_withdrawals() {
+    log_admin_action(Role::Controller, AdminAction::WithdrawalsResumed);
+    PAUSE_STATE.with(|state| state.borrow_mut().paused = false);
+}
+
+/// Pauses or resumes the withdrawals of the wallet with the given derivation path
+/// (i.e. the principal of the owner for user wallets). Operators can pause a wallet,
+/// only controllers can resume it.
+#[update]
+pub fn set_wallet_paused(derivation_path: Vec<Vec<u8>>, paused: bool) {
+    let required_role = if paused { Role::Operator } else { Role::Controller };
+    log_admin_action(required_role, AdminAction::WalletPaused { derivation_path: derivation_path.clone(), paused });
+    PAUSE_STATE.with(|state| common::set_wallet_paused(&mut state.borrow_mut(), derivation_path, paused));
+}
+
+/// Returns the withdrawals currently paused.
+#[query]
+pub fn get_pause_state() -> PauseState {
+    PAUSE_STATE.with(|state| state.borrow().clone())
+}
+
+/// Adds the given principal to the operators of this canister. Controllers only.
+#[update]
+pub fn add_operator(operator: candid::Principal) {
+    log_admin_action(Role::Controller, AdminAction::OperatorAdded { operator });
+    ADMIN_STATE.with(|state| common::add_operator(&mut state.borrow_mut(), operator));
+}
+
+/// Removes the given principal from the operators of this canister. Controllers only.
+#[update]
+pub fn remove_operator(operator: candid::Principal) {
+    log_admin_action(Role::Controller, AdminAction::OperatorRemoved { operator });
+    ADMIN_STATE.with(|state| common::remove_operator(&mut state.borrow_mut(), &operator));
+}
+
+/// Returns the operators of this canister. Operators and controllers.
+#[query]
+pub fn get_operators() -> Vec<candid::Principal> {
+    ADMIN_STATE.with(|state| {
+        let state = state.borrow();
+        common::check_role(&state, &api::caller(), Role::Operator);
+        state.operators.clone()
+    })
+}
+
+/// Returns the role of the caller on this canister, if any.
+#[query]
+pub fn get_role() -> Option<Role> {
+    ADMIN_STATE.with(|state| common::get_role(&state.borrow(), &api::caller()))
+}
+
+/// Returns the audit log of the admin actions. Operators and controllers.
+#[query]
+pub fn get_audit_log() -> Vec<AuditEntry> {
+    ADMIN_STATE.with(|state| {
+        let state = state.borrow();
+        common::check_role(&state, &api::caller(), Role::Operator);
+        state.audit_log.clone()
+    })
+}
+
+/// Sets the ECDSA key name used for the given network. Controllers only.
+/// The public keys of all the wallets change with the key name, so it cannot change
+/// once custody wallet canisters are registered.
+#[update]
+pub fn set_ecdsa_key_name(bitcoin_network: BitcoinNetwork, key_name: String) {
+    log_admin_action(Role::Controller, AdminAction::ConfigurationUpdated {
+        bitcoin_network,
+        key_name: key_name.clone(),
+        fiduciary_id: None,
+        max_amount_per_request: None,
+    });
+    set_key_names(vec![(bitcoin_network, key_name)]);
+}
+
+/// Registers the given custody wallet canister, which can then pair with this canister
+/// and get its public keys. The derivation paths of a namespaced canister are prefixed
+/// with its ID, so that the wallets of different canisters never share keys; only the
+/// canisters which created wallets before the namespacing shall not be. Controllers only.
+#[update]
+pub fn register_custody_canister(canister_id: candid::Principal, namespaced: bool) {
+    log_admin_action(Role::Controller, AdminAction::CustodyCanisterRegistered { canister_id, namespaced: Some(namespaced) });
+    register_custody_id(canister_id, namespaced);
+}
+
+/// Unregisters the given custody wallet canister, along with its policy and statistics.
+/// Controllers only.
+#[update]
+pub fn unregister_custody_canister(canister_id: candid::Principal) {
+    log_admin_action(Role::Controller, AdminAction::CustodyCanisterUnregistered { canister_id });
+    CUSTODY_CANISTERS.with(|canisters| canisters.borrow_mut().remove(&canister_id));
+    CUSTODY_CLIENTS.with(|clients| clients.borrow_mut().remove(&canister_id));
+}
+
+/// Sets the policy of the given custody wallet canister, i.e. the maximum amount sent
+/// by each of its transactions and by all of them each day. Controllers only.
+#[update]
+pub fn set_custody_client_policy(canister_id: candid::Principal, policy: ClientPolicy) {
+    log_admin_action(Role::Controller, AdminAction::CustodyClientPolicyUpdated { canister_id, policy: policy.clone() });
+    get_custody_client(&canister_id);
+    update_custody_client(&canister_id, |client| client.policy = policy);
+}
+
+/// Returns the registered custody wallet canisters, with their policy, quota and
+/// statistics. Operators and controllers.
+#[query]
+pub fn get_custody_clients() -> Vec<CustodyClient> {
+    ADMIN_STATE.with(|state| common::check_role(&state.borrow(), &api::caller(), Role::Operator));
+    CUSTODY_CLIENTS.with(|clients| clients.borrow().values().cloned().collect())
+}
+
+/// Pairs the calling custody wallet canister with this canister, given the versions of
+/// the protocol it speaks, and returns the latest version spoken by both canisters.
+/// Registered custody wallet canisters only.
+#[update]
+pub fn negotiate_pairing(versions: ProtocolVersions) -> Result<PairingReply, String> {
+    let caller = api::caller();
+    get_custody_client(&caller);
+    let negotiated_version = common::negotiate_protocol_version(&versions);
+    record_pairing(caller, versions.max_version, negotiated_version.clone());
+    negotiated_version.map(|protocol_version| PairingReply { protocol_version })
+}
+
+/// Pairs the calling custody wallet canister of the first version of the protocol, which
+/// cannot negotiate, given the only version it speaks. Registered custody wallet canisters only.
+#[update]
+pub fn pair(protocol_version: u32) -> PairingReply {
+    let caller = api::caller();
+    get_custody_client(&caller);
+    let negotiated_version = common::check_protocol_version(protocol_version).map(|_| protocol_version);
+    record_pairing(caller, protocol_version, negotiated_version.clone());
+    PairingReply { protocol_version: negotiated_version.unwrap_or(common::PROTOCOL_VERSION) }
+}
+
+// Record the pairing of the given custody wallet canister, speaking up to the given
+// version of the protocol, with the version negotiated or the error.
+fn record_pairing(custody_id: candid::Principal, peer_protocol_version: u32, negotiated_version: Result<u32, String>) {
+    let error = negotiated_version.clone().err().map(|error| format!("Failed to pair with the custody wallet canister {}: {}", custody_id, error));
+    if let Some(error) = &error {
+        ic_cdk::print(error);
+    }
+    CUSTODY_CANISTERS.with(|canisters| {
+        canisters.borrow_mut().insert(custody_id, Pairing {
+            peer: custody_id,
+            protocol_version: common::PROTOCOL_VERSION,
+            peer_protocol_version: Some(peer_protocol_version),
+            negotiated_version: negotiated_version.ok(),
+            paired_at: error.is_none().then(api::time),
+            error,
+        });
+    });
+}
+
+/// Returns the pairings of this canister with the registered custody wallet canisters,
+/// along with the protocol version spoken by each canister.
+#[query]
+pub fn get_pairing() -> Vec<Pairing> {
+    CUSTODY_CANISTERS.with(|canisters| canisters.borrow().values().cloned().collect())
+}
+
+// Register the given custody wallet canister, not paired yet. A canister already
+// registered keeps its namespace, policy and statistics.
+fn register_custody_id(canister_id: candid::Principal, namespaced: bool) {
+    CUSTODY_CLIENTS.with(|clients| {
+        clients.borrow_mut().entry(canister_id).or_insert(CustodyClient {
+            canister_id,
+            namespaced,
+            policy: ClientPolicy::default(),
+            stats: Default::default(),
+            quota_day: 0,
+            quota_used: 0,
+        });
+    });
+    CUSTODY_CANISTERS.with(|canisters| {
+        canisters.borrow_mut().entry(canister_id).or_insert(Pairing {
+            peer: canister_id,
+            protocol_version: common::PROTOCOL_VERSION,
+            peer_protocol_version: None,
+            negotiated_version: None,
+            paired_at: None,
+            error: None,
+        });
+    });
+}
+
+// Get the registered custody wallet canister with the given ID.
+fn get_custody_client(canister_id: &candid::Principal) -> CustodyClient {
+    CUSTODY_CLIENTS.with(|clients| clients.borrow().get(canister_id).cloned())
+        .unwrap_or_else(|| panic!("The principal {} is not a registered custody wallet canister.", canister_id))
+}
+
+// Update the given custody wallet canister, if still registered.
+fn update_custody_client(canister_id: &candid::Principal, update: impl FnOnce(&mut CustodyClient)) {
+    CUSTODY_CLIENTS.with(|clients| {
+        if let Some(client) = clients.borrow_mut().get_mut(canister_id) {
+            update(client);
+        }
+    });
+}
+
+// Get the derivation path of this canister for the given derivation path of a wallet of
+// the given custody wallet canister, i.e. prefixed with the ID of the canister if namespaced.
+fn client_derivation_path(custody_id: &candid::Principal, derivation_path: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    match get_custody_client(custody_id).namespaced {
+        true => [vec![custody_id.as_slice().to_vec()], derivation_path].concat(),
+        false => derivation_path,
+    }
+}
+
+// Get the registered custody wallet canisters whose wallets have the same derivation
+// paths as the ones of the given canister, i.e. the canister itself if namespaced, or
+// else all the canisters not namespaced, whose IDs can be given for the same wallets.
+fn namespace_custody_ids(custody_id: &candid::Principal) -> Vec<candid::Principal> {
+    match get_custody_client(custody_id).namespaced {
+        true => vec![*custody_id],
+        false => CUSTODY_CLIENTS.with(|clients| {
+            clients.borrow().values()
+                .filter(|client| !client.namespaced)
+                .map(|client| client.canister_id)
+                .collect()
+        }),
+    }
+}
+
+// Check the given amount against the policy of the given custody wallet canister and
+// reserve it on the daily quota of the canister, until the transaction is signed. The
+// amount sent from the wallets of the canisters not namespaced is checked against the
+// policies and reserved on the quotas of all of them.
+fn reserve_client_quota(custody_id: &candid::Principal, amount: u64, now: u64) {
+    let today = now / NANOSECONDS_PER_DAY;
+    let custody_ids = namespace_custody_ids(custody_id);
+    for custody_id in &custody_ids {
+        let client = get_custody_client(custody_id);
+        if let Some(max_amount) = client.policy.max_amount_per_transaction {
+            if amount > max_amount {
+                panic!("The amount {} exceeds the maximum of {} per transaction of the custody wallet canister {}.", amount, max_amount, custody_id);
+            }
+        }
+        let quota_used = if client.quota_day == today { client.quota_used } else { 0 };
+        if let Some(daily_quota) = client.policy.daily_quota {
+            if quota_used + amount > daily_quota {
+                panic!("The amount {} exceeds the daily quota of the custody wallet canister {}: {} of {} used.", amount, custody_id, quota_used, daily_quota);
+            }
+        }
+    }
+    for custody_id in &custody_ids {
+        update_custody_client(custody_id, |client| {
+            if client.quota_day != today {
+                client.quota_day = today;
+                client.quota_used = 0;
+            }
+            client.quota_used += amount;
+        });
+    }
+}
+
+// Record the signature of a transaction of the given custody wallet canister sending the
+// given amount, or release the amount from the quotas it was reserved on if the
+// transaction failed to be signed, see `reserve_client_quota`.
+fn record_client_signature(custody_id: &candid::Principal, amount: u64, signed: bool, now: u64) {
+    update_custody_client(custody_id, |client| {
+        if signed {
+            client.stats.signed_transactions += 1;
+            client.stats.signed_amount += amount;
+        } else {
+            client.stats.failed_transactions += 1;
+        }
+    });
+    if !signed {
+        for custody_id in namespace_custody_ids(custody_id) {
+            update_custody_client(&custody_id, |client| {
+                if client.quota_day == now / NANOSECONDS_PER_DAY {
+                    client.quota_used = client.quota_used.saturating_sub(amount);
+                }
+            });
+        }
+    }
+}
+
+// Insert the second (and last) signature in the given transaction of a wallet of the
+// given custody wallet canister, within the namespace, policy and quota of the canister,
+// and send it. A transaction that is not sent gives its amount back to the quota.
+async fn sign_and_send_client_transaction(
+    custody_id: candid::Principal,
+    bitcoin_network: BitcoinNetwork,
+    transaction_info: &common::TransactionInfo,
+    key_name: &str,
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<common::TransactionInfo, Vec<InputSigningError>>
+{
+    transaction_info.check_derivation_path(&derivation_path);
+    let derivation_path = client_derivation_path(&custody_id, derivation_path);
+    let amount = transaction_info.sent_amount().to_sat();
+    reserve_client_quota(&custody_id, amount, api::time());
+    let result = match common::sign_transaction(
+        transaction_info,
+        key_name,
+        &derivation_path,
         common::MultisigIndex::Last)
-        .await;
+        .await
+    {
+        Ok(transaction_info) => common::send_transaction(bitcoin_network, &transaction_info).await
+            .map(|_| transaction_info),
+        Err(failed_inputs) => Err(failed_inputs),
+    };
+    record_client_signature(&custody_id, amount, result.is_ok(), api::time());
+    result
+}
 
-    // Send the transaction.
-    common::send_transaction(bitcoin_network, &transaction_info).await;
+// Check that the caller has at least the given role, then record the given action
+// in the audit log.
+fn log_admin_action(required_role: Role, action: AdminAction) {
+    ADMIN_STATE.with(|state| {
+        common::record_admin_action(&mut state.borrow_mut(), api::caller(), required_role, action);
+    });
+}
+
+// Check that the withdrawals of the wallet with the given derivation path are not paused.
+fn check_not_paused(derivation_path: &Vec<Vec<u8>>) {
+    PAUSE_STATE.with(|state| common::check_not_paused(&state.borrow(), derivation_path));
+}
+
+// Get the send request waiting for approval with the given ID.
+fn get_pending_send(request_id: u64) -> PendingSend {
+    PENDING_SENDS.with(|pending_sends| pending_sends.borrow().get(&request_id).cloned())
+        .unwrap_or_else(|| panic!("No send request waiting for approval with the ID {}", request_id))
+}
+
+// Check that the given principal is the second factor of the given owner.
+fn check_second_factor(owner: candid::Principal, principal: candid::Principal) {
+    if get_second_factor(owner) != Some(principal) {
+        panic!("The principal {} is not the second factor of {}.", principal, owner);
+    }
+}
+
+// Check that the given owner has no second factor, i.e. that its send requests
+// can be signed without approval.
+fn check_no_second_factor(owner: &candid::Principal) {
+    if get_second_factor(*owner).is_some() {
+        panic!("The send requests of {} must be approved by its second factor, use request_send.", owner);
+    }
+}
+
+/// Sets the heir of the caller's wallet, who can finalize withdrawals from it with
+/// `finalize_inheritance_request` once the caller has not called `check_in` for the
+/// given period. The same heir must be set on the custody wallet canister.
+#[update]
+pub fn set_heir(bitcoin_network: BitcoinNetwork, heir: candid::Principal, inactivity_period_seconds: u64) {
+    INHERITANCES.with(|inheritances| {
+        common::set_heir(inheritances.borrow_mut().entry(bitcoin_network).or_default(), api::caller(), heir, inactivity_period_seconds, api::time());
+    });
+}
+
+/// Removes the heir of the caller's wallet.
+#[update]
+pub fn remove_heir(bitcoin_network: BitcoinNetwork) {
+    INHERITANCES.with(|inheritances| {
+        if let Some(inheritances) = inheritances.borrow_mut().get_mut(&bitcoin_network) {
+            inheritances.remove(&api::caller());
+        }
+    });
+}
+
+/// Records that the caller is still active, which postpones the time from
+/// which its heir can withdraw.
+#[update]
+pub fn check_in(bitcoin_network: BitcoinNetwork) {
+    INHERITANCES.with(|inheritances| {
+        common::check_in(inheritances.borrow_mut().entry(bitcoin_network).or_default(), api::caller(), api::time());
+    });
+}
+
+/// Returns the heir of the given owner, with its last check-in.
+#[query]
+pub fn get_inheritance(bitcoin_network: BitcoinNetwork, owner: candid::Principal) -> Option<Inheritance> {
+    INHERITANCES.with(|inheritances| {
+        inheritances.borrow().get(&bitcoin_network).and_then(|inheritances| inheritances.get(&owner).cloned())
+    })
+}
+
+// Check that the given heir can withdraw from the wallet of the given owner
+// on the given network.
+fn check_heir(bitcoin_network: BitcoinNetwork, owner: candid::Principal, heir: candid::Principal, now: u64) {
+    INHERITANCES.with(|inheritances| {
+        let inheritances = inheritances.borrow();
+        let empty = HashMap::new();
+        common::check_heir(inheritances.get(&bitcoin_network).unwrap_or(&empty), owner, heir, now);
+    });
+}
+
+/// Finalizes a send request from the wallet of the given owner, initiated by its
+/// heir (i.e. the caller) on the custody wallet canister, once the owner has not
+/// checked in for its inactivity period.
+#[update]
+pub async fn finalize_inheritance_request(bitcoin_network: BitcoinNetwork, custody_id: candid::Principal, owner: candid::Principal, raw_transaction_info: RawTransactionInfo) -> Result<String, Vec<InputSigningError>> {
+
+    let principal = &api::caller();
+    check_heir(bitcoin_network, owner, *principal, api::time());
+    check_not_paused(&vec![owner.as_slice().to_vec()]);
+
+    let key_name = get_key_name(bitcoin_network);
+
+    // Get the transaction info from the raw one.
+    let mut transaction_info = common::TransactionInfo::from_raw(raw_transaction_info);
+    check_ledger_debt(custody_id, bitcoin_network, owner, &transaction_info).await;
+
+    // Insert the second (and last) signature, with the key of the owner, and send the transaction.
+    transaction_info = sign_and_send_client_transaction(
+        custody_id,
+        bitcoin_network,
+        &transaction_info,
+        &key_name,
+        vec![owner.as_slice().to_vec()])
+        .await?;
+
+    // Return the transaction id.
+    Ok(transaction_info.transaction().txid().to_string())
+}
+
+/// Sets the members of the shared wallet with the given name created by the caller,
+/// who is always a member. They must match the members set on the custody wallet.
+#[update]
+pub fn set_shared_wallet_members(name: String, members: Vec<candid::Principal>) {
+    let principal = api::caller();
+    let mut unique_members = vec![principal];
+    for member in members {
+        if candid::Principal::anonymous() == member {
+            panic!("A member cannot be anonymous.");
+        }
+        if !unique_members.contains(&member) {
+            unique_members.push(member);
+        }
+    }
+    SHARED_WALLET_MEMBERS.with(|shared_wallets| {
+        shared_wallets.borrow_mut().insert(SharedWalletId { creator: principal, name }, unique_members);
+    });
+}
+
+/// Returns the members of the given shared wallet.
+#[query]
+pub fn get_shared_wallet_members(id: SharedWalletId) -> Vec<candid::Principal> {
+    SHARED_WALLET_MEMBERS.with(|shared_wallets| shared_wallets.borrow().get(&id).cloned().unwrap_or_default())
+}
+
+/// Finalizes a send request from the given shared wallet, approved by its members
+/// on the custody wallet canister. The caller must be a member of the wallet.
+#[update]
+pub async fn finalize_shared_send_request(bitcoin_network: BitcoinNetwork, custody_id: candid::Principal, id: SharedWalletId, raw_transaction_info: RawTransactionInfo) -> Result<String, Vec<InputSigningError>> {
+
+    let principal = &api::caller();
+    let is_member = SHARED_WALLET_MEMBERS.with(|shared_wallets| {
+        shared_wallets.borrow().get(&id).map_or(false, |members| members.contains(principal))
+    });
+    if !is_member {
+        panic!("The principal {} is not a member of the shared wallet {}.", principal, id.name);
+    }
+    check_not_paused(&common::shared_wallet_derivation_path(&id));
+
+    let key_name = get_key_name(bitcoin_network);
+
+    // Get the transaction info from the raw one.
+    let mut transaction_info = common::TransactionInfo::from_raw(raw_transaction_info);
+
+    // Insert the second (and last) signature, with the key of the shared wallet, and send
+    // the transaction.
+    transaction_info = sign_and_send_client_transaction(
+        custody_id,
+        bitcoin_network,
+        &transaction_info,
+        &key_name,
+        common::shared_wallet_derivation_path(&id))
+        .await?;
+
+    // Return the transaction id.
+    Ok(transaction_info.transaction().txid().to_string())
+}
+
+// Get the root public key for the given key name.
+// It is fetched from the management canister the first time, then cached.
+async fn get_root_public_key(key_name: String) -> ECDSAPublicKeyReply {
+    match ROOT_PUBLIC_KEYS.with(|keys| keys.borrow().get(&key_name).cloned()) {
+        Some(root_public_key) => root_public_key,
+        None => {
+            let root_public_key = common::ecdsa_root_public_key(key_name.clone()).await;
+            ROOT_PUBLIC_KEYS.with(|keys| {
+                keys.borrow_mut().insert(key_name, root_public_key.clone());
+            });
+            root_public_key
+        },
+    }
+}
+
+/// Finalizes a send request given as a PSBT (BIP174), which must contain the
+/// witness UTXO and the witness script of each input, along with the partial
+/// signatures of another signer (i.e. the custody wallet or the key held by
+/// the user for 2-of-3 wallets) for all the inputs. The wallet is the one of the
+/// caller on the given custody wallet canister.
+#[update]
+pub async fn finalize_send_request_psbt(bitcoin_network: BitcoinNetwork, custody_id: candid::Principal, psbt: Vec<u8>) -> Result<String, Vec<InputSigningError>> {
+
+    let principal = &api::caller();
+    check_no_second_factor(principal);
+    let key_name = get_key_name(bitcoin_network);
+    let derivation_path = vec![principal.as_slice().to_vec()];
+    check_not_paused(&derivation_path);
+    let derivation_path = client_derivation_path(&custody_id, derivation_path);
+
+    let psbt = Psbt::deserialize(&psbt).expect("Invalid PSBT.");
+    let (unsigned_transaction_info, _) = common::TransactionInfo::from_psbt(&psbt);
+    check_ledger_debt(custody_id, bitcoin_network, *principal, &unsigned_transaction_info).await;
+    let amount = unsigned_transaction_info.sent_amount().to_sat();
+    reserve_client_quota(&custody_id, amount, api::time());
+
+    // Get the public key of this canister for the caller.
+    let root_public_key = get_root_public_key(key_name.clone()).await;
+    let public_key = common::derive_public_key(&root_public_key, &derivation_path);
+
+    // Insert the signature of this canister along the one of the co-signer.
+    let result = common::cosign_psbt(
+        &psbt,
+        &key_name,
+        &derivation_path,
+        &public_key)
+    .await;
+    let result = match result {
+        Ok(transaction_info) => common::send_transaction(bitcoin_network, &transaction_info).await
+            .map(|_| transaction_info),
+        Err(failed_inputs) => Err(failed_inputs),
+    };
+    record_client_signature(&custody_id, amount, result.is_ok(), api::time());
+    let transaction_info = result?;
 
     // Return the transaction id.
-    transaction_info.transaction().txid().to_string()
+    Ok(transaction_info.transaction().txid().to_string())
 }
 
+// Get the key name of the given network, as set at init, upgrade or by the controllers,
+// or else the default one.
 fn get_key_name(bitcoin_network: BitcoinNetwork) -> String {
+    if let Some(key_name) = KEY_NAMES.with(|key_names| key_names.borrow().get(&bitcoin_network).cloned()) {
+        return key_name;
+    }
     String::from(match bitcoin_network {
         // For local development, we use a special test key with dfx.
         BitcoinNetwork::Regtest => "dfx_test_key",
-        // On the IC we're using key_1 for testnet and mainnet.
-        BitcoinNetwork::Mainnet | BitcoinNetwork::Testnet => "key_1",
+        // On the IC we're using the production key for testnet and mainnet.
+        BitcoinNetwork::Mainnet | BitcoinNetwork::Testnet => common::PRODUCTION_KEY_NAME,
     })
 }
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let inheritances = INHERITANCES.with(|inheritances| inheritances.borrow().clone());
+    let shared_wallet_members = SHARED_WALLET_MEMBERS.with(|shared_wallets| shared_wallets.borrow().clone());
+    let second_factors = SECOND_FACTORS.with(|second_factors| second_factors.borrow().clone());
+    let pending_sends = PENDING_SENDS.with(|pending_sends| pending_sends.borrow().clone());
+    let next_pending_send_id = NEXT_PENDING_SEND_ID.with(|id| id.get());
+    let pause_state = PAUSE_STATE.with(|state| state.borrow().clone());
+    let key_names = KEY_NAMES.with(|key_names| key_names.borrow().clone());
+    let admin_state = ADMIN_STATE.with(|state| state.borrow().clone());
+    let custody_canisters = CUSTODY_CANISTERS.with(|canisters| canisters.borrow().clone());
+    let custody_clients = CUSTODY_CLIENTS.with(|clients| clients.borrow().clone());
+    let withdrawal_authorizations = WITHDRAWAL_AUTHORIZATIONS.with(|authorizations| authorizations.borrow().clone());
+    let ledgers = LEDGERS.with(|ledgers| ledgers.borrow().clone());
+    let transfer_authorizations = TRANSFER_AUTHORIZATIONS.with(|authorizations| authorizations.borrow().clone());
+    let transfer_allowances = TRANSFER_ALLOWANCES.with(|allowances| allowances.borrow().clone());
+    // The ledgers, transfer authorizations and allowances and the inheritances by network
+    // share the last element, the tuples restored with defaults having at most 12 elements.
+    // The first element keeps the inheritances shared by all networks of the previous versions.
+    let no_inheritances: HashMap<candid::Principal, Inheritance> = HashMap::new();
+    ic_cdk::storage::stable_save((no_inheritances, Some(shared_wallet_members), Some(second_factors), Some(pending_sends), Some(next_pending_send_id), Some(pause_state), Some(key_names), Some(admin_state), Some(custody_canisters), Some(custody_clients), Some(withdrawal_authorizations), Some((ledgers, transfer_authorizations, Some(transfer_allowances), Some(inheritances))),))
+        .expect("Saving inheritances, shared wallet members, second factors, pending sends, pause state, key names, admin state, custody canisters, withdrawal authorizations and ledgers to stable store must succeed.");
+}
+
+/// The key names of the given arguments, if any, replace the ones of the previous version,
+/// and the custody wallet canisters given are registered along the previous ones.
+#[post_upgrade]
+fn post_upgrade(args: Option<InitArguments>) {
+    // Nothing was saved by the versions without inheritances.
+    let (inheritances, shared_wallet_members, second_factors, pending_sends, next_pending_send_id, pause_state, key_names, admin_state, custody_canisters, custody_clients, withdrawal_authorizations, ledgers) = ic_cdk::storage::stable_restore::<(
+        HashMap<candid::Principal, Inheritance>,
+        Option<HashMap<SharedWalletId, Vec<candid::Principal>>>,
+        Option<HashMap<candid::Principal, candid::Principal>>,
+        Option<BTreeMap<u64, PendingSend>>,
+        Option<u64>,
+        Option<PauseState>,
+        Option<HashMap<BitcoinNetwork, String>>,
+        Option<AdminState>,
+        Option<BTreeMap<candid::Principal, Pairing>>,
+        Option<BTreeMap<candid::Principal, CustodyClient>>,
+        Option<HashMap<candid::Principal, Vec<WithdrawalAuthorization>>>,
+        Option<LedgerState>,
+    )>()
+        .unwrap_or_default();
+    let (ledgers, transfer_authorizations, transfer_allowances, network_inheritances) = ledgers.unwrap_or_default();
+    // The versions before the inheritances were kept by network shared them across
+    // all the networks.
+    let inheritances = network_inheritances.unwrap_or_else(|| {
+        [BitcoinNetwork::Mainnet, BitcoinNetwork::Testnet, BitcoinNetwork::Regtest].into_iter()
+            .map(|bitcoin_network| (bitcoin_network, inheritances.clone()))
+            .collect()
+    });
+    INHERITANCES.with(|state| {
+        state.replace(inheritances);
+    });
+    SHARED_WALLET_MEMBERS.with(|state| {
+        state.replace(shared_wallet_members.unwrap_or_default());
+    });
+    SECOND_FACTORS.with(|state| {
+        state.replace(second_factors.unwrap_or_default());
+    });
+    PENDING_SENDS.with(|state| {
+        state.replace(pending_sends.unwrap_or_default());
+    });
+    NEXT_PENDING_SEND_ID.with(|state| {
+        state.set(next_pending_send_id.unwrap_or_default());
+    });
+    PAUSE_STATE.with(|state| {
+        state.replace(pause_state.unwrap_or_default());
+    });
+    KEY_NAMES.with(|state| {
+        state.replace(key_names.unwrap_or_default());
+    });
+    ADMIN_STATE.with(|state| {
+        state.replace(admin_state.unwrap_or_default());
+    });
+    CUSTODY_CANISTERS.with(|state| {
+        state.replace(custody_canisters.unwrap_or_default());
+    });
+    WITHDRAWAL_AUTHORIZATIONS.with(|state| {
+        state.replace(withdrawal_authorizations.unwrap_or_default());
+    });
+    LEDGERS.with(|state| {
+        state.replace(ledgers);
+    });
+    TRANSFER_AUTHORIZATIONS.with(|state| {
+        state.replace(transfer_authorizations);
+    });
+    TRANSFER_ALLOWANCES.with(|state| {
+        state.replace(transfer_allowances.unwrap_or_default());
+    });
+    // The custody wallet canisters of the versions without namespaces created their
+    // wallets with the raw derivation paths, which they keep so that their addresses
+    // do not change.
+    let namespaced = custody_clients.is_some();
+    CUSTODY_CLIENTS.with(|state| {
+        state.replace(custody_clients.unwrap_or_default());
+    });
+    for canister_id in CUSTODY_CANISTERS.with(|canisters| canisters.borrow().keys().cloned().collect::<Vec<_>>()) {
+        register_custody_id(canister_id, namespaced);
+    }
+    // The custody wallet canisters given at the upgrade have no wallets on this canister
+    // yet, so their derivation paths are prefixed like the ones given at installation.
+    if let Some(args) = args {
+        set_key_names(args.ecdsa_key_names.unwrap_or_default());
+        for canister_id in args.custody_ids.unwrap_or_default() {
+            register_custody_id(canister_id, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = NANOSECONDS_PER_DAY;
+
+    // The ID of the custody wallet canister registered by the tests.
+    fn test_custody_id() -> candid::Principal {
+        let custody_id = candid::Principal::from_slice(&[7]);
+        register_custody_id(custody_id, true);
+        custody_id
+    }
+
+    fn test_raw_transaction_info() -> RawTransactionInfo {
+        RawTransactionInfo {
+            transaction: vec![],
+            witness_script: vec![],
+            sig_hashes: vec![],
+            input_amounts: vec![],
+            protocol_version: None,
+            inputs: None,
+        }
+    }
+
+    #[test]
+    fn only_the_second_factor_approves_the_send_requests() {
+        let owner = candid::Principal::from_slice(&[1]);
+        let second_factor = candid::Principal::from_slice(&[2]);
+        check_no_second_factor(&owner);
+        assert!(std::panic::catch_unwind(|| insert_pending_send(owner, BitcoinNetwork::Regtest, test_custody_id(), test_raw_transaction_info())).is_err());
+
+        insert_second_factor(owner, second_factor);
+        assert_eq!(get_second_factor(owner), Some(second_factor));
+        assert!(std::panic::catch_unwind(|| check_no_second_factor(&owner)).is_err());
+        check_second_factor(owner, second_factor);
+        assert!(std::panic::catch_unwind(|| check_second_factor(owner, owner)).is_err());
+
+        // The second factor cannot be replaced by the owner.
+        assert!(std::panic::catch_unwind(|| insert_second_factor(owner, candid::Principal::from_slice(&[3]))).is_err());
+    }
+
+    #[test]
+    fn rejects_an_invalid_second_factor() {
+        let owner = candid::Principal::from_slice(&[1]);
+        assert!(std::panic::catch_unwind(|| insert_second_factor(owner, candid::Principal::anonymous())).is_err());
+        assert!(std::panic::catch_unwind(|| insert_second_factor(owner, owner)).is_err());
+        assert_eq!(get_second_factor(owner), None);
+    }
+
+    #[test]
+    fn lists_the_pending_sends_of_an_owner() {
+        let owner = candid::Principal::from_slice(&[1]);
+        let other_owner = candid::Principal::from_slice(&[2]);
+        insert_second_factor(owner, candid::Principal::from_slice(&[3]));
+        insert_second_factor(other_owner, candid::Principal::from_slice(&[5]));
+
+        let first_id = insert_pending_send(owner, BitcoinNetwork::Regtest, test_custody_id(), test_raw_transaction_info());
+        let other_id = insert_pending_send(other_owner, BitcoinNetwork::Regtest, test_custody_id(), test_raw_transaction_info());
+        let second_id = insert_pending_send(owner, BitcoinNetwork::Regtest, test_custody_id(), test_raw_transaction_info());
+        assert_eq!((first_id, other_id, second_id), (0, 1, 2));
+
+        let pending_ids: Vec<u64> = get_pending_sends(owner).into_iter().map(|(request_id, _)| request_id).collect();
+        assert_eq!(pending_ids, vec![first_id, second_id]);
+        assert_eq!(get_pending_send(other_id).owner, other_owner);
+        assert!(std::panic::catch_unwind(|| get_pending_send(3)).is_err());
+    }
+
+    #[test]
+    fn pauses_a_shared_wallet_apart_from_the_wallet_of_its_creator() {
+        let creator = candid::Principal::from_slice(&[1]);
+        let id = SharedWalletId { creator, name: String::from("wallet") };
+        PAUSE_STATE.with(|state| common::set_wallet_paused(&mut state.borrow_mut(), common::shared_wallet_derivation_path(&id), true));
+
+        assert!(std::panic::catch_unwind(|| check_not_paused(&common::shared_wallet_derivation_path(&id))).is_err());
+        check_not_paused(&vec![creator.as_slice().to_vec()]);
+    }
+
+    #[test]
+    fn rejects_an_empty_key_name() {
+        assert!(std::panic::catch_unwind(|| set_key_names(vec![(BitcoinNetwork::Testnet, String::new())])).is_err());
+        assert_eq!(get_key_name(BitcoinNetwork::Testnet), "key_1");
+
+        KEY_NAMES.with(|key_names| key_names.borrow_mut().insert(BitcoinNetwork::Testnet, String::from("test_key_1")));
+        assert_eq!(get_key_name(BitcoinNetwork::Testnet), "test_key_1");
+        assert_eq!(get_key_name(BitcoinNetwork::Regtest), "dfx_test_key");
+    }
+
+    #[test]
+    fn only_the_registered_custody_canisters_are_accepted() {
+        let custody_id = candid::Principal::from_slice(&[1]);
+        assert!(std::panic::catch_unwind(|| get_custody_client(&custody_id)).is_err());
+        register_custody_id(custody_id, true);
+        get_custody_client(&custody_id);
+        assert!(std::panic::catch_unwind(|| get_custody_client(&candid::Principal::from_slice(&[2]))).is_err());
+
+        // Registering a canister again keeps its pairing.
+        CUSTODY_CANISTERS.with(|canisters| canisters.borrow_mut().get_mut(&custody_id).unwrap().paired_at = Some(1));
+        register_custody_id(custody_id, false);
+        assert_eq!(get_pairing().len(), 1);
+        assert_eq!(get_pairing()[0].paired_at, Some(1));
+    }
+
+    #[test]
+    fn namespaces_the_derivation_paths_of_the_new_custody_canisters() {
+        let custody_id = test_custody_id();
+        let legacy_id = candid::Principal::from_slice(&[8]);
+        register_custody_id(legacy_id, false);
+        let derivation_path = vec![vec![1]];
+
+        assert_eq!(client_derivation_path(&custody_id, derivation_path.clone()), vec![custody_id.as_slice().to_vec(), vec![1]]);
+        assert_eq!(client_derivation_path(&legacy_id, derivation_path.clone()), derivation_path);
+
+        // A canister registered again keeps its namespace.
+        register_custody_id(legacy_id, true);
+        assert!(!get_custody_client(&legacy_id).namespaced);
+    }
+
+    #[test]
+    fn reserves_the_amounts_on_the_daily_quota_of_a_custody_canister() {
+        let custody_id = test_custody_id();
+        update_custody_client(&custody_id, |client| client.policy = ClientPolicy {
+            max_amount_per_transaction: Some(60_000),
+            daily_quota: Some(100_000),
+        });
+
+        assert!(std::panic::catch_unwind(|| reserve_client_quota(&custody_id, 60_001, DAY)).is_err());
+        reserve_client_quota(&custody_id, 60_000, DAY);
+        assert!(std::panic::catch_unwind(|| reserve_client_quota(&custody_id, 50_000, DAY)).is_err());
+
+        // The amounts of the transactions which failed to be signed are released.
+        record_client_signature(&custody_id, 60_000, false, DAY);
+        reserve_client_quota(&custody_id, 50_000, DAY);
+        record_client_signature(&custody_id, 50_000, true, DAY);
+        reserve_client_quota(&custody_id, 50_000, DAY);
+        assert!(std::panic::catch_unwind(|| reserve_client_quota(&custody_id, 1, DAY)).is_err());
+
+        // The quota is reset every day.
+        reserve_client_quota(&custody_id, 60_000, 2 * DAY);
+        let client = get_custody_client(&custody_id);
+        assert_eq!((client.quota_day, client.quota_used), (2, 60_000));
+        assert_eq!((client.stats.signed_transactions, client.stats.signed_amount, client.stats.failed_transactions), (1, 50_000, 1));
+    }
+
+    #[test]
+    fn shares_the_quotas_of_the_custody_canisters_not_namespaced() {
+        let namespaced_id = test_custody_id();
+        let legacy_ids = [candid::Principal::from_slice(&[10]), candid::Principal::from_slice(&[11])];
+        for legacy_id in legacy_ids {
+            register_custody_id(legacy_id, false);
+        }
+        update_custody_client(&legacy_ids[1], |client| client.policy.daily_quota = Some(100_000));
+
+        // The canisters not namespaced spend from the same wallets, so each amount is
+        // checked against the policies of all of them.
+        reserve_client_quota(&legacy_ids[0], 60_000, DAY);
+        reserve_client_quota(&namespaced_id, 60_000, DAY);
+        assert!(std::panic::catch_unwind(|| reserve_client_quota(&legacy_ids[0], 50_000, DAY)).is_err());
+        assert_eq!(get_custody_client(&legacy_ids[1]).quota_used, 60_000);
+        assert_eq!(get_custody_client(&namespaced_id).quota_used, 60_000);
+
+        record_client_signature(&legacy_ids[0], 60_000, false, DAY);
+        assert_eq!(get_custody_client(&legacy_ids[1]).quota_used, 0);
+        assert_eq!(get_custody_client(&legacy_ids[1]).stats.failed_transactions, 0);
+    }
+
+    #[test]
+    fn takes_the_transfers_from_the_allowance_of_their_sender() {
+        let custody_id = test_custody_id();
+        let sender = candid::Principal::from_slice(&[1]);
+        TRANSFER_ALLOWANCES.with(|allowances| allowances.borrow_mut().insert(sender, vec![
+            TransferAllowance { custody_id, bitcoin_network: BitcoinNetwork::Regtest, amount: 50_000 },
+        ]));
+
+        assert!(take_transfer_allowance(custody_id, BitcoinNetwork::Regtest, sender, 30_000).is_ok());
+        assert!(take_transfer_allowance(custody_id, BitcoinNetwork::Regtest, sender, 30_000).is_err());
+        assert!(take_transfer_allowance(custody_id, BitcoinNetwork::Testnet, sender, 10_000).is_err());
+        assert!(take_transfer_allowance(custody_id, BitcoinNetwork::Regtest, candid::Principal::from_slice(&[2]), 10_000).is_err());
+        assert!(take_transfer_allowance(custody_id, BitcoinNetwork::Regtest, sender, 20_000).is_ok());
+    }
+
+    #[test]
+    fn keeps_the_key_names_once_custody_canisters_are_registered() {
+        check_key_name(BitcoinNetwork::Testnet, "test_key_1");
+        test_custody_id();
+        assert!(std::panic::catch_unwind(|| check_key_name(BitcoinNetwork::Testnet, "test_key_1")).is_err());
+        check_key_name(BitcoinNetwork::Testnet, &get_key_name(BitcoinNetwork::Testnet));
+    }
+
+    #[test]
+    fn keeps_the_heirs_of_each_network_apart() {
+        let owner = candid::Principal::from_slice(&[1]);
+        let heir = candid::Principal::from_slice(&[2]);
+        INHERITANCES.with(|inheritances| {
+            common::set_heir(inheritances.borrow_mut().entry(BitcoinNetwork::Testnet).or_default(), owner, heir, 1, 0);
+        });
+        check_heir(BitcoinNetwork::Testnet, owner, heir, DAY);
+        assert!(std::panic::catch_unwind(|| check_heir(BitcoinNetwork::Mainnet, owner, heir, DAY)).is_err());
+    }
+}
@@ -8,7 +8,7 @@ import { frome8s, networkToString, networkToLogo } from './utils';
 import { canisterId as walletId }                  from "../declarations/custody_wallet";
 import { canisterId as fiduciaryId }               from "../declarations/fiduciary";
 import { _SERVICE as FiduciaryService }            from '../declarations/fiduciary/fiduciary.did';
-import { network }                                 from '../declarations/custody_wallet/custody_wallet.did';
+import { network, input_signing_error }            from '../declarations/custody_wallet/custody_wallet.did';
 
 import NumberInput                                 from './components/NumberInput';
 import Title                                       from './components/Title';
@@ -49,6 +49,7 @@ import FormControl                                 from '@mui/material/FormContr
 import Select                                      from '@mui/material/Select';
 
 import { ActorSubclass }                           from '@dfinity/agent';
+import { Principal }                               from '@dfinity/principal';
 
 import React, { useEffect, useState }              from 'react';
 
@@ -99,8 +100,8 @@ function App() {
   const [sentOutput,     setSentOutput    ] = useState<string>             (""       );
 
   const refreshNetwork = async () => {
-    let network = await walletActor?.get_network();
-    setBitcoinNetwork(network);
+    let networks = await walletActor?.get_networks();
+    setBitcoinNetwork(networks?.[0]);
   }
 
   const refreshWalletKey = async () => {
@@ -120,28 +121,38 @@ function App() {
   }
 
   const refreshUserAddress = async () => {
-    let address = isAuthenticated ? await walletActor?.get_wallet_address() : undefined;
+    let address = isAuthenticated && bitcoinNetwork !== undefined ? await walletActor?.get_wallet_address(bitcoinNetwork) : undefined;
     setUserAddress(address);
   }
 
   const refreshBalance = () => {
     setBalanceSats(undefined);
-    if (userAddress !== undefined){
-      walletActor?.get_balance(userAddress).then((balance) => {
+    if (userAddress !== undefined && bitcoinNetwork !== undefined){
+      walletActor?.get_balance(bitcoinNetwork, userAddress).then((balance) => {
         setBalanceSats(balance);
       });
     }
   }
 
+  const formatSigningErrors = (errors: input_signing_error[]) => {
+    return errors.map((error) => "Failed to sign input " + error.input_index.toString() + ": " + error.message).join("\n");
+  }
+
   const walletSend = () => {
     if (!canSend){
       throw new Error("Initial conditions are not met");
     }
     setSendLoading(true);
-    walletActor?.init_send_request({destination_address: destination, amount_in_satoshi: amount}).then(async (raw_transaction_info) => {
-      const tx_id = await (fiduciaryActor as ActorSubclass<FiduciaryService>).finalize_send_request(bitcoinNetwork as network, raw_transaction_info);
+    walletActor?.init_send_request(bitcoinNetwork as network, {destination_address: destination, amount_in_satoshi: amount, export_psbt: [], shared_wallet: []}).then(async (init_result) => {
+      if ('Err' in init_result){
+        throw new Error(formatSigningErrors(init_result.Err.failed_inputs));
+      }
+      const finalize_result = await (fiduciaryActor as ActorSubclass<FiduciaryService>).finalize_send_request(bitcoinNetwork as network, Principal.fromText(walletId), init_result.Ok.raw_transaction_info);
+      if ('Err' in finalize_result){
+        throw new Error(formatSigningErrors(finalize_result.Err));
+      }
       setSentSuccess(true);
-      setSentOutput(tx_id);
+      setSentOutput(finalize_result.Ok);
     }).catch((error) => {
       setSentSuccess(false);
       setSentOutput(error.toString());
@@ -150,16 +161,17 @@ function App() {
     });
   }
 
-  // Refresh the wallet address on wallet actor change
+  // Refresh the network and the wallet address on wallet actor change
   useEffect(() => {
     refreshNetwork();  
     refreshUserAddress();
   }, [walletActor]);
 
-  // Refresh the keys on bitcoin network change
+  // Refresh the keys and the wallet address on bitcoin network change
   useEffect(() => {
     refreshWalletKey();
     refreshFiduciaryKey();
+    refreshUserAddress();
   }, [bitcoinNetwork]);
 
   // Refresh the balance on user address change
@@ -6,11 +6,39 @@ use ic_cdk::api::management_canister::bitcoin::{
 };
 
 // The fees for the various bitcoin endpoints.
-const GET_BALANCE_COST_CYCLES: u64 = 100_000_000;
-const GET_UTXOS_COST_CYCLES: u64 = 10_000_000_000;
-const GET_CURRENT_FEE_PERCENTILES_CYCLES: u64 = 100_000_000;
-const SEND_TRANSACTION_BASE_CYCLES: u64 = 5_000_000_000;
-const SEND_TRANSACTION_PER_BYTE_CYCLES: u64 = 20_000_000;
+struct BitcoinApiCosts {
+    get_balance: u64,
+    get_utxos: u64,
+    get_current_fee_percentiles: u64,
+    send_transaction_base: u64,
+    send_transaction_per_byte: u64,
+}
+
+// The fees on the bitcoin mainnet, whose API is served by a 34-node subnet.
+const MAINNET_COSTS: BitcoinApiCosts = BitcoinApiCosts {
+    get_balance: 100_000_000,
+    get_utxos: 10_000_000_000,
+    get_current_fee_percentiles: 100_000_000,
+    send_transaction_base: 5_000_000_000,
+    send_transaction_per_byte: 20_000_000,
+};
+
+// The fees on the bitcoin testnet and regtest, whose API is served by a 13-node subnet.
+const TESTNET_COSTS: BitcoinApiCosts = BitcoinApiCosts {
+    get_balance: 40_000_000,
+    get_utxos: 4_000_000_000,
+    get_current_fee_percentiles: 40_000_000,
+    send_transaction_base: 2_000_000_000,
+    send_transaction_per_byte: 8_000_000,
+};
+
+// Get the fees of the bitcoin endpoints for the given network.
+fn costs(network: BitcoinNetwork) -> &'static BitcoinApiCosts {
+    match network {
+        BitcoinNetwork::Mainnet => &MAINNET_COSTS,
+        BitcoinNetwork::Testnet | BitcoinNetwork::Regtest => &TESTNET_COSTS,
+    }
+}
 
 /// Returns the balance of the given bitcoin address.
 ///
@@ -25,7 +53,7 @@ pub async fn get_balance(network: BitcoinNetwork, address: String) -> u64 {
             network: network.into(),
             min_confirmations: None,
         },),
-        GET_BALANCE_COST_CYCLES,
+        costs(network).get_balance,
     )
     .await;
 
@@ -45,7 +73,7 @@ pub async fn get_utxos(network: BitcoinNetwork, address: String) -> GetUtxosResp
             network: network.into(),
             filter: None,
         },),
-        GET_UTXOS_COST_CYCLES,
+        costs(network).get_utxos,
     )
     .await;
 
@@ -64,7 +92,7 @@ pub async fn get_current_fee_percentiles(network: BitcoinNetwork) -> Vec<Millisa
         (GetCurrentFeePercentilesRequest {
             network: network.into(),
         },),
-        GET_CURRENT_FEE_PERCENTILES_CYCLES,
+        costs(network).get_current_fee_percentiles,
     )
     .await;
 
@@ -75,9 +103,9 @@ pub async fn get_current_fee_percentiles(network: BitcoinNetwork) -> Vec<Millisa
 ///
 /// Relies on the `bitcoin_send_transaction` endpoint.
 /// See https://internetcomputer.org/docs/current/references/ic-interface-spec/#ic-bitcoin_send_transaction
-pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) {
-    let transaction_fee = SEND_TRANSACTION_BASE_CYCLES
-        + (transaction.len() as u64) * SEND_TRANSACTION_PER_BYTE_CYCLES;
+pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) -> Result<(), String> {
+    let transaction_fee = costs(network).send_transaction_base
+        + (transaction.len() as u64) * costs(network).send_transaction_per_byte;
 
     let res: Result<(), _> = call_with_payment(
         Principal::management_canister(),
@@ -90,5 +118,5 @@ pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) {
     )
     .await;
 
-    res.unwrap();
+    res.map_err(|(code, message)| format!("Failed to send the transaction: {:?} {}", code, message))
 }
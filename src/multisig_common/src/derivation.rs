@@ -0,0 +1,117 @@
+use crate::types::ECDSAPublicKeyReply;
+use bitcoin::hashes::{hmac, sha512, Hash, HashEngine};
+use secp256k1::{PublicKey, Secp256k1, Verification};
+
+/// Derives the public key and chain code at the given derivation path, starting
+/// from the given extended public key.
+///
+/// This follows the (non-hardened, BIP32-like) derivation scheme of the threshold
+/// ECDSA API of the IC, where each element of the derivation path can be of any length.
+/// Given the reply of `ecdsa_public_key` for an empty derivation path (i.e. the root
+/// key of the canister), it returns the same public key and chain code as the
+/// management canister would for the given derivation path.
+pub fn derive_public_key(root: &ECDSAPublicKeyReply, derivation_path: &Vec<Vec<u8>>) -> ECDSAPublicKeyReply {
+    let secp = Secp256k1::verification_only();
+
+    let mut public_key = PublicKey::from_slice(&root.public_key)
+        .expect("Invalid root public key.");
+    let mut chain_code = root.chain_code.clone();
+
+    for index in derivation_path {
+        (public_key, chain_code) = derive_child_key(&secp, &public_key, &chain_code, index);
+    }
+
+    ECDSAPublicKeyReply {
+        public_key: public_key.serialize().to_vec(),
+        chain_code,
+    }
+}
+
+// Derives the child public key and chain code for the given index.
+fn derive_child_key<C: Verification>(
+    secp: &Secp256k1<C>,
+    public_key: &PublicKey,
+    chain_code: &[u8],
+    index: &[u8],
+) -> (PublicKey, Vec<u8>) {
+    let mut key = chain_code.to_vec();
+    let mut input = public_key.serialize().to_vec();
+    loop {
+        let mut engine = hmac::HmacEngine::<sha512::Hash>::new(&key);
+        engine.input(&input);
+        engine.input(index);
+        let output = hmac::Hmac::<sha512::Hash>::from_engine(engine).to_byte_array();
+        let (offset, next_chain_code) = output.split_at(32);
+
+        let mut child_key = *public_key;
+        match child_key.add_exp_assign(secp, offset) {
+            Ok(()) => return (child_key, next_chain_code.to_vec()),
+            // The offset is not a valid scalar or the child key is the point at
+            // infinity: retry as the IC does, keeping the index but keyed with the
+            // next chain code, and with `0x01 || next_chain_code` in place of the key.
+            Err(_) => {
+                key = next_chain_code.to_vec();
+                input = [&[0x01_u8][..], next_chain_code].concat();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The root key of the vectors below, whose secret key is the SHA-256 hash of "root".
+    // The expected keys and chain codes were computed with `PublicKey::derive_subkey_with_chain_code`
+    // of the ic-secp256k1 crate, version 0.3.0, which DFINITY publishes from the IC repository
+    // (https://github.com/dfinity/ic, packages/ic-secp256k1) as the derivation of the
+    // threshold ECDSA keys of the management canister.
+    fn root() -> ECDSAPublicKeyReply {
+        ECDSAPublicKeyReply {
+            public_key: hex::decode("026fef36847d1538016251759ee198c0f0ee252d579a276dab96132b0703875cad").unwrap(),
+            chain_code: (0..32).collect(),
+        }
+    }
+
+    fn check_vector(derivation_path: Vec<Vec<u8>>, public_key: &str, chain_code: &str) {
+        let derived = derive_public_key(&root(), &derivation_path);
+        assert_eq!(hex::encode(derived.public_key), public_key);
+        assert_eq!(hex::encode(derived.chain_code), chain_code);
+    }
+
+    #[test]
+    fn empty_path_is_the_root_key() {
+        let root = root();
+        check_vector(vec![], &hex::encode(&root.public_key), &hex::encode(&root.chain_code));
+    }
+
+    #[test]
+    fn derives_known_vectors() {
+        check_vector(
+            vec![vec![1, 2, 3]],
+            "03b05d72f7ae5bec73bf0a1d2155f15dc2182f735d5128384d145b1964f0fdfa01",
+            "52602e9c4d0433505480860a4c03158c4ff361567b78309fe7b27788585f0009");
+        check_vector(
+            vec![vec![0x2a; 29], b"savings".to_vec()],
+            "021f828bfc4813438b01441fad867fdf5b09d591d5a3c0e88f246f5bfe1140c72a",
+            "69a536a7975fea17f634fe92a9062a0c9ffdc15d8e6333a99907dc1d926c8e32");
+        check_vector(
+            vec![vec![]],
+            "0234ca0b2e01344c4fa62ee1d82501c1b54694cf3d666939bcca6b1aa81ee967d8",
+            "9910807dcfd5a3a6040a71d53259eabb7c18b17e3a568b3bc7dfee8befecb5c3");
+        // A path prefixed with the ID of a custody wallet canister, as derived by the fiduciary.
+        check_vector(
+            vec![vec![0x2b; 10], vec![0x2a; 29], b"savings".to_vec()],
+            "02289126963ba471976e9cf022d4fa765798768ef591952ea2b17fe905c5d67f6e",
+            "3d59c8583b62cca8fc6032bd86bc885c799c5a5bb67a25ecb9c56d48d5780b42");
+    }
+
+    #[test]
+    fn derives_each_element_from_the_previous_key() {
+        let parent = derive_public_key(&root(), &vec![vec![0x2a; 29]]);
+        let child = derive_public_key(&parent, &vec![b"savings".to_vec()]);
+        let derived = derive_public_key(&root(), &vec![vec![0x2a; 29], b"savings".to_vec()]);
+        assert_eq!(child.public_key, derived.public_key);
+        assert_eq!(child.chain_code, derived.chain_code);
+    }
+}
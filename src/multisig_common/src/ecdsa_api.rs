@@ -5,8 +5,30 @@ use ic_cdk::{api::call::call_with_payment, call};
 // The fee for the `sign_with_ecdsa` endpoint using the test key.
 const SIGN_WITH_ECDSA_COST_CYCLES: u64 = 25_000_000_000;
 
+// The fee for the `sign_with_ecdsa` endpoint using the production key, held by a 34-node subnet.
+const SIGN_WITH_ECDSA_PRODUCTION_KEY_COST_CYCLES: u64 = 26_153_846_153;
+
+/// The name of the production ECDSA key, the only one to be used on the bitcoin mainnet.
+pub const PRODUCTION_KEY_NAME: &str = "key_1";
+
 /// Returns the ECDSA public key of this canister at the given derivation path.
 pub async fn ecdsa_public_key(key_name: String, derivation_path: Vec<Vec<u8>>, canister_id: Option<Principal>) -> Vec<u8> {
+    ecdsa_public_key_reply(key_name, derivation_path, canister_id)
+        .await
+        .public_key
+}
+
+/// Returns the ECDSA public key of this canister at the given derivation path,
+/// along with its chain code.
+pub async fn ecdsa_public_key_reply(key_name: String, derivation_path: Vec<Vec<u8>>, canister_id: Option<Principal>) -> ECDSAPublicKeyReply {
+    try_ecdsa_public_key_reply(key_name, derivation_path, canister_id)
+        .await
+        .unwrap()
+}
+
+/// Returns the ECDSA public key of this canister at the given derivation path,
+/// along with its chain code, or the reason why the ECDSA API failed to return it.
+pub async fn try_ecdsa_public_key_reply(key_name: String, derivation_path: Vec<Vec<u8>>, canister_id: Option<Principal>) -> Result<ECDSAPublicKeyReply, String> {
     // Retrieve the public key of this canister at the given derivation path
     // from the ECDSA API.
     let res: Result<(ECDSAPublicKeyReply,), _> = call(
@@ -23,14 +45,23 @@ pub async fn ecdsa_public_key(key_name: String, derivation_path: Vec<Vec<u8>>, c
     )
     .await;
 
-    res.unwrap().0.public_key
+    match res {
+        Ok((reply,)) => Ok(reply),
+        Err((code, message)) => Err(format!("Failed to get the ECDSA public key (code {:?}): {}", code, message)),
+    }
 }
 
+/// Returns the signature of the given message hash, or the reason why
+/// the ECDSA API failed to sign it.
 pub async fn sign_with_ecdsa(
     key_name: String,
     derivation_path: Vec<Vec<u8>>,
     message_hash: Vec<u8>,
-) -> Vec<u8> {
+) -> Result<Vec<u8>, String> {
+    let cost_cycles = match key_name.as_str() {
+        PRODUCTION_KEY_NAME => SIGN_WITH_ECDSA_PRODUCTION_KEY_COST_CYCLES,
+        _ => SIGN_WITH_ECDSA_COST_CYCLES,
+    };
     let res: Result<(SignWithECDSAReply,), _> = call_with_payment(
         Principal::management_canister(),
         "sign_with_ecdsa",
@@ -42,9 +73,12 @@ pub async fn sign_with_ecdsa(
                 name: key_name,
             },
         },),
-        SIGN_WITH_ECDSA_COST_CYCLES,
+        cost_cycles,
     )
     .await;
 
-    res.unwrap().0.signature
+    match res {
+        Ok((reply,)) => Ok(reply.signature),
+        Err((code, message)) => Err(format!("Failed to sign with ECDSA (code {:?}): {}", code, message)),
+    }
 }
@@ -1,19 +1,28 @@
 mod bitcoin_api;
+mod derivation;
 mod ecdsa_api;
+mod policy;
+mod registry_api;
 
 pub mod types;
 
 pub mod common {
 
     use crate::bitcoin_api;
+    use crate::derivation;
     use crate::ecdsa_api;
+    use crate::policy;
+    use crate::registry_api;
     use crate::types::*;
 
     use bitcoin::SegwitV0Sighash;
     use bitcoin::Sequence;
     use bitcoin::absolute::LockTime;
     use bitcoin::address::NetworkChecked;
+    use bitcoin::psbt::Psbt;
+    use bitcoin::secp256k1::{ecdsa::Signature, Message, Secp256k1};
     use candid::Principal;
+    use futures::future::join_all;
     use secp256k1::PublicKey;
     use bitcoin::{
         blockdata::witness::Witness,
@@ -26,12 +35,48 @@ pub mod common {
         sighash,
     };
     use ic_cdk::api::management_canister::bitcoin::{MillisatoshiPerByte, BitcoinNetwork, Satoshi, Utxo};
+    use ic_cdk::api::call::RejectionCode;
     use ic_cdk::{call, print};
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap};
     use std::str::FromStr;
 
+    pub use crate::ecdsa_api::PRODUCTION_KEY_NAME;
+
+    /// The version of the protocol between the custody wallet and the fiduciary canister,
+    /// i.e. the latest one spoken by this canister.
+    pub const PROTOCOL_VERSION: u32 = 5;
+
+    /// The oldest version of the protocol still spoken by this canister, so that it can
+    /// talk to a peer not upgraded yet.
+    pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+    // The first version of the protocol wrapping the calls to the fiduciary canister
+    // in envelopes, see `call_fiduciary`.
+    const ENVELOPE_PROTOCOL_VERSION: u32 = 2;
+
+    // The first version of the protocol in which the fiduciary canister co-signs batches
+    // of withdrawals, see `send_batch_to_fiduciary`.
+    const BATCH_PROTOCOL_VERSION: u32 = 4;
+
+    // The first version of the protocol in which the fiduciary canister keeps the internal
+    // ledger of transfers between users, see `book_fiduciary_transfer`.
+    const LEDGER_PROTOCOL_VERSION: u32 = 5;
+
     const SIG_HASH_TYPE: EcdsaSighashType = EcdsaSighashType::All;
 
+    // Maximum number of signatures requested concurrently to the ECDSA API.
+    const MAX_CONCURRENT_SIGNATURES: usize = 10;
+
+    // Time during which the fee percentiles are reused instead of being
+    // requested again to the bitcoin API (i.e. roughly one block).
+    const FEE_PERCENTILES_TTL_NS: u64 = 10 * 60 * 1_000_000_000;
+
+    // Maximum length of the name of a shared wallet, in bytes.
+    const MAX_SHARED_WALLET_NAME_LENGTH: usize = 64;
+
+    // Assume that any amount below this threshold is dust.
+    const DUST_THRESHOLD: u64 = 1_000;
+
     // Utility function to translate the bitcoin network from the IC cdk 
     // to the bitoin network of the rust-bitcoin library.
     fn match_network(bitcoin_network: BitcoinNetwork) -> Network {
@@ -42,34 +87,85 @@ pub mod common {
         }
     }
 
-    // Index of the public key used to sign the transaction.
-    // Only supports 2-by-2 multisig.
+    // Position of the signature being inserted in the witness of the transaction.
+    // The first signature starts a partial witness holding the signatures collected
+    // so far, and the last one completes it into the witness satisfying the script.
     #[derive(PartialEq)]
     pub enum MultisigIndex {
         First,
         Last,
     }
 
+    // Path used to spend the outputs of a wallet.
+    #[derive(Clone, Copy, PartialEq)]
+    pub enum SpendPath {
+        // With the signatures of the canisters, or any path without timelock.
+        Multisig,
+        // With the signature of the user key alone, once the recovery delay
+        // has elapsed since the outputs were created.
+        Recovery,
+    }
+
+    // Information about an input of a transaction, required to sign it and build its witness.
+    #[derive(Clone)]
+    pub struct InputInfo {
+        // The witness script of the output spent.
+        pub witness_script: ScriptBuf,
+        // The derivation path of the wallet of the output spent, if known, i.e. unless
+        // the input comes from a PSBT or a peer of the first versions of the protocol.
+        pub derivation_path: Option<Vec<Vec<u8>>>,
+        // The amount of the output spent.
+        pub amount: Amount,
+        // The type of the script of the output spent.
+        pub script_type: ScriptType,
+        // The sighash of the input, computed from the fields above.
+        pub sig_hash: SegwitV0Sighash,
+    }
+
+    // The witness script, derivation path (if known) and amount of the output spent by an input.
+    pub type SpentOutput = (ScriptBuf, Option<Vec<Vec<u8>>>, Amount);
+
     // Output of the build_transaction function.
-    // Contains the data required to sign the multisig transaction and build the witness.
+    // Contains the data required to sign the multisig transaction and build the witness,
+    // for each input, so that a transaction can spend from several wallets.
     #[derive(Clone)]
     pub struct TransactionInfo {
         transaction: Transaction,
-        witness_script: ScriptBuf,
-        sig_hashes: Vec<SegwitV0Sighash>,
+        inputs: Vec<InputInfo>,
     }
 
     impl TransactionInfo {
         
-        // Constructor.
-        pub fn new(transaction: Transaction, witness_script: ScriptBuf, sig_hashes: Vec<SegwitV0Sighash>) -> Self {
-            if transaction.input.len() != sig_hashes.len() {
-                panic!("Transaction inputs and sighashes must have the same length.");
+        // Constructor, from the given transaction and the witness script, derivation path
+        // and amount of the output spent by each of its inputs. The sighashes are computed
+        // from them.
+        pub fn new(transaction: Transaction, inputs: Vec<SpentOutput>) -> Self {
+            if transaction.input.len() != inputs.len() {
+                panic!("Transaction inputs and amounts must have the same length.");
             }
+            let mut cache = sighash::SighashCache::new(&transaction);
+            let inputs = inputs
+                .into_iter()
+                .enumerate()
+                .map(|(input_index, (witness_script, derivation_path, amount))| {
+                    let sig_hash = cache.p2wsh_signature_hash(
+                        input_index,
+                        &witness_script,
+                        amount,
+                        SIG_HASH_TYPE,
+                    ).expect("failed to compute sighash");
+                    InputInfo {
+                        witness_script,
+                        derivation_path,
+                        amount,
+                        script_type: ScriptType::P2wsh,
+                        sig_hash,
+                    }
+                })
+                .collect();
             TransactionInfo {
                 transaction,
-                witness_script,
-                sig_hashes,
+                inputs,
             }
         }
 
@@ -78,55 +174,266 @@ pub mod common {
             &self.transaction
         }
 
-        // Get the witness script
-        pub fn witness_script(&self) -> &ScriptBuf {
-            &self.witness_script
+        // Get the information about each input
+        pub fn inputs(&self) -> &Vec<InputInfo> {
+            &self.inputs
+        }
+
+        // Get the amount sent to other addresses than the ones spent by the inputs,
+        // i.e. without the change.
+        pub fn sent_amount(&self) -> Amount {
+            let script_pubkeys: Vec<ScriptBuf> = self.inputs
+                .iter()
+                .map(|input| ScriptBuf::new_p2wsh(&input.witness_script.wscript_hash()))
+                .collect();
+            self.transaction.output
+                .iter()
+                .filter(|output| !script_pubkeys.contains(&output.script_pubkey))
+                .fold(Amount::ZERO, |total, output| total + output.value)
+        }
+
+        // Get the amount spent from the wallets of the inputs, i.e. the amount sent with
+        // the fee, without the change.
+        pub fn spent_amount(&self) -> Amount {
+            let script_pubkeys: Vec<ScriptBuf> = self.inputs
+                .iter()
+                .map(|input| ScriptBuf::new_p2wsh(&input.witness_script.wscript_hash()))
+                .collect();
+            let change = self.transaction.output
+                .iter()
+                .filter(|output| script_pubkeys.contains(&output.script_pubkey))
+                .fold(Amount::ZERO, |total, output| total + output.value);
+            self.inputs
+                .iter()
+                .fold(Amount::ZERO, |total, input| total + input.amount) - change
         }
 
-        // Get the sighashes
-        pub fn sig_hashes(&self) -> &Vec<SegwitV0Sighash> {
-            &self.sig_hashes
+        // Check that the inputs whose derivation path is known spend from the wallet
+        // with the given derivation path.
+        pub fn check_derivation_path(&self, derivation_path: &Vec<Vec<u8>>) {
+            if self.inputs.iter().any(|input| input.derivation_path.as_ref().map_or(false, |path| path != derivation_path)) {
+                panic!("The transaction spends from another wallet than the one of the signer.");
+            }
         }
 
-        // Constructor from raw transaction info
+        // Constructor from raw transaction info, built by a canister speaking a version
+        // of the protocol spoken by this canister. The raw transaction infos of the first
+        // version have no protocol version, and those of the first two versions have the
+        // same witness script for all the inputs. The sighashes are computed again from the
+        // inputs and checked against the given ones, so that the signers only sign the
+        // witness scripts and amounts they see.
         pub fn from_raw(raw_transaction_info: RawTransactionInfo) -> Self {
+            let protocol_version = raw_transaction_info.protocol_version.unwrap_or(MIN_PROTOCOL_VERSION);
+            check_protocol_version(protocol_version)
+                .unwrap_or_else(|error| panic!("{}", error));
             let transaction = consensus::deserialize(&raw_transaction_info.transaction)
                 .unwrap();
-            let witness_script = ScriptBuf::from(raw_transaction_info.witness_script);
-            let sig_hashes: Vec<SegwitV0Sighash> = raw_transaction_info.sig_hashes
-                .into_iter()
-                .map(|s| SegwitV0Sighash::from_byte_array(s.try_into().unwrap()))
+            let inputs = match raw_transaction_info.inputs {
+                Some(inputs) => inputs
+                    .into_iter()
+                    .map(|input| match input.script_type {
+                        ScriptType::P2wsh => (ScriptBuf::from(input.witness_script), Some(input.derivation_path), Amount::from_sat(input.amount)),
+                    })
+                    .collect(),
+                None => raw_transaction_info.input_amounts
+                    .into_iter()
+                    .map(|amount| (ScriptBuf::from(raw_transaction_info.witness_script.clone()), None, Amount::from_sat(amount)))
+                    .collect(),
+            };
+            let transaction_info = TransactionInfo::new(transaction, inputs);
+            let sig_hashes: Vec<Vec<u8>> = transaction_info.inputs
+                .iter()
+                .map(|input| input.sig_hash.to_byte_array().to_vec())
                 .collect();
-            TransactionInfo::new(transaction, witness_script, sig_hashes)
+            if sig_hashes != raw_transaction_info.sig_hashes {
+                panic!("The sighashes do not match the inputs of the transaction.");
+            }
+            transaction_info
         }
 
-        // Get the raw transaction info
+        // Get the raw transaction info. The witness script of the first input is also
+        // given alone, for the peers of the first two versions of the protocol.
         pub fn to_raw(&self) -> RawTransactionInfo {
             let transaction = consensus::serialize(&self.transaction);
-            let witness_script = self.witness_script
-                .clone()
-                .into_bytes();
-            let sig_hashes = self.sig_hashes
+            let witness_script = self.inputs
+                .first()
+                .map(|input| input.witness_script.clone().into_bytes())
+                .unwrap_or_default();
+            let sig_hashes = self.inputs
+                .iter()
+                .map(|input| input.sig_hash.to_byte_array().to_vec())
+                .collect();
+            let input_amounts = self.inputs
+                .iter()
+                .map(|input| input.amount.to_sat())
+                .collect();
+            let inputs = self.inputs
                 .iter()
-                .map(|s| s.to_byte_array().to_vec())
+                .map(|input| RawInputInfo {
+                    witness_script: input.witness_script.clone().into_bytes(),
+                    derivation_path: input.derivation_path.clone().unwrap_or_default(),
+                    amount: input.amount.to_sat(),
+                    script_type: input.script_type,
+                })
                 .collect();
             RawTransactionInfo {
                 transaction,
                 witness_script,
                 sig_hashes,
+                input_amounts,
+                protocol_version: Some(PROTOCOL_VERSION),
+                inputs: Some(inputs),
+            }
+        }
+
+        // Get the PSBT (BIP174) of the unsigned transaction, with the witness UTXO and
+        // witness script of each input.
+        pub fn to_psbt(&self) -> Psbt {
+            // The transaction of a PSBT must not contain any signature.
+            let mut transaction = self.transaction.clone();
+            for input in transaction.input.iter_mut() {
+                input.witness.clear();
+                input.script_sig = ScriptBuf::new();
+            }
+
+            let mut psbt = Psbt::from_unsigned_tx(transaction)
+                .expect("Failed to create the PSBT from the unsigned transaction.");
+
+            for (input, input_info) in psbt.inputs.iter_mut().zip(self.inputs.iter()) {
+                input.witness_utxo = Some(TxOut {
+                    value: input_info.amount,
+                    script_pubkey: ScriptBuf::new_p2wsh(&input_info.witness_script.wscript_hash()),
+                });
+                input.witness_script = Some(input_info.witness_script.clone());
+                input.sighash_type = Some(SIG_HASH_TYPE.into());
             }
+
+            psbt
+        }
+
+        // Add the given SEC1 signatures as partial signatures of the given PSBT, for
+        // the public key at the given index in the witness script of each input.
+        pub fn add_partial_signatures(&self, psbt: &mut Psbt, key_index: usize, sec1_signatures: &[Vec<u8>]) {
+            if psbt.inputs.len() != sec1_signatures.len() {
+                panic!("PSBT inputs and signatures must have the same length.");
+            }
+
+            for ((input, input_info), sec1_signature) in psbt.inputs.iter_mut().zip(self.inputs.iter()).zip(sec1_signatures.iter()) {
+                let public_key = signer_public_key(&input_info.witness_script, key_index);
+                input.partial_sigs.insert(public_key, bitcoin::ecdsa::Signature {
+                    sig: Signature::from_compact(sec1_signature)
+                        .expect("Invalid SEC1 signature."),
+                    hash_ty: SIG_HASH_TYPE,
+                });
+            }
+        }
+
+        // Constructor from a PSBT (BIP174), whose inputs must all spend from the same
+        // witness script. The sighashes are computed from the witness UTXO and witness
+        // script of each input.
+        // Returns the transaction info and the SEC1 signatures found in the partial
+        // signatures, by index of their public key in the witness script. Only the
+        // signers who signed all the inputs are returned, and their signatures are
+        // verified against the sighashes.
+        pub fn from_psbt(psbt: &Psbt) -> (Self, BTreeMap<usize, Vec<Vec<u8>>>) {
+            
+            let witness_script = match psbt.inputs.first().and_then(|input| input.witness_script.clone()) {
+                Some(witness_script) => witness_script,
+                None => panic!("The PSBT inputs must have a witness script."),
+            };
+            let script_pubkey = ScriptBuf::new_p2wsh(&witness_script.wscript_hash());
+
+            let mut inputs = vec![];
+            for input in psbt.inputs.iter() {
+                if input.witness_script.as_ref() != Some(&witness_script) {
+                    panic!("All the PSBT inputs must be spent from the same witness script.");
+                }
+                match &input.witness_utxo {
+                    Some(utxo) if utxo.script_pubkey == script_pubkey => inputs.push((witness_script.clone(), None, utxo.value)),
+                    _ => panic!("The PSBT inputs must have a witness UTXO matching the witness script."),
+                }
+            }
+
+            let transaction_info = TransactionInfo::new(psbt.unsigned_tx.clone(), inputs);
+
+            // Get and verify the signatures of each signer of the witness script.
+            let secp = Secp256k1::verification_only();
+            let mut partial_signatures = BTreeMap::new();
+            for key_index in 0..witness_script_public_keys(&witness_script).len() {
+                let public_key = signer_public_key(&witness_script, key_index);
+                let mut sec1_signatures = vec![];
+                for (input, input_info) in psbt.inputs.iter().zip(transaction_info.inputs.iter()) {
+                    let signature = match input.partial_sigs.get(&public_key) {
+                        Some(signature) => signature,
+                        None => break,
+                    };
+                    let message = Message::from_digest_slice(&input_info.sig_hash.to_byte_array()).unwrap();
+                    if secp.verify_ecdsa(&message, &signature.sig, &public_key.inner).is_err() {
+                        panic!("Invalid partial signature of {}.", public_key);
+                    }
+                    sec1_signatures.push(signature.sig.serialize_compact().to_vec());
+                }
+                if sec1_signatures.len() == psbt.inputs.len() {
+                    partial_signatures.insert(key_index, sec1_signatures);
+                }
+            }
+
+            (transaction_info, partial_signatures)
         }
     }
 
+    // Get the public key at the given index in the given multisig witness script.
+    fn signer_public_key(witness_script: &ScriptBuf, key_index: usize) -> bitcoin::PublicKey {
+        let public_keys = witness_script_public_keys(witness_script);
+        bitcoin::PublicKey::from_slice(public_keys.get(key_index).expect("The witness script has no public key at this index."))
+            .expect("Invalid public key in the witness script.")
+    }
+
+    /// Get the public keys of the given witness script, in the order they appear.
+    pub fn witness_script_public_keys(witness_script: &ScriptBuf) -> Vec<Vec<u8>> {
+        policy::public_keys(witness_script)
+            .unwrap_or_else(|error| panic!("{}", error))
+            .iter()
+            .map(|public_key| public_key.to_bytes())
+            .collect()
+    }
+
     // Information about a user wallet.
     #[derive(Clone)]
     pub struct UserWallet {
-        // The witness script of the 2-of-2 multisig wallet.
+        // The witness script of the wallet.
         pub witness_script: ScriptBuf,
         // The wallet address.
         pub address: Address<NetworkChecked>,
         // The derivation path of the wallet, derived from the user's principal.
         pub derivation_path: Vec<Vec<u8>>,
+        // The public key held by the user, if the wallet is a 2-of-3 multisig
+        // where the user is the third signer, or has a recovery path.
+        pub user_public_key: Option<Vec<u8>>,
+        // The relative timelock (in blocks) after which the user key alone can spend
+        // the outputs of the wallet, if the wallet has a recovery path.
+        pub recovery_delay: Option<u16>,
+        // The miniscript policy the witness script is compiled from, if the wallet
+        // has a custom policy.
+        pub policy: Option<String>,
+    }
+
+    // Information about a shared wallet.
+    #[derive(Clone)]
+    pub struct SharedWallet {
+        // The wallet itself.
+        pub wallet: UserWallet,
+        // The principals who can propose and approve send requests.
+        pub members: Vec<Principal>,
+        // The number of approvals required before signing a send request.
+        pub threshold: u64,
+    }
+
+    // Fee percentiles obtained from the bitcoin API, with the time they were obtained.
+    #[derive(Clone)]
+    pub struct CachedFeePercentiles {
+        pub fee_percentiles: Vec<MillisatoshiPerByte>,
+        pub timestamp: u64,
     }
 
     // Main data structure. Contains the user wallets and the 
@@ -139,8 +446,57 @@ pub mod common {
         pub key_name: String,
         // The principal of the fiduciary canister.
         pub fiduciary_canister: candid::Principal,
+        // The root public key and chain code of this canister, used to derive
+        // the public keys of the user wallets locally.
+        pub root_public_key: Option<ECDSAPublicKeyReply>,
         // The user wallets.
         pub user_wallets: HashMap<candid::Principal, UserWallet>,
+        // The shared wallets.
+        pub shared_wallets: HashMap<SharedWalletId, SharedWallet>,
+        // The public keys of the fiduciary canister, by derivation path.
+        pub fiduciary_public_keys: HashMap<Vec<Vec<u8>>, Vec<u8>>,
+        // The last fee percentiles obtained from the bitcoin API.
+        pub fee_percentiles: Option<CachedFeePercentiles>,
+        // The hits and misses of the caches above.
+        pub cache_metrics: CacheMetrics,
+        // The maximum amount of a send request, if limited.
+        pub max_amount_per_request: Option<Satoshi>,
+        // The version of the protocol negotiated with the fiduciary canister.
+        pub fiduciary_protocol_version: u32,
+    }
+
+    impl CustodyData {
+        // Merge the given copy of this custody wallet, updated across calls to other
+        // canisters while this one may have changed as well: the wallets and fiduciary
+        // public keys it does not have yet are added, the root public key if it has none,
+        // the fee percentiles if more recent, and the cache hits and misses of the copy
+        // are counted. Nothing is merged if the copy has another key name or fiduciary.
+        pub fn merge(&mut self, copy: CustodyData) {
+            if copy.key_name != self.key_name || copy.fiduciary_canister != self.fiduciary_canister {
+                return;
+            }
+            for (principal, wallet) in copy.user_wallets {
+                self.user_wallets.entry(principal).or_insert(wallet);
+            }
+            for (id, shared_wallet) in copy.shared_wallets {
+                self.shared_wallets.entry(id).or_insert(shared_wallet);
+            }
+            for (derivation_path, public_key) in copy.fiduciary_public_keys {
+                self.fiduciary_public_keys.entry(derivation_path).or_insert(public_key);
+            }
+            if self.root_public_key.is_none() {
+                self.root_public_key = copy.root_public_key;
+            }
+            if let Some(fee_percentiles) = copy.fee_percentiles {
+                if self.fee_percentiles.as_ref().map_or(true, |cached| cached.timestamp < fee_percentiles.timestamp) {
+                    self.fee_percentiles = Some(fee_percentiles);
+                }
+            }
+            self.cache_metrics.fiduciary_key_hits += copy.cache_metrics.fiduciary_key_hits;
+            self.cache_metrics.fiduciary_key_misses += copy.cache_metrics.fiduciary_key_misses;
+            self.cache_metrics.fee_percentiles_hits += copy.cache_metrics.fee_percentiles_hits;
+            self.cache_metrics.fee_percentiles_misses += copy.cache_metrics.fee_percentiles_misses;
+        }
     }
 
     impl Default for CustodyData {
@@ -150,7 +506,14 @@ pub mod common {
                 network: BitcoinNetwork::default(),
                 key_name: String::default(),
                 fiduciary_canister: Principal::anonymous(),
+                root_public_key: None,
                 user_wallets: HashMap::new(),
+                shared_wallets: HashMap::new(),
+                fiduciary_public_keys: HashMap::new(),
+                fee_percentiles: None,
+                cache_metrics: CacheMetrics::default(),
+                max_amount_per_request: None,
+                fiduciary_protocol_version: MIN_PROTOCOL_VERSION,
             }
         }
     }
@@ -162,7 +525,14 @@ pub mod common {
                 network,
                 key_name,
                 fiduciary_canister,
+                root_public_key: None,
                 user_wallets: HashMap::new(),
+                shared_wallets: HashMap::new(),
+                fiduciary_public_keys: HashMap::new(),
+                fee_percentiles: None,
+                cache_metrics: CacheMetrics::default(),
+                max_amount_per_request: None,
+                fiduciary_protocol_version: MIN_PROTOCOL_VERSION,
             }
         }
     }
@@ -175,23 +545,139 @@ pub mod common {
         .await
     }
 
+    /// Get the root public key and chain code of this canister for the given key name,
+    /// i.e. the key at the empty derivation path.
+    pub async fn ecdsa_root_public_key(key_name: String) -> ECDSAPublicKeyReply {
+        ecdsa_api::ecdsa_public_key_reply(
+            key_name,
+            vec![],
+            Option::None)
+        .await
+    }
+
+    /// Get the root public key and chain code of the given custody wallet, fetching
+    /// it from the ECDSA API and caching it in the custody wallet if needed.
+    pub async fn root_public_key(custody_data: &mut CustodyData) -> ECDSAPublicKeyReply {
+        match &custody_data.root_public_key {
+            Some(root_public_key) => root_public_key.clone(),
+            None => {
+                let root_public_key = ecdsa_root_public_key(custody_data.key_name.clone()).await;
+                custody_data.root_public_key = Some(root_public_key.clone());
+                root_public_key
+            },
+        }
+    }
+
+    /// Check that the given key name is available to this canister, with a test call
+    /// to the ECDSA API, and return the root public key and chain code of the key.
+    pub async fn validate_key_name(key_name: String) -> Result<ECDSAPublicKeyReply, String> {
+        ecdsa_api::try_ecdsa_public_key_reply(
+            key_name.clone(),
+            vec![],
+            Option::None)
+        .await
+        .map_err(|message| format!("Invalid ECDSA key name {}: {}", key_name, message))
+    }
+
+    /// Check the interlocks of the bitcoin mainnet that can be checked synchronously: the
+    /// key name must be the production one, and the amount of the send requests must be limited.
+    pub fn check_mainnet_configuration(key_name: &str, max_amount_per_request: Option<Satoshi>) {
+        if key_name != PRODUCTION_KEY_NAME {
+            panic!("The ECDSA key name of the bitcoin mainnet must be {}, not {}.", PRODUCTION_KEY_NAME, key_name);
+        }
+        if max_amount_per_request.is_none() {
+            panic!("The amount of the send requests must be limited on the bitcoin mainnet.");
+        }
+    }
+
+    /// Check that this canister and the given fiduciary canister run on different subnets,
+    /// so that the node providers of both subnets would need to collude to steal the funds.
+    pub async fn check_mainnet_subnets(fiduciary_canister: Principal) -> MainnetReadiness {
+        let custody_subnet = registry_api::get_subnet_for_canister(ic_cdk::api::id()).await;
+        let fiduciary_subnet = registry_api::get_subnet_for_canister(fiduciary_canister).await;
+        let error = match (&custody_subnet, &fiduciary_subnet) {
+            (Ok(custody_subnet), Ok(fiduciary_subnet)) if custody_subnet == fiduciary_subnet =>
+                Some(format!("The fiduciary canister {} runs on the same subnet {} as this canister.", fiduciary_canister, custody_subnet)),
+            (Ok(_), Ok(_)) => None,
+            (Err(error), _) | (_, Err(error)) => Some(error.clone()),
+        };
+        MainnetReadiness {
+            custody_subnet: custody_subnet.ok(),
+            fiduciary_subnet: fiduciary_subnet.ok(),
+            error,
+        }
+    }
+
+    /// Derive locally the public key at the given derivation path from the given root
+    /// public key, instead of calling the management canister.
+    pub fn derive_public_key(root_public_key: &ECDSAPublicKeyReply, derivation_path: &Vec<Vec<u8>>) -> Vec<u8> {
+        derivation::derive_public_key(root_public_key, derivation_path).public_key
+    }
+
+    /// Check that the public key derived locally from the given root public key is
+    /// the same as the one returned by the management canister for the given derivation path.
+    pub async fn check_key_derivation(key_name: String, root_public_key: &ECDSAPublicKeyReply, derivation_path: Vec<Vec<u8>>) -> bool {
+        let expected = ecdsa_api::ecdsa_public_key_reply(
+            key_name,
+            derivation_path.clone(),
+            Option::None)
+        .await;
+        let derived = derivation::derive_public_key(root_public_key, &derivation_path);
+        derived.public_key == expected.public_key && derived.chain_code == expected.chain_code
+    }
+
     /// Get the balance of bitcoins of the given address.
     pub async fn get_balance(network: BitcoinNetwork, address: String) -> u64 {
+        let address = normalize_address(&address, network);
         bitcoin_api::get_balance(network, address).await
     }
 
     // Get or create the wallet for a given principal.
     // If there is no wallet for this principal, it is created and added to the custody wallet.
     // Otherwise, the existing wallet address is returned.
-    pub async fn get_or_create_wallet(custody_data: &mut CustodyData, principal: candid::Principal) -> Address<NetworkChecked> {
+    // If a user public key is given, the wallet is a 2-of-3 multisig where the user
+    // holds the third key, so the user can spend with the signature of only one canister.
+    // If a recovery delay is given as well, the wallet is instead a 2-of-2 multisig with
+    // a recovery path, where the user key alone can spend the outputs that have not been
+    // moved for the given number of blocks.
+    // If a miniscript policy is given, the witness script is compiled from it instead,
+    // where the keys are named `custody`, `fiduciary` and `user`.
+    pub async fn get_or_create_wallet(
+        custody_data: &mut CustodyData,
+        principal: candid::Principal,
+        user_public_key: Option<Vec<u8>>,
+        recovery_delay: Option<u16>,
+        policy: Option<String>,
+    ) -> Address<NetworkChecked> {
 
         if Principal::anonymous() == principal {
             panic!("Principal cannot be anonymous.");
         }
 
+        let user_public_key = user_public_key.map(|public_key| {
+            if public_key.len() != 33 || PublicKey::from_slice(&public_key).is_err() {
+                panic!("The user public key must be a valid compressed public key.");
+            }
+            public_key
+        });
+
+        if recovery_delay.is_some() && user_public_key.is_none() {
+            panic!("A recovery delay requires a user public key.");
+        }
+        if recovery_delay == Some(0) {
+            panic!("The recovery delay must be at least one block.");
+        }
+        if recovery_delay.is_some() && policy.is_some() {
+            panic!("A recovery delay cannot be combined with a custom policy.");
+        }
+
         // Check if we already have a wallet for this principal.
         match custody_data.user_wallets.get(&principal) {
             Some(wallet) => {
+                if (user_public_key.is_some() || policy.is_some())
+                    && (user_public_key != wallet.user_public_key || recovery_delay != wallet.recovery_delay || policy != wallet.policy) {
+                    panic!("The principal {} already has a wallet with a different user key, recovery delay or policy.", principal);
+                }
                 return wallet.address.clone();
             },
             None => {},
@@ -201,29 +687,102 @@ pub mod common {
         // Right now there is only one wallet for each principal,
         // so the it is derived from the principal itself.
         let derivation_path = vec![principal.as_slice().to_vec()];
-        // First public key is from the custody_data canister (i.e. this canister).
-        let pk1 = ecdsa_api::ecdsa_public_key(
-            custody_data.key_name.clone(),
-            derivation_path.clone(),
-            Option::None)
-        .await;
+        let user_wallet = create_wallet(custody_data, derivation_path, user_public_key, recovery_delay, policy).await;
+        let address = user_wallet.address.clone();
+
+        // Store the script and wallet address for this principal.
+        custody_data.user_wallets.insert(principal, user_wallet);
+
+        address
+    }
+
+    /// Create a shared wallet, owned by the given members, with the given name.
+    /// Send requests from the wallet are proposals that the given threshold of members
+    /// must approve before the custody wallet signs them. The creator is always a member.
+    /// The wallet is derived from its creator and its name, so that a creator can
+    /// create several shared wallets.
+    pub async fn create_shared_wallet(
+        custody_data: &mut CustodyData,
+        creator: candid::Principal,
+        name: String,
+        members: Vec<Principal>,
+        threshold: u64,
+    ) -> Address<NetworkChecked> {
+
+        if Principal::anonymous() == creator {
+            panic!("Principal cannot be anonymous.");
+        }
+        if name.is_empty() || name.len() > MAX_SHARED_WALLET_NAME_LENGTH {
+            panic!("The name of the shared wallet must have between 1 and {} bytes.", MAX_SHARED_WALLET_NAME_LENGTH);
+        }
+
+        let id = SharedWalletId { creator, name };
+        if custody_data.shared_wallets.contains_key(&id) {
+            panic!("The principal {} already has a shared wallet named {}.", creator, id.name);
+        }
+
+        let mut unique_members = vec![creator];
+        for member in members {
+            if Principal::anonymous() == member {
+                panic!("A member cannot be anonymous.");
+            }
+            if !unique_members.contains(&member) {
+                unique_members.push(member);
+            }
+        }
+        if threshold == 0 || threshold > unique_members.len() as u64 {
+            panic!("The threshold must be between 1 and the number of members ({}).", unique_members.len());
+        }
+
+        let wallet = create_wallet(custody_data, shared_wallet_derivation_path(&id), None, None, None).await;
+        let address = wallet.address.clone();
+
+        custody_data.shared_wallets.insert(id, SharedWallet {
+            wallet,
+            members: unique_members,
+            threshold,
+        });
+
+        address
+    }
+
+    /// Get the derivation path of the given shared wallet, in both canisters.
+    /// It has two elements, so it is distinct from the ones of the user wallets.
+    pub fn shared_wallet_derivation_path(id: &SharedWalletId) -> Vec<Vec<u8>> {
+        vec![id.creator.as_slice().to_vec(), id.name.as_bytes().to_vec()]
+    }
+
+    // Create a wallet for the given derivation path.
+    async fn create_wallet(
+        custody_data: &mut CustodyData,
+        derivation_path: Vec<Vec<u8>>,
+        user_public_key: Option<Vec<u8>>,
+        recovery_delay: Option<u16>,
+        policy: Option<String>,
+    ) -> UserWallet {
+        // First public key is from the custody canister (i.e. this canister). It is derived
+        // locally from the root public key, which is fetched only once.
+        let root_public_key = root_public_key(custody_data).await;
+        let pk1 = derive_public_key(&root_public_key, &derivation_path);
         // Second public key is generated by the fiduciary canister.
-        let fiduciary_pk: Result<(Vec<u8>,), _> = call(
-            custody_data.fiduciary_canister,
-            "public_key",
-            (custody_data.network, derivation_path.clone(),),
-        )
-        .await;
-        let pk2 = fiduciary_pk.expect("Failed to obtain public key from fiduciary canister.").0;
+        let pk2 = match cached_fiduciary_public_key(custody_data, &derivation_path) {
+            Some(pk2) => pk2,
+            None => {
+                let pk2 = get_fiduciary_public_key(custody_data, &derivation_path).await;
+                custody_data.fiduciary_public_keys.insert(derivation_path.clone(), pk2.clone());
+                pk2
+            },
+        };
     
-        // Create a 2-of-2 multisig witness script.
-        let witness_script = bitcoin::blockdata::script::Builder::new()
-            .push_int(2)
-            .push_slice(PublicKey::from_slice(pk1.as_slice()).unwrap().serialize())
-            .push_slice(PublicKey::from_slice(pk2.as_slice()).unwrap().serialize())
-            .push_int(2)
-            .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKMULTISIG)
-            .into_script();
+        // Create the witness script of the wallet.
+        let witness_script = build_wallet_witness_script(&pk1, &pk2, &user_public_key, recovery_delay, &policy)
+            .unwrap_or_else(|error| panic!("{}", error));
+
+        // Both canisters must take part in the wallet, otherwise it is not a custody wallet.
+        let public_keys = witness_script_public_keys(&witness_script);
+        if !public_keys.contains(&compress_public_key(&pk1)) || !public_keys.contains(&compress_public_key(&pk2)) {
+            panic!("The policy must use both the {} and {} keys.", policy::CUSTODY_KEY, policy::FIDUCIARY_KEY);
+        }
 
         let script_pub_key = ScriptBuf::new_p2wsh(&witness_script.wscript_hash());
 
@@ -237,101 +796,1039 @@ pub mod common {
             }
         };
 
-        // Store the script and wallet address for this principal.
-        custody_data.user_wallets.insert(principal, UserWallet {
+        UserWallet {
             witness_script,
-            address: address.clone(),
+            address,
             derivation_path,
-        });
+            user_public_key,
+            recovery_delay,
+            policy,
+        }
+    }
 
-        address
+    // Get the descriptors of the user and shared wallets of the given custody wallet,
+    // along with the key name and fiduciary canister they were derived with.
+    pub fn wallet_descriptors(custody_data: &CustodyData) -> WalletDescriptors {
+        WalletDescriptors {
+            key_name: custody_data.key_name.clone(),
+            fiduciary_id: custody_data.fiduciary_canister,
+            user_wallets: custody_data.user_wallets.iter()
+                .map(|(principal, wallet)| (*principal, wallet_descriptor(wallet)))
+                .collect(),
+            shared_wallets: custody_data.shared_wallets.iter()
+                .map(|(id, shared_wallet)| SharedWalletDescriptor {
+                    id: id.clone(),
+                    wallet: wallet_descriptor(&shared_wallet.wallet),
+                    members: shared_wallet.members.clone(),
+                    threshold: shared_wallet.threshold,
+                })
+                .collect(),
+        }
     }
 
-    /// Build a transaction to transfer the given amount from the given principal's
-    /// wallet to the given destination address.
-    /// The transaction returned is not signed by any party.
-    pub async fn build_unsigned_transaction(
-        custody_data: &CustodyData,
-        from_principal: candid::Principal,
-        dst_address: String,
-        amount: Satoshi,
-    ) -> TransactionInfo {
+    // Get the descriptor of the given wallet.
+    fn wallet_descriptor(wallet: &UserWallet) -> WalletDescriptor {
+        WalletDescriptor {
+            derivation_path: wallet.derivation_path.clone(),
+            witness_script: wallet.witness_script.to_bytes(),
+            user_public_key: wallet.user_public_key.clone(),
+            recovery_delay: wallet.recovery_delay,
+            policy: wallet.policy.clone(),
+        }
+    }
 
-        // Check if we already have a wallet for this principal.
-        let user_wallet = match custody_data.user_wallets.get(&from_principal) {
-            Some(info) => {
-                info
+    // Restore the wallets of the given descriptors in the given custody wallet, which
+    // must have the key name and fiduciary canister the wallets were derived with.
+    // The addresses are rebuilt from the witness scripts.
+    pub fn restore_wallets(custody_data: &mut CustodyData, descriptors: WalletDescriptors) {
+        if descriptors.key_name != custody_data.key_name || descriptors.fiduciary_id != custody_data.fiduciary_canister {
+            panic!("The wallets were derived with another key name or fiduciary canister.");
+        }
+        let network = custody_data.network;
+        for (principal, descriptor) in descriptors.user_wallets {
+            custody_data.user_wallets.insert(principal, restore_wallet(descriptor, network));
+        }
+        for descriptor in descriptors.shared_wallets {
+            custody_data.shared_wallets.insert(descriptor.id, SharedWallet {
+                wallet: restore_wallet(descriptor.wallet, network),
+                members: descriptor.members,
+                threshold: descriptor.threshold,
+            });
+        }
+    }
+
+    // Restore the wallet of the given descriptor on the given network.
+    fn restore_wallet(descriptor: WalletDescriptor, network: BitcoinNetwork) -> UserWallet {
+        let witness_script = ScriptBuf::from_bytes(descriptor.witness_script);
+        UserWallet {
+            address: Address::p2wsh(&witness_script, match_network(network)),
+            witness_script,
+            derivation_path: descriptor.derivation_path,
+            user_public_key: descriptor.user_public_key,
+            recovery_delay: descriptor.recovery_delay,
+            policy: descriptor.policy,
+        }
+    }
+
+    /// Verify the wallet of the given principal against both canisters.
+    /// The public keys are fetched again, from the custody canister with the given key
+    /// name currently configured, which may differ from the one the wallet was created
+    /// with, and from the fiduciary canister with its own. The witness script is rebuilt
+    /// from them and compared byte-for-byte with the one stored in the user wallet.
+    /// A mismatch typically reveals a misconfigured key name.
+    pub async fn verify_wallet(custody_data: &CustodyData, principal: candid::Principal, key_name: String) -> WalletVerification {
+
+        let user_wallet = match custody_data.user_wallets.get(&principal) {
+            Some(wallet) => {
+                wallet
             },
             None => {
-                panic!("No wallet found for the principal {}", from_principal);
+                panic!("No wallet found for the principal {}", principal);
             },
         };
 
-        // Get fee percentiles from previous transactions to estimate our own fee.
-        let fee_percentiles = bitcoin_api::get_current_fee_percentiles(custody_data.network).await;
+        // Fetch again both public keys for the derivation path of the wallet.
+        let pk1 = ecdsa_api::ecdsa_public_key(
+            key_name.clone(),
+            user_wallet.derivation_path.clone(),
+            Option::None)
+        .await;
+        let pk2 = get_fiduciary_public_key(custody_data, &user_wallet.derivation_path).await;
+
+        // Get the name of the key used by the fiduciary canister, to help diagnose mismatches.
+        let fiduciary_key_name = get_fiduciary_key_name(custody_data).await;
+
+        // Extract the public keys of the stored witness script.
+        let stored_keys = witness_script_public_keys(&user_wallet.witness_script);
+
+        let custody_public_key = compress_public_key(&pk1);
+        let fiduciary_public_key = compress_public_key(&pk2);
+
+        // Rebuild the witness script and compare it with the stored one.
+        let witness_script = build_wallet_witness_script(&pk1, &pk2, &user_wallet.user_public_key, user_wallet.recovery_delay, &user_wallet.policy)
+            .unwrap_or_else(|error| panic!("{}", error));
+
+        WalletVerification {
+            address: user_wallet.address.to_string(),
+            custody_key_name: key_name,
+            fiduciary_key_name,
+            custody_key_matches: stored_keys.contains(&custody_public_key),
+            fiduciary_key_matches: stored_keys.contains(&fiduciary_public_key),
+            witness_script_matches: witness_script.as_bytes() == user_wallet.witness_script.as_bytes(),
+            custody_public_key,
+            fiduciary_public_key,
+        }
+    }
 
-        let fee_per_byte = if fee_percentiles.is_empty() {
-            // There are no fee percentiles. This case can only happen on a regtest
-            // network where there are no non-coinbase transactions. In this case,
-            // we use a default of 2000 millisatoshis/byte (i.e. 2 satoshi/byte)
-            2000
-        } else {
-            // Choose the 50th percentile for sending fees.
-            fee_percentiles[50]
-        };
+    /// Set the heir of the given owner, who can withdraw from the wallet of the owner
+    /// once the owner has not checked in for the given period. It counts as a check-in
+    /// at the given time.
+    pub fn set_heir(
+        inheritances: &mut HashMap<Principal, Inheritance>,
+        owner: Principal,
+        heir: Principal,
+        inactivity_period_seconds: u64,
+        now: u64,
+    ) {
+        if Principal::anonymous() == heir {
+            panic!("The heir cannot be anonymous.");
+        }
+        if owner == heir {
+            panic!("The heir cannot be the owner of the wallet.");
+        }
+        if inactivity_period_seconds == 0 {
+            panic!("The inactivity period must be at least one second.");
+        }
+        inheritances.insert(owner, Inheritance {
+            heir,
+            inactivity_period_seconds,
+            last_check_in: now,
+        });
+    }
 
-        print("Fetching UTXOs...");
-        // Note that pagination may have to be used to get all UTXOs for the given address.
-        // For the sake of simplicity, it is assumed here that the `utxo` field in the response
-        // contains all UTXOs.
-        let own_utxos = bitcoin_api::get_utxos(custody_data.network, user_wallet.address.to_string())
-            .await
-            .utxos;
+    /// Record that the given owner is still active at the given time, which postpones
+    /// the time from which its heir can withdraw.
+    pub fn check_in(inheritances: &mut HashMap<Principal, Inheritance>, owner: Principal, now: u64) {
+        match inheritances.get_mut(&owner) {
+            Some(inheritance) => inheritance.last_check_in = now,
+            None => panic!("No heir found for the principal {}", owner),
+        }
+    }
 
-        let dst_address = Address::from_str(&dst_address)
-            .expect("Destination address is invalid")
-            .require_network(match_network(custody_data.network))
-            .expect("Wrong network for destination address");
+    /// Check that the given heir can withdraw from the wallet of the given owner,
+    /// i.e. that the owner has not checked in for its inactivity period at the given time.
+    pub fn check_heir(inheritances: &HashMap<Principal, Inheritance>, owner: Principal, heir: Principal, now: u64) {
+        let inheritance = match inheritances.get(&owner) {
+            Some(inheritance) if inheritance.heir == heir => inheritance,
+            _ => panic!("The principal {} is not the heir of {}.", heir, owner),
+        };
+        let inactivity_period = inheritance.inactivity_period_seconds.saturating_mul(1_000_000_000);
+        if now < inheritance.last_check_in.saturating_add(inactivity_period) {
+            panic!("The principal {} checked in less than {} seconds ago.", owner, inheritance.inactivity_period_seconds);
+        }
+    }
 
-        // Build the transaction that sends `amount` to the destination address.
-        let transaction_info = build_transaction(
-            user_wallet,
-            &own_utxos,
-            &dst_address,
-            amount,
-            fee_per_byte,
-        ).await;
+    /// Enable the allowlist of destination addresses of the given owner, with the given
+    /// cooling-off period before an address added becomes active. If the allowlist is
+    /// already enabled, its cooling-off period can only be increased, and a pending
+    /// disabling is cancelled. The allowlist functions take the current time.
+    pub fn enable_allowlist(
+        allowlists: &mut HashMap<Principal, Allowlist>,
+        owner: Principal,
+        cooling_off_period_seconds: u64,
+        now: u64,
+    ) -> AllowlistEvent {
+        match allowlists.get_mut(&owner) {
+            Some(allowlist) => {
+                if cooling_off_period_seconds < allowlist.cooling_off_period_seconds {
+                    panic!("The cooling-off period can only be increased.");
+                }
+                allowlist.cooling_off_period_seconds = cooling_off_period_seconds;
+                allowlist.disabled_from = None;
+            },
+            None => {
+                allowlists.insert(owner, Allowlist {
+                    cooling_off_period_seconds,
+                    addresses: vec![],
+                    disabled_from: None,
+                });
+            },
+        }
+        allowlist_event(owner, AllowlistEventKind::Enabled { cooling_off_period_seconds }, now)
+    }
 
-        transaction_info
+    /// Add the given destination address to the allowlist of the given owner. It only
+    /// becomes active after the cooling-off period of the allowlist.
+    pub fn add_allowed_address(
+        allowlists: &mut HashMap<Principal, Allowlist>,
+        owner: Principal,
+        address: String,
+        network: BitcoinNetwork,
+        now: u64,
+    ) -> AllowlistEvent {
+        let address = normalize_address(&address, network);
+        let allowlist = get_allowlist_mut(allowlists, owner);
+        if allowlist.addresses.iter().any(|allowed| allowed.address == address) {
+            panic!("The address {} is already in the allowlist.", address);
+        }
+        let active_from = now
+            .saturating_add(allowlist.cooling_off_period_seconds.saturating_mul(1_000_000_000));
+        allowlist.addresses.push(AllowedAddress {
+            address: address.clone(),
+            active_from,
+        });
+        allowlist_event(owner, AllowlistEventKind::AddressAdded { address, active_from }, now)
     }
 
-    // Builds a transaction to send the given `amount` of satoshis to the
-    // destination address.
-    async fn build_transaction(
-        user_wallet: &UserWallet,
-        own_utxos: &[Utxo],
-        dst_address: &Address,
-        amount: Satoshi,
-        fee_per_byte: MillisatoshiPerByte,
-    ) -> TransactionInfo {
-        // We have a chicken-and-egg problem where we need to know the length
-        // of the transaction in order to compute its proper fee, but we need
-        // to know the proper fee in order to figure out the inputs needed for
-        // the transaction.
-        //
-        // We solve this problem iteratively. We start with a fee of zero, build
-        // and sign a transaction, see what its size is, and then update the fee,
-        // rebuild the transaction, until the fee is set to the correct amount.
-        print("Building transaction...");
-        let mut total_fee = 0;
-        loop {
-            let transaction_info =
-                build_transaction_with_fee(&user_wallet, own_utxos, dst_address, amount, total_fee)
-                    .expect("Error building transaction.");
+    /// Remove the given destination address from the allowlist of the given owner.
+    /// Unlike additions, removals take effect immediately.
+    pub fn remove_allowed_address(
+        allowlists: &mut HashMap<Principal, Allowlist>,
+        owner: Principal,
+        address: String,
+        network: BitcoinNetwork,
+        now: u64,
+    ) -> AllowlistEvent {
+        let address = normalize_address(&address, network);
+        let allowlist = get_allowlist_mut(allowlists, owner);
+        let length = allowlist.addresses.len();
+        allowlist.addresses.retain(|allowed| allowed.address != address);
+        if allowlist.addresses.len() == length {
+            panic!("The address {} is not in the allowlist.", address);
+        }
+        allowlist_event(owner, AllowlistEventKind::AddressRemoved { address }, now)
+    }
 
-            // Sign the transaction. In this case, we only care about the size
+    /// Disable the allowlist of the given owner. Like additions, it only takes effect
+    /// after the cooling-off period of the allowlist.
+    pub fn disable_allowlist(allowlists: &mut HashMap<Principal, Allowlist>, owner: Principal, now: u64) -> AllowlistEvent {
+        let allowlist = get_allowlist_mut(allowlists, owner);
+        let effective_from = now
+            .saturating_add(allowlist.cooling_off_period_seconds.saturating_mul(1_000_000_000));
+        allowlist.disabled_from = Some(effective_from);
+        allowlist_event(owner, AllowlistEventKind::Disabled { effective_from }, now)
+    }
+
+    /// Check that the given destination address is allowed by the given allowlist,
+    /// i.e. that it was added to it for longer than its cooling-off period.
+    /// Any address is allowed without allowlist, or once it is disabled.
+    pub fn check_allowlist(allowlist: Option<&Allowlist>, dst_address: &str, network: BitcoinNetwork, now: u64) {
+        let allowlist = match allowlist {
+            Some(allowlist) => allowlist,
+            None => return,
+        };
+        if allowlist.disabled_from.map_or(false, |disabled_from| disabled_from <= now) {
+            return;
+        }
+        let dst_address = normalize_address(dst_address, network);
+        match allowlist.addresses.iter().find(|allowed| allowed.address == dst_address) {
+            Some(allowed) if allowed.active_from <= now => {},
+            Some(allowed) => panic!("The address {} is only allowed from {}.", dst_address, allowed.active_from),
+            None => panic!("The address {} is not in the allowlist.", dst_address),
+        }
+    }
+
+    // Get the allowlist of the given owner.
+    fn get_allowlist_mut(allowlists: &mut HashMap<Principal, Allowlist>, owner: Principal) -> &mut Allowlist {
+        match allowlists.get_mut(&owner) {
+            Some(allowlist) => allowlist,
+            None => panic!("No allowlist found for the principal {}", owner),
+        }
+    }
+
+    // Create an event of the allowlist of the given owner, at the given time.
+    fn allowlist_event(owner: Principal, kind: AllowlistEventKind, now: u64) -> AllowlistEvent {
+        AllowlistEvent {
+            timestamp: now,
+            owner,
+            kind,
+        }
+    }
+
+    // Get the canonical form of the given address, checking that it is valid for the given network.
+    fn normalize_address(address: &str, network: BitcoinNetwork) -> String {
+        Address::from_str(address)
+            .expect("Address is invalid")
+            .require_network(match_network(network))
+            .expect("Wrong network for address")
+            .to_string()
+    }
+
+    /// Get the role of the given principal on this canister: the controllers of the
+    /// canister have the controller role, the operators set by them the operator role.
+    pub fn get_role(admin_state: &AdminState, principal: &Principal) -> Option<Role> {
+        role_of(admin_state, principal, ic_cdk::api::is_controller(principal))
+    }
+
+    // Get the role of the given principal, which is a controller of this canister or not.
+    fn role_of(admin_state: &AdminState, principal: &Principal, is_controller: bool) -> Option<Role> {
+        if is_controller {
+            Some(Role::Controller)
+        } else if admin_state.operators.contains(principal) {
+            Some(Role::Operator)
+        } else {
+            None
+        }
+    }
+
+    /// Check that the given principal has at least the given role, and return its role.
+    /// The controller role includes the operator one.
+    pub fn check_role(admin_state: &AdminState, principal: &Principal, required_role: Role) -> Role {
+        match get_role(admin_state, principal) {
+            Some(role) if role >= required_role => role,
+            _ => panic!("The principal {} does not have the {:?} role.", principal, required_role),
+        }
+    }
+
+    /// Check that the given principal has at least the given role, then record the
+    /// given action in the audit log.
+    pub fn record_admin_action(admin_state: &mut AdminState, principal: Principal, required_role: Role, action: AdminAction) {
+        let role = check_role(admin_state, &principal, required_role);
+        print(format!("Admin action by {}: {:?}", principal, action));
+        admin_state.audit_log.push(AuditEntry {
+            timestamp: ic_cdk::api::time(),
+            principal,
+            role,
+            action,
+        });
+    }
+
+    /// Add the given principal to the operators.
+    pub fn add_operator(admin_state: &mut AdminState, operator: Principal) {
+        if Principal::anonymous() == operator {
+            panic!("An operator cannot be anonymous.");
+        }
+        if !admin_state.operators.contains(&operator) {
+            admin_state.operators.push(operator);
+        }
+    }
+
+    /// Remove the given principal from the operators.
+    pub fn remove_operator(admin_state: &mut AdminState, operator: &Principal) {
+        admin_state.operators.retain(|principal| principal != operator);
+    }
+
+    /// Pause or resume the withdrawals of the wallet with the given derivation path.
+    pub fn set_wallet_paused(pause_state: &mut PauseState, derivation_path: Vec<Vec<u8>>, paused: bool) {
+        pause_state.paused_wallets.retain(|path| *path != derivation_path);
+        if paused {
+            pause_state.paused_wallets.push(derivation_path);
+        }
+    }
+
+    /// Check that the withdrawals of the wallet with the given derivation path are not
+    /// paused, neither globally nor for this wallet.
+    pub fn check_not_paused(pause_state: &PauseState, derivation_path: &Vec<Vec<u8>>) {
+        if let Some(error) = pause_error(pause_state, derivation_path) {
+            panic!("{}", error);
+        }
+    }
+
+    /// Get the reason why the withdrawals of the wallet with the given derivation path are
+    /// paused, if they are, see `check_not_paused`.
+    pub fn pause_error(pause_state: &PauseState, derivation_path: &Vec<Vec<u8>>) -> Option<String> {
+        if pause_state.paused {
+            return Some(String::from("Withdrawals are paused."));
+        }
+        if pause_state.paused_wallets.contains(derivation_path) {
+            return Some(String::from("Withdrawals are paused for this wallet."));
+        }
+        None
+    }
+
+    /// Get the versions of the protocol spoken by this canister.
+    pub fn protocol_versions() -> ProtocolVersions {
+        ProtocolVersions {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+        }
+    }
+
+    /// Check that the given version of the protocol is spoken by this canister.
+    pub fn check_protocol_version(protocol_version: u32) -> Result<(), String> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+            return Err(format!("The protocol version {} is not supported, only the versions {} to {} are.", protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION));
+        }
+        Ok(())
+    }
+
+    /// Negotiate the version of the protocol to speak with a peer speaking the given versions,
+    /// i.e. the latest version spoken by both canisters.
+    pub fn negotiate_protocol_version(versions: &ProtocolVersions) -> Result<u32, String> {
+        let protocol_version = versions.max_version.min(PROTOCOL_VERSION);
+        if protocol_version < versions.min_version.max(MIN_PROTOCOL_VERSION) {
+            return Err(format!(
+                "No common protocol version between the versions {} to {} of the peer and {} to {}.",
+                versions.min_version, versions.max_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ));
+        }
+        Ok(protocol_version)
+    }
+
+    /// Pair this canister with the given fiduciary canister, which must have registered this
+    /// canister and speak a common version of the protocol.
+    pub async fn pair_with_fiduciary(fiduciary_canister: Principal) -> Pairing {
+        let reply: Result<(Result<PairingReply, String>,), _> = call(
+            fiduciary_canister,
+            "negotiate_pairing",
+            (protocol_versions(),),
+        )
+        .await;
+        let reply = match reply {
+            Ok((reply,)) => reply,
+            // The fiduciary canisters of the first version of the protocol cannot negotiate,
+            // and only speak this version.
+            Err((RejectionCode::CanisterError, _)) => {
+                let reply: Result<(PairingReply,), _> = call(
+                    fiduciary_canister,
+                    "pair",
+                    (MIN_PROTOCOL_VERSION,),
+                )
+                .await;
+                reply
+                    .map(|(reply,)| reply)
+                    .map_err(|(code, message)| format!("Failed to pair with the fiduciary canister {} (code {:?}): {}", fiduciary_canister, code, message))
+            },
+            Err((code, message)) => Err(format!("Failed to pair with the fiduciary canister {} (code {:?}): {}", fiduciary_canister, code, message)),
+        };
+        let (peer_protocol_version, negotiated_version) = match &reply {
+            Ok(reply) => (Some(reply.protocol_version), check_protocol_version(reply.protocol_version).map(|_| reply.protocol_version)),
+            Err(error) => (None, Err(error.clone())),
+        };
+        let error = negotiated_version.clone().err();
+        Pairing {
+            peer: fiduciary_canister,
+            protocol_version: PROTOCOL_VERSION,
+            peer_protocol_version,
+            negotiated_version: negotiated_version.ok(),
+            paired_at: error.is_none().then(ic_cdk::api::time),
+            error,
+        }
+    }
+
+    // Send the given request to the fiduciary canister, in an envelope of the version of
+    // the protocol negotiated with it.
+    async fn call_fiduciary(custody_data: &CustodyData, request: FiduciaryRequest) -> FiduciaryResponse {
+        try_call_fiduciary(custody_data, request).await
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    // Same as `call_fiduciary`, but returns the errors instead of trapping, e.g. for
+    // the calls made from timers.
+    async fn try_call_fiduciary(custody_data: &CustodyData, request: FiduciaryRequest) -> Result<FiduciaryResponse, String> {
+        let envelope: Result<(FiduciaryResponseEnvelope,), _> = call(
+            custody_data.fiduciary_canister,
+            "handle_request",
+            (FiduciaryRequestEnvelope {
+                protocol_version: custody_data.fiduciary_protocol_version,
+                request,
+            },),
+        )
+        .await;
+        let envelope = envelope
+            .map_err(|(code, message)| format!("Failed to call the fiduciary canister: {:?} {}", code, message))?
+            .0;
+        check_protocol_version(envelope.protocol_version)?;
+        envelope.response
+            .map_err(|error| format!("The fiduciary canister failed to handle the request: {}", error))
+    }
+
+    // Send the given batch of withdrawals, whose transaction holds the first signatures,
+    // to the fiduciary canister, which checks it, inserts the last signatures and sends
+    // the transaction. Returns the ID of the transaction.
+    // The settlements of the internal ledger paid by the transaction, if any, are checked
+    // by the fiduciary canister against its own ledger.
+    pub async fn send_batch_to_fiduciary(
+        custody_data: &CustodyData,
+        withdrawals: Vec<BatchedWithdrawal>,
+        settlements: Vec<Settlement>,
+        transaction_info: &TransactionInfo,
+    ) -> Result<String, String> {
+        if custody_data.fiduciary_protocol_version < BATCH_PROTOCOL_VERSION {
+            return Err(format!(
+                "The fiduciary canister speaks the version {} of the protocol, batches of withdrawals require the version {}.",
+                custody_data.fiduciary_protocol_version, BATCH_PROTOCOL_VERSION
+            ));
+        }
+        if !settlements.is_empty() && custody_data.fiduciary_protocol_version < LEDGER_PROTOCOL_VERSION {
+            return Err(format!(
+                "The fiduciary canister speaks the version {} of the protocol, settlements of the internal ledger require the version {}.",
+                custody_data.fiduciary_protocol_version, LEDGER_PROTOCOL_VERSION
+            ));
+        }
+        let request = FiduciaryRequest::SignBatch {
+            bitcoin_network: custody_data.network,
+            withdrawals,
+            raw_transaction_info: transaction_info.to_raw(),
+            settlements: if settlements.is_empty() { None } else { Some(settlements) },
+        };
+        match try_call_fiduciary(custody_data, request).await? {
+            FiduciaryResponse::BatchSent { transaction_id } => Ok(transaction_id),
+            response => Err(format!("Unexpected response of the fiduciary canister: {:?}", response)),
+        }
+    }
+
+    // Book the given transfer between two users in the ledger of the fiduciary canister,
+    // which checks that the sender authorized it.
+    pub async fn book_fiduciary_transfer(
+        custody_data: &CustodyData,
+        from: Principal,
+        to: Principal,
+        amount: u64,
+    ) -> Result<(), String> {
+        if custody_data.fiduciary_protocol_version < LEDGER_PROTOCOL_VERSION {
+            return Err(format!(
+                "The fiduciary canister speaks the version {} of the protocol, internal transfers require the version {}.",
+                custody_data.fiduciary_protocol_version, LEDGER_PROTOCOL_VERSION
+            ));
+        }
+        let request = FiduciaryRequest::BookTransfer {
+            bitcoin_network: custody_data.network,
+            from,
+            to,
+            amount,
+        };
+        match try_call_fiduciary(custody_data, request).await? {
+            FiduciaryResponse::TransferBooked => Ok(()),
+            response => Err(format!("Unexpected response of the fiduciary canister: {:?}", response)),
+        }
+    }
+
+    // Get the public key generated by the fiduciary canister for the given derivation path.
+    async fn get_fiduciary_public_key(custody_data: &CustodyData, derivation_path: &[Vec<u8>]) -> Vec<u8> {
+        if custody_data.fiduciary_protocol_version < ENVELOPE_PROTOCOL_VERSION {
+            let fiduciary_pk: Result<(Vec<u8>,), _> = call(
+                custody_data.fiduciary_canister,
+                "public_key",
+                (custody_data.network, derivation_path.to_vec(),),
+            )
+            .await;
+            return fiduciary_pk.expect("Failed to obtain public key from fiduciary canister.").0;
+        }
+        let request = FiduciaryRequest::PublicKey {
+            bitcoin_network: custody_data.network,
+            derivation_path: derivation_path.to_vec(),
+        };
+        match call_fiduciary(custody_data, request).await {
+            FiduciaryResponse::PublicKey { public_key } => public_key,
+            response => panic!("Unexpected response of the fiduciary canister: {:?}", response),
+        }
+    }
+
+    // Get the name of the key used by the fiduciary canister.
+    async fn get_fiduciary_key_name(custody_data: &CustodyData) -> String {
+        if custody_data.fiduciary_protocol_version < ENVELOPE_PROTOCOL_VERSION {
+            let fiduciary_key_name: Result<(String,), _> = call(
+                custody_data.fiduciary_canister,
+                "get_ecdsa_key_name",
+                (custody_data.network,),
+            )
+            .await;
+            return fiduciary_key_name.expect("Failed to obtain key name from fiduciary canister.").0;
+        }
+        let request = FiduciaryRequest::EcdsaKeyName { bitcoin_network: custody_data.network };
+        match call_fiduciary(custody_data, request).await {
+            FiduciaryResponse::EcdsaKeyName { key_name } => key_name,
+            response => panic!("Unexpected response of the fiduciary canister: {:?}", response),
+        }
+    }
+
+    // Create the witness script of a wallet from the custody and fiduciary public keys:
+    //  - with a policy, the policy compiled with the given keys;
+    //  - without user public key, a 2-of-2 multisig;
+    //  - with a user public key but no recovery delay, a 2-of-3 multisig;
+    //  - with both, a 2-of-2 multisig OR the user key after the recovery delay.
+    fn build_wallet_witness_script(
+        pk1: &[u8],
+        pk2: &[u8],
+        user_public_key: &Option<Vec<u8>>,
+        recovery_delay: Option<u16>,
+        wallet_policy: &Option<String>,
+    ) -> Result<ScriptBuf, String> {
+        let mut keys = vec![
+            (policy::CUSTODY_KEY, pk1.to_vec()),
+            (policy::FIDUCIARY_KEY, pk2.to_vec()),
+        ];
+        if let Some(user_public_key) = user_public_key {
+            keys.push((policy::USER_KEY, user_public_key.clone()));
+        }
+        match (wallet_policy, user_public_key, recovery_delay) {
+            (Some(wallet_policy), _, _) => policy::compile_policy(wallet_policy, &keys),
+            (None, Some(_), Some(recovery_delay)) => policy::recovery_script(&keys, recovery_delay),
+            (None, Some(_), None) => policy::encode_miniscript(policy::SELF_CUSTODY_MINISCRIPT, &keys),
+            (None, None, _) => policy::encode_miniscript(policy::MULTISIG_MINISCRIPT, &keys),
+        }
+    }
+
+    // Get the compressed SEC1 encoding of the given public key.
+    fn compress_public_key(public_key: &[u8]) -> Vec<u8> {
+        PublicKey::from_slice(public_key).unwrap().serialize().to_vec()
+    }
+
+    /// Build a transaction to transfer the given amount from the given principal's
+    /// wallet to the given destination address, which must be allowed by the given
+    /// allowlist of the principal, if any.
+    /// The transaction returned is not signed by any party.
+    pub async fn build_unsigned_transaction(
+        custody_data: &mut CustodyData,
+        from_principal: candid::Principal,
+        dst_address: String,
+        amount: Satoshi,
+        allowlist: Option<&Allowlist>,
+    ) -> TransactionInfo {
+        check_allowlist(allowlist, &dst_address, custody_data.network, ic_cdk::api::time());
+        let user_wallet = get_user_wallet(custody_data, from_principal);
+        build_wallet_transaction(custody_data, &user_wallet, Some(dst_address), Some(amount), SpendPath::Multisig).await
+    }
+
+    /// Check that the given principal can queue a withdrawal of the given amount from its
+    /// wallet to the given destination address, to be sent in a batch of withdrawals,
+    /// see `build_batch_transaction`.
+    pub fn check_withdrawal(
+        custody_data: &CustodyData,
+        owner: candid::Principal,
+        dst_address: &str,
+        amount: Satoshi,
+        allowlist: Option<&Allowlist>,
+    ) {
+        get_user_wallet(custody_data, owner);
+        parse_address(dst_address, custody_data.network).unwrap_or_else(|error| panic!("{}", error));
+        if amount < DUST_THRESHOLD {
+            panic!("The amount of a withdrawal must be at least {} satoshis.", DUST_THRESHOLD);
+        }
+        if let Some(max_amount) = custody_data.max_amount_per_request {
+            if amount > max_amount {
+                panic!("The amount of a send request cannot exceed {} satoshis.", max_amount);
+            }
+        }
+        check_allowlist(allowlist, dst_address, custody_data.network, ic_cdk::api::time());
+    }
+
+    /// Check that the outputs of the given transaction, built outside the canister (e.g. a
+    /// PSBT), are within the limits of a send request: the amount sent, without the change,
+    /// cannot exceed the maximum per request, and every destination must be allowed at the
+    /// given time by the given allowlist, if any.
+    pub fn check_psbt_outputs(
+        transaction_info: &TransactionInfo,
+        max_amount_per_request: Option<Satoshi>,
+        allowlist: Option<&Allowlist>,
+        network: BitcoinNetwork,
+        now: u64,
+    ) {
+        if let Some(max_amount) = max_amount_per_request {
+            if transaction_info.sent_amount().to_sat() > max_amount {
+                panic!("The amount of a send request cannot exceed {} satoshis.", max_amount);
+            }
+        }
+        let change_script_pubkeys: Vec<ScriptBuf> = transaction_info.inputs
+            .iter()
+            .map(|input| ScriptBuf::new_p2wsh(&input.witness_script.wscript_hash()))
+            .collect();
+        for output in &transaction_info.transaction.output {
+            if change_script_pubkeys.contains(&output.script_pubkey) {
+                continue;
+            }
+            // The outputs without an address are only rejected by an allowlist in force.
+            let dst_address = Address::from_script(&output.script_pubkey, match_network(network))
+                .map(|address| address.to_string())
+                .unwrap_or_else(|_| hex::encode(output.script_pubkey.as_bytes()));
+            check_allowlist(allowlist, &dst_address, network, now);
+        }
+    }
+
+    // Get the adjustment of the balance of the given principal on the given ledger, i.e.
+    // what it received minus what it sent through the transfers not settled on chain yet.
+    pub fn ledger_adjustment(ledger: &Ledger, principal: &Principal) -> i64 {
+        ledger.adjustments.get(principal).cloned().unwrap_or_default()
+    }
+
+    // Get the balance of a principal from the balance of its wallet on chain, the
+    // adjustment of its balance on the internal ledger and the amount of its withdrawals
+    // not sent yet. Returns None if the principal owes more than it holds.
+    pub fn ledger_balance(onchain_balance: u64, adjustment: i64, pending_withdrawals: u64) -> Option<u64> {
+        let balance = onchain_balance as i128 + adjustment as i128 - pending_withdrawals as i128;
+        u64::try_from(balance).ok()
+    }
+
+    // Get the given amount as an adjustment of the balances on the internal ledger, which
+    // cannot exceed the largest i64.
+    pub fn ledger_amount(amount: u64) -> Result<i64, String> {
+        i64::try_from(amount).map_err(|_| format!("The amount {} satoshi cannot be booked on the internal ledger.", amount))
+    }
+
+    // Book a transfer of the given amount between two principals on the given ledger.
+    pub fn book_transfer(ledger: &mut Ledger, from: Principal, to: Principal, amount: u64) -> Result<LedgerEntry, String> {
+        let signed_amount = ledger_amount(amount)?;
+        adjust(&mut ledger.adjustments, &from, -signed_amount);
+        adjust(&mut ledger.adjustments, &to, signed_amount);
+        let entry = LedgerEntry {
+            id: ledger.entries.last().map_or(0, |entry| entry.id + 1),
+            from,
+            to,
+            amount,
+            timestamp: ic_cdk::api::time(),
+            settlement_transaction_id: None,
+            memo: None,
+            created_at_time: None,
+        };
+        ledger.entries.push(entry.clone());
+        Ok(entry)
+    }
+
+    // Revert the transfer with the given ID booked on the given ledger, e.g. when the
+    // fiduciary canister refused to book it.
+    pub fn unbook_transfer(ledger: &mut Ledger, id: u64) {
+        if let Some(position) = ledger.entries.iter().position(|entry| entry.id == id) {
+            let entry = ledger.entries.remove(position);
+            let signed_amount = ledger_amount(entry.amount).expect("The amounts booked fit in the ledger.");
+            adjust(&mut ledger.adjustments, &entry.from, signed_amount);
+            adjust(&mut ledger.adjustments, &entry.to, -signed_amount);
+        }
+    }
+
+    // Book the given settlements, paid on chain by the transaction with the given ID, on
+    // the given ledger. A settlement is booked as a transfer back from the creditor to the
+    // debtor, which cancels the adjustments of their balances. Returns the entries booked,
+    // or an error without booking any of them.
+    pub fn book_settlements(ledger: &mut Ledger, settlements: &[Settlement], transaction_id: &str) -> Result<Vec<LedgerEntry>, String> {
+        for settlement in settlements {
+            ledger_amount(settlement.amount)?;
+        }
+        settlements.iter()
+            .map(|settlement| {
+                let mut entry = book_transfer(ledger, settlement.creditor, settlement.debtor, settlement.amount)?;
+                entry.settlement_transaction_id = Some(transaction_id.to_string());
+                ledger.entries.last_mut().unwrap().settlement_transaction_id = entry.settlement_transaction_id.clone();
+                Ok(entry)
+            })
+            .collect()
+    }
+
+    // Apply the given settlements to the given adjustments of the balances, checking that
+    // each settlement only pays a debt to a credit: the debtors must not end up in credit,
+    // nor the creditors in debt.
+    pub fn apply_settlements(adjustments: &mut BTreeMap<Principal, i64>, settlements: &[Settlement]) -> Result<(), String> {
+        for settlement in settlements {
+            let signed_amount = ledger_amount(settlement.amount)?;
+            adjust(adjustments, &settlement.debtor, signed_amount);
+            adjust(adjustments, &settlement.creditor, -signed_amount);
+        }
+        for settlement in settlements {
+            if adjustments.get(&settlement.debtor).map_or(false, |adjustment| *adjustment > 0) {
+                return Err(format!("The settlements exceed the debt of {}.", settlement.debtor));
+            }
+            if adjustments.get(&settlement.creditor).map_or(false, |adjustment| *adjustment < 0) {
+                return Err(format!("The settlements exceed the credit of {}.", settlement.creditor));
+            }
+        }
+        Ok(())
+    }
+
+    // Add the given amount to the adjustment of the balance of the given principal,
+    // forgetting the principals whose balance is no longer adjusted.
+    fn adjust(adjustments: &mut BTreeMap<Principal, i64>, principal: &Principal, amount: i64) {
+        let adjustment = adjustments.get(principal).cloned().unwrap_or_default() + amount;
+        if adjustment == 0 {
+            adjustments.remove(principal);
+        } else {
+            adjustments.insert(*principal, adjustment);
+        }
+    }
+
+    // Check that a principal owing the given debt on the internal ledger still holds enough
+    // funds to pay it once the given transaction is sent, in each wallet it spends from.
+    pub async fn check_ledger_debt(network: BitcoinNetwork, transaction_info: &TransactionInfo, debt: u64) -> Result<(), String> {
+        for (witness_script, spent) in wallet_spendings(transaction_info) {
+            check_wallet_debt(network, &witness_script, spent, debt).await?;
+        }
+        Ok(())
+    }
+
+    // Get the amount spent by the given transaction from each wallet of its inputs, by
+    // witness script: the amount of its inputs, minus the change going back to it.
+    fn wallet_spendings(transaction_info: &TransactionInfo) -> BTreeMap<ScriptBuf, u64> {
+        let mut spendings: BTreeMap<ScriptBuf, u64> = BTreeMap::new();
+        for input in &transaction_info.inputs {
+            *spendings.entry(input.witness_script.clone()).or_default() += input.amount.to_sat();
+        }
+        for (witness_script, spent) in spendings.iter_mut() {
+            let script_pubkey = ScriptBuf::new_p2wsh(&witness_script.wscript_hash());
+            let change: u64 = transaction_info.transaction.output
+                .iter()
+                .filter(|output| output.script_pubkey == script_pubkey)
+                .map(|output| output.value.to_sat())
+                .sum();
+            *spent = spent.saturating_sub(change);
+        }
+        spendings
+    }
+
+    // Check that the wallet with the given witness script still holds the given debt once
+    // the given amount is spent from it.
+    pub async fn check_wallet_debt(network: BitcoinNetwork, witness_script: &ScriptBuf, spent: u64, debt: u64) -> Result<(), String> {
+        if debt == 0 {
+            return Ok(());
+        }
+        let script_pubkey = ScriptBuf::new_p2wsh(&witness_script.wscript_hash());
+        let address = Address::from_script(&script_pubkey, match_network(network))
+            .map_err(|error| format!("Failed to generate bitcoin address from P2WSH script pubkey: {}", error))?;
+        let balance = bitcoin_api::get_balance(network, address.to_string()).await;
+        if balance < spent.saturating_add(debt) {
+            return Err(format!(
+                "Insufficient balance: {}, of which {} owed on the internal ledger, trying to spend {} satoshi with fee",
+                balance, debt, spent
+            ));
+        }
+        Ok(())
+    }
+
+    /// Build a transaction that moves all the funds of the given principal's wallet
+    /// back to the same wallet, which resets the timer of its recovery path.
+    /// The transaction returned is not signed by any party.
+    pub async fn build_refresh_transaction(
+        custody_data: &mut CustodyData,
+        from_principal: candid::Principal,
+    ) -> TransactionInfo {
+        let user_wallet = get_user_wallet(custody_data, from_principal);
+        build_wallet_transaction(custody_data, &user_wallet, None, None, SpendPath::Multisig).await
+    }
+
+    /// Build a transaction that moves all the funds of the given principal's wallet
+    /// which can be spent through its recovery path (i.e. whose recovery delay has
+    /// elapsed) to the given destination address.
+    /// The transaction returned is not signed by any party, it shall be signed with
+    /// the user key.
+    pub async fn build_recovery_transaction(
+        custody_data: &mut CustodyData,
+        from_principal: candid::Principal,
+        dst_address: String,
+    ) -> TransactionInfo {
+        let user_wallet = get_user_wallet(custody_data, from_principal);
+        build_wallet_transaction(custody_data, &user_wallet, Some(dst_address), None, SpendPath::Recovery).await
+    }
+
+    /// Build a transaction to transfer the given amount from the given shared
+    /// wallet to the given destination address.
+    /// The transaction returned is not signed by any party.
+    pub async fn build_shared_wallet_transaction(
+        custody_data: &mut CustodyData,
+        id: &SharedWalletId,
+        dst_address: String,
+        amount: Satoshi,
+    ) -> TransactionInfo {
+        let wallet = match custody_data.shared_wallets.get(id) {
+            Some(shared_wallet) => shared_wallet.wallet.clone(),
+            None => panic!("No shared wallet named {} found for the principal {}", id.name, id.creator),
+        };
+        build_wallet_transaction(custody_data, &wallet, Some(dst_address), Some(amount), SpendPath::Multisig).await
+    }
+
+    // Get the wallet of the given principal.
+    fn get_user_wallet(custody_data: &CustodyData, principal: candid::Principal) -> UserWallet {
+        match custody_data.user_wallets.get(&principal) {
+            Some(user_wallet) => user_wallet.clone(),
+            None => panic!("No wallet found for the principal {}", principal),
+        }
+    }
+
+    // Build a transaction to transfer the given amount from the given wallet
+    // to the given destination address, using the given spend path.
+    // If no destination address is given, the wallet address is used.
+    // If no amount is given, all the funds that can be spent are transferred.
+    async fn build_wallet_transaction(
+        custody_data: &mut CustodyData,
+        user_wallet: &UserWallet,
+        dst_address: Option<String>,
+        amount: Option<Satoshi>,
+        spend_path: SpendPath,
+    ) -> TransactionInfo {
+
+        // Get the fee per byte from previous transactions to estimate our own fee.
+        let fee_per_byte = get_fee_per_byte(custody_data).await;
+
+        if spend_path == SpendPath::Recovery && user_wallet.recovery_delay.is_none() {
+            panic!("The wallet {} has no recovery path.", user_wallet.address);
+        }
+
+        if let (Some(max_amount), Some(amount)) = (custody_data.max_amount_per_request, amount) {
+            if amount > max_amount {
+                panic!("The amount of a send request cannot exceed {} satoshis.", max_amount);
+            }
+        }
+
+        print("Fetching UTXOs...");
+        // Note that pagination may have to be used to get all UTXOs for the given address.
+        // For the sake of simplicity, it is assumed here that the `utxo` field in the response
+        // contains all UTXOs.
+        let utxos_response = bitcoin_api::get_utxos(custody_data.network, user_wallet.address.to_string())
+            .await;
+
+        let mut own_utxos = utxos_response.utxos;
+
+        // Only the UTXOs that are old enough can be spent through the recovery path.
+        if spend_path == SpendPath::Recovery {
+            let recovery_delay = user_wallet.recovery_delay.unwrap() as u32;
+            own_utxos.retain(|utxo| utxos_response.tip_height + 1 >= utxo.height + recovery_delay);
+        }
+
+        let dst_address = match dst_address {
+            Some(dst_address) => Address::from_str(&dst_address)
+                .expect("Destination address is invalid")
+                .require_network(match_network(custody_data.network))
+                .expect("Wrong network for destination address"),
+            None => user_wallet.address.clone(),
+        };
+
+        // Build the transaction that sends `amount` to the destination address.
+        let transaction_info = build_transaction(
+            user_wallet,
+            &own_utxos,
+            &dst_address,
+            amount,
+            fee_per_byte,
+            spend_path,
+        ).await;
+
+        transaction_info
+    }
+
+    // Get the fee per byte (in millisatoshis) to pay for a transaction, from the fee
+    // percentiles of previous transactions.
+    async fn get_fee_per_byte(custody_data: &mut CustodyData) -> MillisatoshiPerByte {
+        let fee_percentiles = get_current_fee_percentiles(custody_data).await;
+
+        if fee_percentiles.is_empty() {
+            // There are no fee percentiles. This case can only happen on a regtest
+            // network where there are no non-coinbase transactions. In this case,
+            // we use a default of 2000 millisatoshis/byte (i.e. 2 satoshi/byte)
+            if custody_data.network == BitcoinNetwork::Mainnet {
+                panic!("No fee percentiles available on the bitcoin mainnet.");
+            }
+            2000
+        } else {
+            // Choose the 50th percentile for sending fees.
+            fee_percentiles[50]
+        }
+    }
+
+    // Get the current fee percentiles of the bitcoin network.
+    // The fee percentiles are cached and only requested again to the
+    // bitcoin API once they are older than FEE_PERCENTILES_TTL_NS.
+    async fn get_current_fee_percentiles(custody_data: &mut CustodyData) -> Vec<MillisatoshiPerByte> {
+        let now = ic_cdk::api::time();
+
+        if let Some(fee_percentiles) = cached_fee_percentiles(custody_data, now) {
+            return fee_percentiles;
+        }
+
+        let fee_percentiles = bitcoin_api::get_current_fee_percentiles(custody_data.network).await;
+        custody_data.fee_percentiles = Some(CachedFeePercentiles {
+            fee_percentiles: fee_percentiles.clone(),
+            timestamp: now,
+        });
+        fee_percentiles
+    }
+
+    // Get the fee percentiles cached at the given time, if they are not expired yet,
+    // and count the hit or the miss of the cache.
+    fn cached_fee_percentiles(custody_data: &mut CustodyData, now: u64) -> Option<Vec<MillisatoshiPerByte>> {
+        match &custody_data.fee_percentiles {
+            Some(cached) if now.saturating_sub(cached.timestamp) < FEE_PERCENTILES_TTL_NS => {
+                let fee_percentiles = cached.fee_percentiles.clone();
+                custody_data.cache_metrics.fee_percentiles_hits += 1;
+                Some(fee_percentiles)
+            },
+            _ => {
+                custody_data.cache_metrics.fee_percentiles_misses += 1;
+                None
+            },
+        }
+    }
+
+    // Get the cached public key of the fiduciary canister for the given derivation path,
+    // and count the hit or the miss of the cache.
+    fn cached_fiduciary_public_key(custody_data: &mut CustodyData, derivation_path: &Vec<Vec<u8>>) -> Option<Vec<u8>> {
+        match custody_data.fiduciary_public_keys.get(derivation_path) {
+            Some(pk2) => {
+                let pk2 = pk2.clone();
+                custody_data.cache_metrics.fiduciary_key_hits += 1;
+                Some(pk2)
+            },
+            None => {
+                custody_data.cache_metrics.fiduciary_key_misses += 1;
+                None
+            },
+        }
+    }
+
+    // Builds a transaction to send the given `amount` of satoshis to the
+    // destination address.
+    async fn build_transaction(
+        user_wallet: &UserWallet,
+        own_utxos: &[Utxo],
+        dst_address: &Address,
+        amount: Option<Satoshi>,
+        fee_per_byte: MillisatoshiPerByte,
+        spend_path: SpendPath,
+    ) -> TransactionInfo {
+        // We have a chicken-and-egg problem where we need to know the length
+        // of the transaction in order to compute its proper fee, but we need
+        // to know the proper fee in order to figure out the inputs needed for
+        // the transaction.
+        //
+        // We solve this problem iteratively. We start with a fee of zero, build
+        // and sign a transaction, see what its size is, and then update the fee,
+        // rebuild the transaction, until the fee is set to the correct amount.
+        print("Building transaction...");
+        let mut total_fee = 0;
+        loop {
+            let transaction_info =
+                build_transaction_with_fee(&user_wallet, own_utxos, dst_address, amount, total_fee, spend_path)
+                    .expect("Error building transaction.");
+
+            // Sign the transaction. In this case, we only care about the size
             // of the signed transaction, so we use a mock signer here for efficiency.
-            let signed_transaction = fake_both_signatures(&transaction_info).transaction;
+            let signed_transaction = fake_signatures(&transaction_info).transaction;
 
             let signed_tx_bytes_len = consensus::serialize(&signed_transaction).len() as u64;
 
@@ -346,34 +1843,45 @@ pub mod common {
 
     // Build a transaction to send the given amount of satoshis to the
     // destination address, with the given fee.
+    // If no amount is given, all the UTXOs are spent to the destination address.
     fn build_transaction_with_fee(
         user_wallet: &UserWallet,
         own_utxos: &[Utxo],
         dst_address: &Address,
-        amount: u64,
+        amount: Option<u64>,
         fee: u64,
+        spend_path: SpendPath,
     ) -> Result<TransactionInfo, String> {
 
-        // Assume that any amount below this threshold is dust.
-        const DUST_THRESHOLD: u64 = 1_000;
-
         // Select which UTXOs to spend. We naively spend the oldest available UTXOs,
         // even if they were previously spent in a transaction. This isn't a
         // problem as long as at most one transaction is created per block and
         // we're using min_confirmations of 1.
         let mut utxos_to_spend = vec![];
-        let mut input_amounts = vec![];
         let mut total_spent = 0;
         for utxo in own_utxos.iter().rev() {
             total_spent += utxo.value;
             utxos_to_spend.push(utxo);
-            input_amounts.push(Amount::from_sat(utxo.value));
-            if total_spent >= amount + fee {
+            if amount.map_or(false, |amount| total_spent >= amount + fee) {
                 // We have enough inputs to cover the amount we want to spend.
                 break;
             }
         }
 
+        // Without amount, everything but the fee is transferred.
+        let amount = match amount {
+            Some(amount) => amount,
+            None => {
+                if total_spent < fee + DUST_THRESHOLD {
+                    return Err(format!(
+                        "Insufficient balance: {}, trying to transfer all funds with fee {}",
+                        total_spent, fee
+                    ));
+                }
+                total_spent - fee
+            },
+        };
+
         // Check that we have enough balance to cover the amount we want to spend.
         if total_spent < amount + fee {
             return Err(format!(
@@ -384,146 +1892,584 @@ pub mod common {
 
         // Build the transaction's inputs from the Utxos.
         let inputs: Vec<TxIn> = utxos_to_spend
-            .into_iter()
+            .iter()
             .map(|utxo| TxIn {
                 previous_output: OutPoint {
                     txid: Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
                     vout: utxo.outpoint.vout,
                 },
-                sequence: Sequence::MAX, // 0xffffffff,
+                sequence: match spend_path {
+                    SpendPath::Multisig => Sequence::MAX, // 0xffffffff,
+                    // The relative timelock of the recovery path is checked against the sequence.
+                    SpendPath::Recovery => Sequence::from_height(user_wallet.recovery_delay.unwrap()),
+                },
                 witness: Witness::new(),
                 script_sig: ScriptBuf::new(),
             })
             .collect();
 
-        // Build the transaction's output from the destination address (and the change address if applicable)
-        let mut outputs = vec![TxOut {
-            script_pubkey: dst_address.script_pubkey(),
-            value: Amount::from_sat(amount),
-        }];
+        // Build the transaction's output from the destination address (and the change address if applicable)
+        let mut outputs = vec![TxOut {
+            script_pubkey: dst_address.script_pubkey(),
+            value: Amount::from_sat(amount),
+        }];
+
+        let remaining_amount = total_spent - amount - fee;
+
+        if remaining_amount >= DUST_THRESHOLD {
+            outputs.push(TxOut {
+                script_pubkey: user_wallet.address.script_pubkey(),
+                value: Amount::from_sat(remaining_amount),
+            });
+        }
+
+        let transaction = Transaction {
+            input: inputs,
+            output: outputs,
+            lock_time: LockTime::ZERO,
+            // Relative timelocks require version 2 (BIP68).
+            version: bitcoin::blockdata::transaction::Version::TWO,
+        };
+
+        // Return all the data required to sign the transaction, with the sighashes of
+        // its inputs computed from the wallet.
+        let inputs = utxos_to_spend
+            .iter()
+            .map(|utxo| (user_wallet.witness_script.clone(), Some(user_wallet.derivation_path.clone()), Amount::from_sat(utxo.value)))
+            .collect();
+        Ok(TransactionInfo::new(transaction, inputs))
+    }
+
+    // The owner of a wallet spent by a batch of withdrawals, with the wallet and its UTXOs.
+    type BatchWallet = (Principal, UserWallet, Vec<Utxo>);
+
+    // A transaction sending a batch of withdrawals, with the settlements of the internal
+    // ledger it pays on chain.
+    pub type BatchTransaction = (TransactionInfo, Vec<Settlement>);
+
+    // The owner of a wallet spent by a batch of withdrawals, with the witness script of
+    // the wallet (if any input spends from it) and the amount spent from it, change deduced.
+    pub type BatchSpending = (Principal, Option<ScriptBuf>, u64);
+
+    // Build a transaction sending the given withdrawals from the wallets of their owners,
+    // which must be user wallets. Each withdrawal pays a share of the fee proportional to
+    // its amount (see `split_fee`), and the change of each wallet goes back to it.
+    // The credit of an owner on the internal ledger, given by the adjustments of the
+    // balances, is paid from the wallets of the principals in debt, up to the amount
+    // withdrawn by the owner with its fee, see `plan_settlements`.
+    // If a wallet cannot fund the withdrawals of its owner, the ID of the first of these
+    // withdrawals is returned along the error, so that the batch can be built without it.
+    pub async fn build_batch_transaction(
+        custody_data: &mut CustodyData,
+        withdrawals: &[BatchedWithdrawal],
+        adjustments: &BTreeMap<Principal, i64>,
+    ) -> Result<BatchTransaction, (u64, String)> {
+
+        let fee_per_byte = get_fee_per_byte(custody_data).await;
+
+        print("Fetching UTXOs...");
+        let mut wallets: Vec<BatchWallet> = vec![];
+        for withdrawal in withdrawals {
+            if wallets.iter().any(|(owner, _, _)| *owner == withdrawal.owner) {
+                continue;
+            }
+            let user_wallet = match custody_data.user_wallets.get(&withdrawal.owner) {
+                Some(user_wallet) => user_wallet.clone(),
+                None => return Err((withdrawal.id, format!("No wallet found for the principal {}", withdrawal.owner))),
+            };
+            let utxos = bitcoin_api::get_utxos(custody_data.network, user_wallet.address.to_string())
+                .await
+                .utxos;
+            wallets.push((withdrawal.owner, user_wallet, utxos));
+        }
+
+        // The wallets of the principals in debt, if any owner has a credit to settle.
+        let credit = wallets.iter().any(|(owner, _, _)| adjustments.get(owner).map_or(false, |adjustment| *adjustment > 0));
+        if credit {
+            for (debtor, adjustment) in adjustments {
+                if *adjustment >= 0 || wallets.iter().any(|(owner, _, _)| owner == debtor) {
+                    continue;
+                }
+                if let Some(user_wallet) = custody_data.user_wallets.get(debtor).cloned() {
+                    let utxos = bitcoin_api::get_utxos(custody_data.network, user_wallet.address.to_string())
+                        .await
+                        .utxos;
+                    wallets.push((*debtor, user_wallet, utxos));
+                }
+            }
+        }
+
+        // The fee is computed iteratively, as in `build_transaction`.
+        print("Building batch transaction...");
+        let mut total_fee = 0;
+        loop {
+            let (transaction_info, settlements) =
+                build_batch_transaction_with_fee(&wallets, withdrawals, adjustments, total_fee, custody_data.network)?;
+
+            let signed_transaction = fake_signatures(&transaction_info).transaction;
+
+            let signed_tx_bytes_len = consensus::serialize(&signed_transaction).len() as u64;
+
+            if (signed_tx_bytes_len * fee_per_byte) / 1000 == total_fee {
+                print(format!("Batch transaction built with fee {}.", total_fee));
+                return Ok((transaction_info, settlements));
+            } else {
+                total_fee = (signed_tx_bytes_len * fee_per_byte) / 1000;
+            }
+        }
+    }
+
+    // Build a transaction sending the given withdrawals from the given wallets, with the
+    // given fee. The inputs of each wallet come first, then the output of each withdrawal,
+    // and finally the change of each wallet.
+    fn build_batch_transaction_with_fee(
+        wallets: &[BatchWallet],
+        withdrawals: &[BatchedWithdrawal],
+        adjustments: &BTreeMap<Principal, i64>,
+        fee: u64,
+        network: BitcoinNetwork,
+    ) -> Result<BatchTransaction, (u64, String)> {
+
+        let amounts: Vec<u64> = withdrawals.iter().map(|withdrawal| withdrawal.amount).collect();
+        let fee_shares = split_fee(fee, &amounts);
+
+        // The amount withdrawn by each owner, with its share of the fee.
+        let mut withdrawn: BTreeMap<Principal, u64> = BTreeMap::new();
+        for (withdrawal, fee_share) in withdrawals.iter().zip(fee_shares.iter()) {
+            *withdrawn.entry(withdrawal.owner).or_default() += withdrawal.amount + fee_share;
+        }
+
+        let settlements = plan_settlements(wallets, &withdrawn, adjustments);
+
+        let mut inputs = vec![];
+        let mut spent_outputs = vec![];
+        let mut outputs = vec![];
+        let mut change_outputs = vec![];
+
+        for (owner, user_wallet, utxos) in wallets {
+            let owner_withdrawals: Vec<&BatchedWithdrawal> = withdrawals.iter()
+                .filter(|withdrawal| withdrawal.owner == *owner)
+                .collect();
+            let owner_withdrawn = withdrawn.get(owner).cloned().unwrap_or_default();
+            let settled_from: u64 = settlements.iter().filter(|settlement| settlement.debtor == *owner).map(|settlement| settlement.amount).sum();
+            let settled_to: u64 = settlements.iter().filter(|settlement| settlement.creditor == *owner).map(|settlement| settlement.amount).sum();
+            let balance: u64 = utxos.iter().map(|utxo| utxo.value).sum();
+
+            // An owner in debt can only withdraw the funds it does not owe, and an owner
+            // in credit can also withdraw the credit settled by the wallets in debt.
+            let debt = adjustments.get(owner).map_or(0, |adjustment| adjustment.min(&0).unsigned_abs());
+            if owner_withdrawn > 0 && owner_withdrawn > balance.saturating_sub(debt) + settled_to {
+                return Err((owner_withdrawals[0].id, format!(
+                    "Insufficient balance: {}, of which {} owed on the internal ledger and {} settled from it, trying to transfer {} satoshi with fee",
+                    balance, debt, settled_to, owner_withdrawn
+                )));
+            }
+
+            // The credit settled is paid by the wallets in debt.
+            let required = owner_withdrawn + settled_from - settled_to;
+
+            // Spend the oldest UTXOs of the wallet, as in `build_transaction_with_fee`.
+            let mut total_spent = 0;
+            for utxo in utxos.iter().rev() {
+                if total_spent >= required {
+                    break;
+                }
+                total_spent += utxo.value;
+                inputs.push(TxIn {
+                    previous_output: OutPoint {
+                        txid: Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
+                        vout: utxo.outpoint.vout,
+                    },
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                    script_sig: ScriptBuf::new(),
+                });
+                spent_outputs.push((
+                    user_wallet.witness_script.clone(),
+                    Some(user_wallet.derivation_path.clone()),
+                    Amount::from_sat(utxo.value),
+                ));
+            }
+
+            // The settlements never exceed the balance of the wallets in debt.
+            if total_spent < required {
+                return Err((owner_withdrawals[0].id, format!(
+                    "Insufficient balance: {}, trying to transfer {} satoshi with fee",
+                    total_spent, required
+                )));
+            }
+
+            for withdrawal in &owner_withdrawals {
+                let dst_address = parse_address(&withdrawal.destination_address, network)
+                    .map_err(|error| (withdrawal.id, error))?;
+                outputs.push(TxOut {
+                    script_pubkey: dst_address.script_pubkey(),
+                    value: Amount::from_sat(withdrawal.amount),
+                });
+            }
 
-        let remaining_amount = total_spent - amount - fee;
+            let remaining_amount = total_spent - required;
 
-        if remaining_amount >= DUST_THRESHOLD {
-            outputs.push(TxOut {
-                script_pubkey: user_wallet.address.script_pubkey(),
-                value: Amount::from_sat(remaining_amount),
-            });
+            if remaining_amount >= DUST_THRESHOLD {
+                change_outputs.push(TxOut {
+                    script_pubkey: user_wallet.address.script_pubkey(),
+                    value: Amount::from_sat(remaining_amount),
+                });
+            }
         }
 
+        outputs.extend(change_outputs);
+
         let transaction = Transaction {
             input: inputs,
             output: outputs,
             lock_time: LockTime::ZERO,
-            version: bitcoin::blockdata::transaction::Version::ONE,
+            version: bitcoin::blockdata::transaction::Version::TWO,
         };
 
-        // Compute the sighashes for each input.
-        let sig_hashes = build_transaction_sighashes(
-            &transaction,
-            &user_wallet.witness_script,
-            input_amounts.clone(),
-        );
-
-        // Return all the data required to sign the transaction.
-        Ok(TransactionInfo::new(transaction, user_wallet.witness_script.clone(), sig_hashes))
+        Ok((TransactionInfo::new(transaction, spent_outputs), settlements))
     }
 
-    // Computes the sighashes for each input of the given transaction.
-    // The sighash is computed using the given witness script and input amounts.
-    fn build_transaction_sighashes(
-        transaction: &Transaction,
-        witness_script: &ScriptBuf,
-        input_amounts: Vec<Amount>,
-    ) -> Vec<SegwitV0Sighash> {
+    // Plan the settlement on chain of the credits of the owners withdrawing the given
+    // amounts, given the adjustments of their balances on the internal ledger. The credit
+    // of each owner is settled up to the amount it withdraws, by the wallets in debt
+    // that withdraw nothing, each one up to its debt and its balance.
+    fn plan_settlements(
+        wallets: &[BatchWallet],
+        withdrawn: &BTreeMap<Principal, u64>,
+        adjustments: &BTreeMap<Principal, i64>,
+    ) -> Vec<Settlement> {
+        let mut capacities: Vec<(Principal, u64)> = wallets.iter()
+            .filter(|(debtor, _, _)| !withdrawn.contains_key(debtor))
+            .map(|(debtor, _, utxos)| {
+                let debt = adjustments.get(debtor).map_or(0, |adjustment| adjustment.min(&0).unsigned_abs());
+                (*debtor, debt.min(utxos.iter().map(|utxo| utxo.value).sum()))
+            })
+            .collect();
 
-        if transaction.input.len() != input_amounts.len() {
-            panic!("Transaction inputs and amounts must have the same length.");
+        let mut settlements = vec![];
+        for (creditor, amount) in withdrawn {
+            let credit = adjustments.get(creditor).map_or(0, |adjustment| adjustment.max(&0).unsigned_abs());
+            let mut remaining = credit.min(*amount);
+            for (debtor, capacity) in capacities.iter_mut() {
+                let settled = remaining.min(*capacity);
+                if settled > 0 {
+                    settlements.push(Settlement {
+                        debtor: *debtor,
+                        creditor: *creditor,
+                        amount: settled,
+                    });
+                    *capacity -= settled;
+                    remaining -= settled;
+                }
+            }
         }
+        settlements
+    }
 
-        let mut sig_hashes = vec![];
+    // Split the given fee between the given amounts, in proportion to each amount.
+    // The satoshis left by the rounding are paid by the first amounts, one each.
+    pub fn split_fee(fee: u64, amounts: &[u64]) -> Vec<u64> {
+        let total: u128 = amounts.iter().map(|amount| *amount as u128).sum();
+        if total == 0 {
+            return vec![0; amounts.len()];
+        }
+        let mut fee_shares: Vec<u64> = amounts.iter()
+            .map(|amount| (fee as u128 * *amount as u128 / total) as u64)
+            .collect();
+        let remainder = fee - fee_shares.iter().sum::<u64>();
+        for fee_share in fee_shares.iter_mut().take(remainder as usize) {
+            *fee_share += 1;
+        }
+        fee_shares
+    }
 
-        let txclone = transaction.clone();
-        let mut cache = sighash::SighashCache::new(&txclone);
+    // Check that the given transaction only sends the given withdrawals from the user
+    // wallets of their owners and pays the given settlements of the internal ledger from
+    // the wallets of the debtors: each input spends from the wallet of an owner or a
+    // debtor, each withdrawal has its own output, the other outputs return the change to
+    // the wallets spent, each debtor pays its settlements, and each owner pays its
+    // withdrawals but the settlements of its credit, and at most its share of the fee,
+    // along with the change left as dust. Returns what is spent from each wallet.
+    pub fn check_batch_transaction(
+        transaction_info: &TransactionInfo,
+        withdrawals: &[BatchedWithdrawal],
+        settlements: &[Settlement],
+        network: BitcoinNetwork,
+    ) -> Result<Vec<BatchSpending>, String> {
 
-        for (input_index, _input) in transaction.input.iter().enumerate() {
+        if withdrawals.is_empty() {
+            return Err(String::from("The batch has no withdrawal."));
+        }
 
-            let value = input_amounts.get(input_index).unwrap();
-            
-            // Compute the sighash for this input using the witness script from the user wallet.
-            let sighash = cache.p2wsh_signature_hash(
-                input_index,
-                witness_script,
-                value.clone(),
-                EcdsaSighashType::All
-            ).expect("failed to compute sighash");
+        // The credits of the owners are settled by the principals in debt that withdraw nothing.
+        let is_owner = |principal: &Principal| withdrawals.iter().any(|withdrawal| withdrawal.owner == *principal);
+        for settlement in settlements {
+            if !is_owner(&settlement.creditor) || is_owner(&settlement.debtor) || settlement.amount == 0 {
+                return Err(format!("Invalid settlement of {} by {}.", settlement.creditor, settlement.debtor));
+            }
+        }
+        let mut principals: Vec<Principal> = withdrawals.iter().map(|withdrawal| withdrawal.owner).collect();
+        principals.extend(settlements.iter().map(|settlement| settlement.debtor));
+        principals.sort();
+        principals.dedup();
+
+        // The witness script spent by the inputs of each principal, and the amount it spends.
+        let mut spendings: BTreeMap<Principal, (ScriptBuf, i128)> = BTreeMap::new();
+        for input in &transaction_info.inputs {
+            let principal = principals.iter()
+                .find(|principal| input.derivation_path == Some(vec![principal.as_slice().to_vec()]))
+                .ok_or("An input of the batch spends from another wallet than the ones of the owners and debtors.")?;
+            let (witness_script, spent) = spendings.entry(*principal).or_insert((input.witness_script.clone(), 0));
+            if *witness_script != input.witness_script {
+                return Err(format!("The inputs of {} spend from several wallets.", principal));
+            }
+            *spent += input.amount.to_sat() as i128;
+        }
 
-            sig_hashes.push(sighash);
+        // Each withdrawal has its own output.
+        let mut outputs: Vec<Option<&TxOut>> = transaction_info.transaction.output.iter().map(Some).collect();
+        for withdrawal in withdrawals {
+            let script_pubkey = parse_address(&withdrawal.destination_address, network)?.script_pubkey();
+            let position = outputs.iter()
+                .position(|output| output.map_or(false, |output| {
+                    output.script_pubkey == script_pubkey && output.value.to_sat() == withdrawal.amount
+                }))
+                .ok_or_else(|| format!("The withdrawal {} has no output in the batch.", withdrawal.id))?;
+            outputs[position] = None;
         }
 
-        sig_hashes
-    }
+        // The other outputs are change, which is deduced from the amount spent by the wallet.
+        for output in outputs.into_iter().flatten() {
+            let (_, spent) = spendings.values_mut()
+                .find(|(witness_script, _)| ScriptBuf::new_p2wsh(&witness_script.wscript_hash()) == output.script_pubkey)
+                .ok_or("An output of the batch is neither a withdrawal nor change.")?;
+            *spent -= output.value.to_sat() as i128;
+        }
+
+        let total_spent: i128 = spendings.values().map(|(_, spent)| spent).sum();
+        let total_amount: i128 = withdrawals.iter().map(|withdrawal| withdrawal.amount as i128).sum();
+        let fee = total_spent - total_amount;
+        if fee < 0 {
+            return Err(String::from("The batch spends less than its withdrawals."));
+        }
 
-    // Fake the signatures of the custody wallet and the fiduciary canister.
-    fn fake_both_signatures(transaction_info: &TransactionInfo) -> TransactionInfo {
+        let amounts: Vec<u64> = withdrawals.iter().map(|withdrawal| withdrawal.amount).collect();
+        let fee_shares = split_fee(fee as u64, &amounts);
+        for principal in &principals {
+            let (amount, fee_share) = withdrawals.iter()
+                .zip(fee_shares.iter())
+                .filter(|(withdrawal, _)| withdrawal.owner == *principal)
+                .fold((0, 0), |(amount, fee), (withdrawal, fee_share)| (amount + withdrawal.amount as i128, fee + *fee_share as i128));
+            let settled: i128 = settlements.iter()
+                .map(|settlement| match settlement {
+                    Settlement { debtor, amount, .. } if debtor == principal => *amount as i128,
+                    Settlement { creditor, amount, .. } if creditor == principal => -(*amount as i128),
+                    _ => 0,
+                })
+                .sum();
+            let spent = spendings.get(principal).map_or(0, |(_, spent)| *spent);
+            if spent < amount + settled || spent >= amount + settled + fee_share + DUST_THRESHOLD as i128 {
+                return Err(format!("The principal {} does not pay its withdrawals, settlements and share of the fee.", principal));
+            }
+        }
 
-        let mut transaction = transaction_info.transaction.clone();
+        Ok(principals.into_iter()
+            .map(|principal| match spendings.remove(&principal) {
+                Some((witness_script, spent)) => (principal, Some(witness_script), spent.max(0) as u64),
+                None => (principal, None, 0),
+            })
+            .collect())
+    }
 
-        for (_index, input) in transaction.input.iter_mut().enumerate() {
-            // Clear any previous witness
-            input.witness.clear();
+    // Parse the given address, checking that it is valid for the given network.
+    fn parse_address(address: &str, network: BitcoinNetwork) -> Result<Address, String> {
+        Address::from_str(address)
+            .map_err(|_| format!("The address {} is invalid.", address))?
+            .require_network(match_network(network))
+            .map_err(|_| format!("The address {} is not valid for the network {:?}.", address, network))
+    }
 
-            // Fake signature using an arbitrary array of bytes.
-            let sec1_signature = vec![255; 64];
+    // Fake the witnesses of the transaction, using a placeholder of the largest witness
+    // satisfying the witness script of each input, whatever the spending path. The size
+    // of the transaction obtained is an upper bound of the size of the signed transaction.
+    fn fake_signatures(transaction_info: &TransactionInfo) -> TransactionInfo {
 
-            // Convert the signature to DER format.
-            let mut der_signature = sec1_to_der(sec1_signature);
-            der_signature.push(SIG_HASH_TYPE.to_u32() as u8);
+        let mut transaction_info = transaction_info.clone();
 
-            // Add the signatures to the witness.
-            input.witness.push(vec![]); // Placeholder for scriptSig
-            input.witness.push(der_signature.clone());
-            input.witness.push(der_signature);
-            input.witness.push(transaction_info.witness_script.clone().into_bytes());
+        for (input, input_info) in transaction_info.transaction.input.iter_mut().zip(transaction_info.inputs.iter()) {
+            input.witness = policy::placeholder_satisfaction(&input_info.witness_script)
+                .unwrap_or_else(|error| panic!("{}", error));
         }
 
-        TransactionInfo::new(transaction, transaction_info.witness_script.clone(), transaction_info.sig_hashes.clone())
+        transaction_info
     }
 
     // Add a signature to the given transaction.
     // The signature is computed using the given key and derivation path.
     // The signature index indicates whether it is the first or last signature.
-    // Warning: this function assumes that the sender of the transaction is the P2WSH
-    // address that corresponds to the witness script of the user wallet. Do not use
-    // this function to sign transactions that are not sent from this address.
+    // If any input fails to be signed, the transaction is left untouched and
+    // the inputs that failed are returned.
+    // All the inputs are signed with the same derivation path, i.e. the transaction
+    // must only spend from the wallet of this derivation path.
     pub async fn sign_transaction(
         transaction_info: &TransactionInfo,
         key_name: &str,
-        derivation_path: &Vec<Vec<u8>>,
+        derivation_path: &[Vec<u8>],
+        signature_index: MultisigIndex,
+    ) -> Result<TransactionInfo, Vec<InputSigningError>>
+    {
+        let mut sec1_signatures = vec![None; transaction_info.inputs.len()];
+
+        let failed_inputs = sign_inputs(
+            transaction_info,
+            key_name,
+            &vec![derivation_path.to_vec(); transaction_info.inputs.len()],
+            &mut sec1_signatures)
+        .await;
+
+        if !failed_inputs.is_empty() {
+            return Err(failed_inputs);
+        }
+
+        Ok(insert_signatures(
+            transaction_info,
+            sec1_signatures.into_iter().map(Option::unwrap).collect(),
+            signature_index))
+    }
+
+    // Co-sign the given PSBT (BIP174), which must already contain the partial
+    // signatures of another signer of the witness script for all its inputs.
+    // The signature is computed using the given key and derivation path, which
+    // must correspond to the given public key of the witness script. The two
+    // signatures are inserted in the witness in the order of their public keys.
+    pub async fn cosign_psbt(
+        psbt: &Psbt,
+        key_name: &str,
+        derivation_path: &[Vec<u8>],
+        public_key: &[u8],
+    ) -> Result<TransactionInfo, Vec<InputSigningError>>
+    {
+        let (transaction_info, partial_signatures) = TransactionInfo::from_psbt(psbt);
+
+        let witness_script = &transaction_info.inputs.first().expect("The PSBT has no input.").witness_script;
+        let key_index = witness_script_public_keys(witness_script)
+            .iter()
+            .position(|key| key.as_slice() == public_key)
+            .expect("The public key of the signer is not in the witness script.");
+
+        let (cosigner_index, cosigner_signatures) = partial_signatures
+            .into_iter()
+            .find(|(index, _)| *index != key_index)
+            .expect("The PSBT is missing the partial signatures of a co-signer.");
+
+        let mut sec1_signatures = vec![None; transaction_info.inputs.len()];
+
+        let failed_inputs = sign_inputs(
+            &transaction_info,
+            key_name,
+            &vec![derivation_path.to_vec(); transaction_info.inputs.len()],
+            &mut sec1_signatures)
+        .await;
+
+        if !failed_inputs.is_empty() {
+            return Err(failed_inputs);
+        }
+
+        let sec1_signatures: Vec<Vec<u8>> = sec1_signatures.into_iter().map(Option::unwrap).collect();
+
+        let (first_signatures, last_signatures) = match key_index < cosigner_index {
+            true => (sec1_signatures, cosigner_signatures),
+            false => (cosigner_signatures, sec1_signatures),
+        };
+
+        let transaction_info = insert_signatures(&transaction_info, first_signatures, MultisigIndex::First);
+        Ok(insert_signatures(&transaction_info, last_signatures, MultisigIndex::Last))
+    }
+
+    // Sign the inputs of the given transaction that are not signed yet, i.e. whose
+    // signature is None, using the given key and the given derivation path of each input.
+    // The signatures are requested concurrently, at most MAX_CONCURRENT_SIGNATURES
+    // at a time. The signatures obtained are stored in the given vector, so that
+    // a subsequent call only requests the signatures of the inputs that failed,
+    // which are returned.
+    pub async fn sign_inputs(
+        transaction_info: &TransactionInfo,
+        key_name: &str,
+        derivation_paths: &[Vec<Vec<u8>>],
+        sec1_signatures: &mut Vec<Option<Vec<u8>>>,
+    ) -> Vec<InputSigningError>
+    {
+        if transaction_info.inputs.len() != sec1_signatures.len() {
+            panic!("Transaction sighashes and signatures must have the same length.");
+        }
+        if transaction_info.inputs.len() != derivation_paths.len() {
+            panic!("Transaction inputs and derivation paths must have the same length.");
+        }
+
+        let unsigned_inputs: Vec<usize> = sec1_signatures
+            .iter()
+            .enumerate()
+            .filter(|(_, signature)| signature.is_none())
+            .map(|(index, _)| index)
+            .collect();
+
+        let mut failed_inputs = vec![];
+
+        for indexes in unsigned_inputs.chunks(MAX_CONCURRENT_SIGNATURES) {
+            // Sign the sighash of each input with the given key and derivation path.
+            let requests = indexes.iter().map(|index| {
+                ecdsa_api::sign_with_ecdsa(
+                    key_name.to_string(),
+                    derivation_paths[*index].clone(),
+                    transaction_info.inputs[*index].sig_hash.to_byte_array().to_vec()
+                )
+            });
+            let results = join_all(requests).await;
+
+            for (index, result) in indexes.iter().zip(results.into_iter()) {
+                match result {
+                    Ok(sec1_signature) => {
+                        sec1_signatures[*index] = Some(sec1_signature);
+                    },
+                    Err(message) => {
+                        failed_inputs.push(InputSigningError {
+                            input_index: *index as u64,
+                            message,
+                        });
+                    },
+                }
+            }
+        }
+
+        failed_inputs
+    }
+
+    // Insert the given SEC1 signatures, one for each input, in the witnesses
+    // of the given transaction.
+    // The signature index indicates whether it is the first or last signature.
+    pub fn insert_signatures(
+        transaction_info: &TransactionInfo,
+        sec1_signatures: Vec<Vec<u8>>,
         signature_index: MultisigIndex,
     ) -> TransactionInfo
     {
-        let mut transaction = transaction_info.transaction.clone();
+        if transaction_info.transaction.input.len() != sec1_signatures.len() {
+            panic!("Transaction inputs and signatures must have the same length.");
+        }
+
+        let mut transaction_info = transaction_info.clone();
 
-        // Sign each input of the transaction.
-        for (index, input) in transaction.input.iter_mut().enumerate() {
+        let secp = Secp256k1::verification_only();
+
+        for ((input, sec1_signature), input_info) in transaction_info.transaction.input.iter_mut()
+            .zip(sec1_signatures.into_iter())
+            .zip(transaction_info.inputs.iter()) {
             
-            // If it is the first signature, clear any previous witness script
-            // and add a placeholder for the scriptSig.
+            // If it is the first signature, clear any previous witness.
             if signature_index == MultisigIndex::First {
                 input.witness.clear();
-                input.witness.push(vec![]);
             }
-
-            // Get the sighash for this input.
-            let sighash = transaction_info.sig_hashes.get(index).unwrap();
-
-            // Sign the sighash with the given key and derivation path.
-            let sec1_signature = ecdsa_api::sign_with_ecdsa(
-                key_name.to_string(),
-                derivation_path.clone(),
-                sighash.to_byte_array().to_vec()
-            ).await;
             
             // Convert the signature to DER format.
             let mut der_signature = sec1_to_der(sec1_signature);
@@ -532,21 +2478,53 @@ pub mod common {
             // Add the signature to the witness.
             input.witness.push(der_signature);
 
-            // If it is the last signature, add the witness script.
+            // If it is the last signature, replace the signatures collected in the
+            // witness by the witness satisfying the witness script.
             if signature_index == MultisigIndex::Last {
-                input.witness.push(transaction_info.witness_script.clone().into_bytes());
+                let public_keys = policy::public_keys(&input_info.witness_script)
+                    .unwrap_or_else(|error| panic!("{}", error));
+                let message = Message::from_digest_slice(&input_info.sig_hash.to_byte_array()).unwrap();
+                let mut signatures = HashMap::new();
+                // Skip the empty elements, e.g. the placeholder of the scriptSig.
+                for der_signature in input.witness.iter().filter(|element| !element.is_empty()) {
+                    let signature = parse_witness_signature(der_signature);
+                    // Find the key that made the signature.
+                    let public_key = public_keys.iter()
+                        .find(|public_key| secp.verify_ecdsa(&message, &signature.sig, &public_key.inner).is_ok())
+                        .expect("The signature does not match any key of the witness script.");
+                    signatures.insert(*public_key, signature);
+                }
+                input.witness = policy::satisfy(&input_info.witness_script, signatures, input.sequence)
+                    .unwrap_or_else(|error| panic!("{}", error));
             }
         }
 
         // Return the transaction info with the updated transaction.
-        TransactionInfo::new(transaction, transaction_info.witness_script.clone(), transaction_info.sig_hashes.clone())
+        transaction_info
     }
 
-    // Send the given transaction to the bitcoin network.
+    // Send the given transaction to the bitcoin network. The signatures are already paid
+    // for, so a rejected transaction is returned as an error of each of its inputs rather
+    // than trapping.
     pub async fn send_transaction(
         bitcoin_network: BitcoinNetwork,
         transaction_info: &TransactionInfo,
-    ) {
+    ) -> Result<(), Vec<InputSigningError>> {
+        try_send_transaction(bitcoin_network, transaction_info).await
+            .map_err(|error| (0..transaction_info.transaction().input.len())
+                .map(|index| InputSigningError {
+                    input_index: index as u64,
+                    message: format!("The transaction was not sent: {}", error),
+                })
+                .collect())
+    }
+
+    // Send the given transaction to the bitcoin network, returning an error instead of
+    // trapping if the transaction is rejected.
+    pub async fn try_send_transaction(
+        bitcoin_network: BitcoinNetwork,
+        transaction_info: &TransactionInfo,
+    ) -> Result<(), String> {
         let transaction_bytes = consensus::serialize(&transaction_info.transaction);
         print(&format!(
             "Signed transaction: {}",
@@ -554,41 +2532,853 @@ pub mod common {
         ));
 
         print("Sending transaction...");
-        bitcoin_api::send_transaction(bitcoin_network, transaction_bytes).await;
+        bitcoin_api::send_transaction(bitcoin_network, transaction_bytes).await?;
         print("Transaction sent.");
+        Ok(())
     }
 
-    // Converts a SEC1 ECDSA signature to the DER format.
+    // Converts a SEC1 ECDSA signature to the DER format, with the minimal encoding of
+    // r and s required by the consensus rules.
     fn sec1_to_der(sec1_signature: Vec<u8>) -> Vec<u8> {
-        let r: Vec<u8> = if sec1_signature[0] & 0x80 != 0 {
-            // r is negative. Prepend a zero byte.
-            let mut tmp = vec![0x00];
-            tmp.extend(sec1_signature[..32].to_vec());
-            tmp
-        } else {
-            // r is positive.
-            sec1_signature[..32].to_vec()
-        };
+        Signature::from_compact(&sec1_signature)
+            .expect("Invalid SEC1 signature.")
+            .serialize_der()
+            .to_vec()
+    }
 
-        let s: Vec<u8> = if sec1_signature[32] & 0x80 != 0 {
-            // s is negative. Prepend a zero byte.
-            let mut tmp = vec![0x00];
-            tmp.extend(sec1_signature[32..].to_vec());
-            tmp
-        } else {
-            // s is positive.
-            sec1_signature[32..].to_vec()
-        };
+    // Parses the given signature of a witness, i.e. in the DER format followed by the
+    // sighash type. Non-minimal encodings, e.g. of the signatures collected in the
+    // witness by previous versions, are accepted, and the signature is normalized.
+    fn parse_witness_signature(witness_signature: &[u8]) -> bitcoin::ecdsa::Signature {
+        let (sighash_type, der_signature) = witness_signature.split_last()
+            .expect("Empty signature in the witness.");
+        let mut signature = Signature::from_der_lax(der_signature)
+            .expect("Invalid signature in the witness.");
+        signature.normalize_s();
+        bitcoin::ecdsa::Signature {
+            sig: signature,
+            hash_ty: EcdsaSighashType::from_standard(*sighash_type as u32)
+                .expect("Invalid sighash type in the witness."),
+        }
+    }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use bitcoin::secp256k1::SecretKey;
+
+        // The uncompressed SEC1 encoding of the public key of the given secret.
+        fn test_public_key(seed: u8) -> Vec<u8> {
+            let secp = Secp256k1::new();
+            bitcoin::secp256k1::PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[seed; 32]).unwrap())
+                .serialize_uncompressed()
+                .to_vec()
+        }
+
+        #[test]
+        fn reuses_the_fee_percentiles_until_they_expire() {
+            let mut custody_data = CustodyData::default();
+            assert_eq!(cached_fee_percentiles(&mut custody_data, 0), None);
+            custody_data.fee_percentiles = Some(CachedFeePercentiles {
+                fee_percentiles: vec![1_000, 2_000],
+                timestamp: 100,
+            });
+            assert_eq!(cached_fee_percentiles(&mut custody_data, 100), Some(vec![1_000, 2_000]));
+            assert_eq!(cached_fee_percentiles(&mut custody_data, 99 + FEE_PERCENTILES_TTL_NS), Some(vec![1_000, 2_000]));
+            assert_eq!(cached_fee_percentiles(&mut custody_data, 100 + FEE_PERCENTILES_TTL_NS), None);
+            assert_eq!(custody_data.cache_metrics.fee_percentiles_hits, 2);
+            assert_eq!(custody_data.cache_metrics.fee_percentiles_misses, 2);
+        }
+
+        #[test]
+        fn reuses_the_fiduciary_public_keys_by_derivation_path() {
+            let mut custody_data = CustodyData::default();
+            let derivation_path = vec![vec![1]];
+            assert_eq!(cached_fiduciary_public_key(&mut custody_data, &derivation_path), None);
+            custody_data.fiduciary_public_keys.insert(derivation_path.clone(), test_public_key(2));
+            assert_eq!(cached_fiduciary_public_key(&mut custody_data, &derivation_path), Some(test_public_key(2)));
+            assert_eq!(cached_fiduciary_public_key(&mut custody_data, &vec![vec![2]]), None);
+            assert_eq!(custody_data.cache_metrics.fiduciary_key_hits, 1);
+            assert_eq!(custody_data.cache_metrics.fiduciary_key_misses, 2);
+        }
+
+        // The witness script of a 2-of-2 wallet with the given keys, or 2-of-3 with a third one.
+        fn test_witness_script(public_keys: &[Vec<u8>]) -> ScriptBuf {
+            let public_keys: Vec<Vec<u8>> = public_keys.iter().map(|public_key| compress_public_key(public_key)).collect();
+            build_wallet_witness_script(&public_keys[0], &public_keys[1], &public_keys.get(2).cloned(), None, &None).unwrap()
+        }
+
+        #[test]
+        fn rebuilds_the_witness_script_with_the_compressed_keys() {
+            let (pk1, pk2) = (test_public_key(1), test_public_key(2));
+            let witness_script = test_witness_script(&[pk1.clone(), pk2.clone()]);
+            assert_eq!(witness_script_public_keys(&witness_script), vec![compress_public_key(&pk1), compress_public_key(&pk2)]);
+            assert_eq!(compress_public_key(&pk1).len(), 33);
+        }
+
+        #[test]
+        fn detects_a_witness_script_built_with_another_key() {
+            let (pk1, pk2) = (test_public_key(1), test_public_key(2));
+            let stored_keys = witness_script_public_keys(&test_witness_script(&[pk1.clone(), test_public_key(3)]));
+            assert!(stored_keys.contains(&compress_public_key(&pk1)));
+            assert!(!stored_keys.contains(&compress_public_key(&pk2)));
+            assert_ne!(test_witness_script(&[pk1.clone(), pk2.clone()]), test_witness_script(&[pk2, pk1]));
+        }
+
+        // A transaction spending the given number of outputs of distinct transactions.
+        fn test_transaction(input_count: u8, output: Vec<TxOut>) -> Transaction {
+            Transaction {
+                input: (1..=input_count).map(|seed| TxIn {
+                    previous_output: OutPoint {
+                        txid: Txid::from_raw_hash(Hash::from_slice(&[seed; 32]).unwrap()),
+                        vout: 0,
+                    },
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                    script_sig: ScriptBuf::new(),
+                }).collect(),
+                output,
+                lock_time: LockTime::ZERO,
+                version: bitcoin::blockdata::transaction::Version::TWO,
+            }
+        }
+
+        // The transaction info of a test transaction spending outputs of 10,000 satoshis
+        // of a 2-of-2 wallet with the keys of the secrets 1 and 2.
+        fn test_transaction_info(input_count: u8) -> TransactionInfo {
+            let transaction = test_transaction(input_count, vec![]);
+            let witness_script = test_witness_script(&[test_public_key(1), test_public_key(2)]);
+            TransactionInfo::new(transaction, vec![(witness_script, None, Amount::from_sat(10_000)); input_count as usize])
+        }
+
+        #[test]
+        fn computes_the_amount_spent_from_each_wallet() {
+            let first_script = test_witness_script(&[test_public_key(1), test_public_key(2)]);
+            let second_script = test_witness_script(&[test_public_key(3), test_public_key(4)]);
+            let transaction = test_transaction(3, vec![
+                TxOut { value: Amount::from_sat(12_000), script_pubkey: ScriptBuf::new_p2wsh(&test_witness_script(&[test_public_key(5), test_public_key(6)]).wscript_hash()) },
+                TxOut { value: Amount::from_sat(7_000), script_pubkey: ScriptBuf::new_p2wsh(&second_script.wscript_hash()) },
+            ]);
+            let transaction_info = TransactionInfo::new(transaction, vec![
+                (first_script.clone(), None, Amount::from_sat(10_000)),
+                (second_script.clone(), None, Amount::from_sat(5_000)),
+                (second_script.clone(), None, Amount::from_sat(5_000)),
+            ]);
+            assert_eq!(wallet_spendings(&transaction_info), BTreeMap::from([(first_script, 10_000), (second_script, 3_000)]));
+        }
+
+        // The SEC1 signature of the given sighash with the given secret.
+        fn test_signature(sig_hash: &SegwitV0Sighash, seed: u8) -> Vec<u8> {
+            let message = Message::from_digest_slice(&sig_hash.to_byte_array()).unwrap();
+            Secp256k1::new()
+                .sign_ecdsa(&message, &SecretKey::from_slice(&[seed; 32]).unwrap())
+                .serialize_compact()
+                .to_vec()
+        }
+
+        #[test]
+        fn resumes_signing_without_requesting_the_signed_inputs() {
+            let transaction_info = test_transaction_info(2);
+            let mut signatures = vec![Some(vec![0x11; 64]), Some(vec![0x22; 64])];
+            let failed_inputs = futures::executor::block_on(sign_inputs(&transaction_info, "key", &[vec![vec![1]], vec![vec![1]]], &mut signatures));
+            assert!(failed_inputs.is_empty());
+            assert_eq!(signatures, vec![Some(vec![0x11; 64]), Some(vec![0x22; 64])]);
+        }
+
+        #[test]
+        #[should_panic(expected = "must have the same length")]
+        fn rejects_a_signing_session_of_another_transaction() {
+            let transaction_info = test_transaction_info(2);
+            futures::executor::block_on(sign_inputs(&transaction_info, "key", &[vec![vec![1]], vec![vec![1]]], &mut vec![None]));
+        }
+
+        // The signatures of the inputs of the given transaction with the given secret.
+        fn test_signatures(transaction_info: &TransactionInfo, seed: u8) -> Vec<Vec<u8>> {
+            test_sig_hashes(transaction_info).iter()
+                .map(|sig_hash| test_signature(sig_hash, seed))
+                .collect()
+        }
+
+        fn test_sig_hashes(transaction_info: &TransactionInfo) -> Vec<SegwitV0Sighash> {
+            transaction_info.inputs().iter().map(|input| input.sig_hash).collect()
+        }
+
+        // The given SEC1 signature, as found in a witness.
+        fn test_witness_signature(sec1_signature: &[u8]) -> Vec<u8> {
+            [sec1_to_der(sec1_signature.to_vec()), vec![SIG_HASH_TYPE.to_u32() as u8]].concat()
+        }
+
+        #[test]
+        fn inserts_the_signatures_of_both_canisters_in_the_witnesses() {
+            let transaction_info = test_transaction_info(2);
+            let (custody_signatures, fiduciary_signatures) = (test_signatures(&transaction_info, 1), test_signatures(&transaction_info, 2));
+            let first = insert_signatures(&transaction_info, custody_signatures.clone(), MultisigIndex::First);
+            let last = insert_signatures(&first, fiduciary_signatures.clone(), MultisigIndex::Last);
+
+            let witnesses: Vec<Vec<Vec<u8>>> = last.transaction().input.iter().map(|input| input.witness.to_vec()).collect();
+            assert_eq!(witnesses, vec![
+                vec![vec![], test_witness_signature(&custody_signatures[0]), test_witness_signature(&fiduciary_signatures[0]), transaction_info.inputs()[0].witness_script.to_bytes()],
+                vec![vec![], test_witness_signature(&custody_signatures[1]), test_witness_signature(&fiduciary_signatures[1]), transaction_info.inputs()[0].witness_script.to_bytes()],
+            ]);
+            assert_eq!(test_sig_hashes(&last), test_sig_hashes(&transaction_info));
+        }
+
+        #[test]
+        fn psbt_keeps_the_inputs_and_partial_signatures() {
+            let transaction_info = test_transaction_info(2);
+            let signatures = test_signatures(&transaction_info, 1);
+
+            let mut psbt = transaction_info.to_psbt();
+            transaction_info.add_partial_signatures(&mut psbt, 0, &signatures);
+            let (parsed, partial_signatures) = TransactionInfo::from_psbt(&psbt);
+            assert_eq!(parsed.transaction(), transaction_info.transaction());
+            assert_eq!(test_sig_hashes(&parsed), test_sig_hashes(&transaction_info));
+            for (parsed_input, input) in parsed.inputs().iter().zip(transaction_info.inputs()) {
+                assert_eq!(parsed_input.witness_script, input.witness_script);
+                assert_eq!(parsed_input.amount, input.amount);
+                assert_eq!(parsed_input.derivation_path, None);
+            }
+            assert_eq!(partial_signatures, BTreeMap::from([(0, signatures)]));
+        }
+
+        #[test]
+        #[should_panic(expected = "Invalid partial signature")]
+        fn psbt_rejects_a_signature_of_another_transaction() {
+            let transaction_info = test_transaction_info(1);
+            let signature = test_signature(&SegwitV0Sighash::from_byte_array([7; 32]), 1);
+            let mut psbt = transaction_info.to_psbt();
+            transaction_info.add_partial_signatures(&mut psbt, 0, &[signature]);
+            TransactionInfo::from_psbt(&psbt);
+        }
+
+        #[test]
+        #[should_panic(expected = "Invalid partial signature")]
+        fn psbt_rejects_the_signatures_of_another_signer() {
+            let transaction_info = test_transaction_info(1);
+            let signature = test_signature(&test_sig_hashes(&transaction_info)[0], 2);
+            let mut psbt = transaction_info.to_psbt();
+            transaction_info.add_partial_signatures(&mut psbt, 0, &[signature]);
+            TransactionInfo::from_psbt(&psbt);
+        }
+
+        #[test]
+        fn builds_a_2_of_3_witness_script_with_the_user_key() {
+            let public_keys = vec![test_public_key(1), test_public_key(2), compress_public_key(&test_public_key(3))];
+            let witness_script = test_witness_script(&public_keys);
+            let expected = bitcoin::blockdata::script::Builder::new()
+                .push_int(2)
+                .push_slice(PublicKey::from_slice(&public_keys[0]).unwrap().serialize())
+                .push_slice(PublicKey::from_slice(&public_keys[1]).unwrap().serialize())
+                .push_slice(PublicKey::from_slice(&public_keys[2]).unwrap().serialize())
+                .push_int(3)
+                .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKMULTISIG)
+                .into_script();
+            assert_eq!(witness_script, expected);
+            assert_eq!(signer_public_key(&witness_script, 2).to_bytes(), compress_public_key(&public_keys[2]));
+        }
+
+        #[test]
+        fn psbt_only_returns_the_signers_of_all_the_inputs() {
+            let transaction = test_transaction(2, vec![]);
+            let witness_script = test_witness_script(&[test_public_key(1), test_public_key(2), test_public_key(3)]);
+            let inputs = vec![(witness_script, None, Amount::from_sat(10_000)); 2];
+            let transaction_info = TransactionInfo::new(transaction, inputs);
+            let sig_hashes = test_sig_hashes(&transaction_info);
+
+            // The user signs all the inputs, the custody canister only the first one.
+            let mut psbt = transaction_info.to_psbt();
+            let user_signatures = vec![test_signature(&sig_hashes[0], 3), test_signature(&sig_hashes[1], 3)];
+            transaction_info.add_partial_signatures(&mut psbt, 2, &user_signatures);
+            let custody_signature = test_signature(&sig_hashes[0], 1);
+            psbt.inputs[0].partial_sigs.insert(signer_public_key(&transaction_info.inputs()[0].witness_script, 0), bitcoin::ecdsa::Signature {
+                sig: Signature::from_compact(&custody_signature).unwrap(),
+                hash_ty: SIG_HASH_TYPE,
+            });
+
+            let (_, partial_signatures) = TransactionInfo::from_psbt(&psbt);
+            assert_eq!(partial_signatures, BTreeMap::from([(2, user_signatures)]));
+        }
+
+        // A wallet of the given principal with the keys of the secrets 1 and 2, and the
+        // key of the secret 3 held by the user to recover it after the given delay.
+        fn test_recovery_wallet(principal: Principal, recovery_delay: u16) -> UserWallet {
+            let user_public_key = compress_public_key(&test_public_key(3));
+            let witness_script = build_wallet_witness_script(
+                &compress_public_key(&test_public_key(1)),
+                &compress_public_key(&test_public_key(2)),
+                &Some(user_public_key.clone()),
+                Some(recovery_delay),
+                &None,
+            ).unwrap();
+            UserWallet {
+                address: Address::p2wsh(&witness_script, Network::Regtest),
+                witness_script,
+                derivation_path: vec![principal.as_slice().to_vec()],
+                user_public_key: Some(user_public_key),
+                recovery_delay: Some(recovery_delay),
+                policy: None,
+            }
+        }
+
+        fn test_utxo(seed: u8, value: u64) -> Utxo {
+            Utxo {
+                outpoint: ic_cdk::api::management_canister::bitcoin::Outpoint { txid: vec![seed; 32], vout: 0 },
+                value,
+                height: 1,
+            }
+        }
+
+        #[test]
+        fn recovery_transaction_spends_everything_with_the_timelock() {
+            let wallet = test_recovery_wallet(Principal::from_slice(&[1]), 144);
+            let dst_address = Address::p2wsh(&test_witness_script(&[test_public_key(4), test_public_key(5)]), Network::Regtest);
+            let utxos = vec![test_utxo(1, 20_000), test_utxo(2, 30_000)];
+            let transaction_info = build_transaction_with_fee(&wallet, &utxos, &dst_address, None, 1_000, SpendPath::Recovery).unwrap();
+
+            let transaction = transaction_info.transaction();
+            assert_eq!(transaction.version, bitcoin::blockdata::transaction::Version::TWO);
+            assert!(transaction.input.iter().all(|input| input.sequence == Sequence::from_height(144)));
+            assert_eq!(transaction.output, vec![TxOut {
+                script_pubkey: dst_address.script_pubkey(),
+                value: Amount::from_sat(49_000),
+            }]);
+            assert!(build_transaction_with_fee(&wallet, &utxos[..1], &dst_address, None, 19_500, SpendPath::Recovery).is_err());
+        }
+
+        #[test]
+        fn satisfies_a_2_of_3_wallet_with_the_custody_and_user_keys() {
+            let transaction = test_transaction(1, vec![]);
+            let witness_script = test_witness_script(&[test_public_key(1), test_public_key(2), test_public_key(3)]);
+            let inputs = vec![(witness_script.clone(), None, Amount::from_sat(10_000))];
+            let transaction_info = TransactionInfo::new(transaction, inputs);
+
+            // The user signs first, the custody canister completes the witness.
+            let (user_signatures, custody_signatures) = (test_signatures(&transaction_info, 3), test_signatures(&transaction_info, 1));
+            let signed = insert_signatures(&transaction_info, user_signatures.clone(), MultisigIndex::First);
+            let signed = insert_signatures(&signed, custody_signatures.clone(), MultisigIndex::Last);
+            let witness = signed.transaction().input[0].witness.to_vec();
+            assert_eq!(witness, vec![
+                vec![],
+                test_witness_signature(&custody_signatures[0]),
+                test_witness_signature(&user_signatures[0]),
+                witness_script.to_bytes(),
+            ]);
+
+            // The fee is estimated with the largest witness.
+            let fake_witness = fake_signatures(&transaction_info).transaction().input[0].witness.to_vec();
+            assert!(fake_witness.iter().map(Vec::len).sum::<usize>() >= witness.iter().map(Vec::len).sum::<usize>());
+        }
+
+        const SECOND: u64 = 1_000_000_000;
+
+        #[test]
+        fn heir_withdraws_once_the_owner_is_inactive() {
+            let owner = Principal::from_slice(&[1]);
+            let heir = Principal::from_slice(&[2]);
+            let mut inheritances = HashMap::new();
+            set_heir(&mut inheritances, owner, heir, 60, 0);
+
+            assert!(std::panic::catch_unwind(|| check_heir(&inheritances, owner, heir, 59 * SECOND)).is_err());
+            check_heir(&inheritances, owner, heir, 60 * SECOND);
+
+            // A check-in postpones the inheritance.
+            check_in(&mut inheritances, owner, 100 * SECOND);
+            assert!(std::panic::catch_unwind(|| check_heir(&inheritances, owner, heir, 159 * SECOND)).is_err());
+            check_heir(&inheritances, owner, heir, 160 * SECOND);
+        }
+
+        #[test]
+        #[should_panic(expected = "is not the heir of")]
+        fn only_the_heir_withdraws_from_the_wallet_of_the_owner() {
+            let owner = Principal::from_slice(&[1]);
+            let mut inheritances = HashMap::new();
+            set_heir(&mut inheritances, owner, Principal::from_slice(&[2]), 60, 0);
+            check_heir(&inheritances, owner, Principal::from_slice(&[3]), 100 * SECOND);
+        }
+
+        #[test]
+        fn rejects_an_invalid_heir() {
+            let owner = Principal::from_slice(&[1]);
+            assert!(std::panic::catch_unwind(|| set_heir(&mut HashMap::new(), owner, owner, 60, 0)).is_err());
+            assert!(std::panic::catch_unwind(|| set_heir(&mut HashMap::new(), owner, Principal::anonymous(), 60, 0)).is_err());
+            assert!(std::panic::catch_unwind(|| set_heir(&mut HashMap::new(), owner, Principal::from_slice(&[2]), 0, 0)).is_err());
+            assert!(std::panic::catch_unwind(|| check_in(&mut HashMap::new(), owner, 0)).is_err());
+        }
+
+        #[test]
+        fn rejects_an_invalid_shared_wallet() {
+            let creator = Principal::from_slice(&[1]);
+            let member = Principal::from_slice(&[2]);
+            let create = |name: &str, members: Vec<Principal>, threshold: u64| {
+                let name = name.to_string();
+                std::panic::catch_unwind(move || {
+                    futures::executor::block_on(create_shared_wallet(&mut CustodyData::default(), creator, name, members, threshold))
+                })
+            };
+            assert!(create("", vec![member], 1).is_err());
+            assert!(create(&"a".repeat(MAX_SHARED_WALLET_NAME_LENGTH + 1), vec![member], 1).is_err());
+            assert!(create("wallet", vec![member, Principal::anonymous()], 1).is_err());
+            assert!(create("wallet", vec![member], 0).is_err());
+            // The creator and the duplicates only count once.
+            assert!(create("wallet", vec![creator, member, member], 3).is_err());
+        }
+
+        #[test]
+        fn derives_the_shared_wallets_apart_from_the_user_wallets() {
+            let creator = Principal::from_slice(&[1]);
+            let id = SharedWalletId { creator, name: String::from("wallet") };
+            let other_id = SharedWalletId { creator, name: String::from("other") };
+            assert_eq!(shared_wallet_derivation_path(&id), vec![creator.as_slice().to_vec(), b"wallet".to_vec()]);
+            assert_ne!(shared_wallet_derivation_path(&id), shared_wallet_derivation_path(&other_id));
+            assert_ne!(shared_wallet_derivation_path(&id), vec![creator.as_slice().to_vec()]);
+        }
+
+        // The address of a 2-of-2 wallet with the keys of the given secret and the next one.
+        fn test_address(seed: u8) -> String {
+            Address::p2wsh(&test_witness_script(&[test_public_key(seed), test_public_key(seed + 1)]), Network::Regtest).to_string()
+        }
+
+        #[test]
+        fn allowlist_allows_the_addresses_after_the_cooling_off_period() {
+            let owner = Principal::from_slice(&[1]);
+            let address = test_address(1);
+            let mut allowlists = HashMap::new();
+            enable_allowlist(&mut allowlists, owner, 60, 0);
+            add_allowed_address(&mut allowlists, owner, address.clone(), BitcoinNetwork::Regtest, 10 * SECOND);
+
+            let allowlist = allowlists.get(&owner);
+            assert!(std::panic::catch_unwind(|| check_allowlist(allowlist, &address, BitcoinNetwork::Regtest, 69 * SECOND)).is_err());
+            check_allowlist(allowlist, &address, BitcoinNetwork::Regtest, 70 * SECOND);
+            check_allowlist(None, &address, BitcoinNetwork::Regtest, 0);
+        }
+
+        #[test]
+        #[should_panic(expected = "is not in the allowlist")]
+        fn allowlist_rejects_the_other_addresses() {
+            let owner = Principal::from_slice(&[1]);
+            let mut allowlists = HashMap::new();
+            enable_allowlist(&mut allowlists, owner, 60, 0);
+            add_allowed_address(&mut allowlists, owner, test_address(1), BitcoinNetwork::Regtest, 0);
+            check_allowlist(allowlists.get(&owner), &test_address(3), BitcoinNetwork::Regtest, 100 * SECOND);
+        }
+
+        #[test]
+        fn allowlist_removals_are_immediate_and_disabling_is_delayed() {
+            let owner = Principal::from_slice(&[1]);
+            let address = test_address(1);
+            let other_address = test_address(3);
+            let mut allowlists = HashMap::new();
+            enable_allowlist(&mut allowlists, owner, 60, 0);
+            add_allowed_address(&mut allowlists, owner, address.clone(), BitcoinNetwork::Regtest, 0);
+            remove_allowed_address(&mut allowlists, owner, address, BitcoinNetwork::Regtest, 100 * SECOND);
+            assert!(allowlists[&owner].addresses.is_empty());
+
+            let event = disable_allowlist(&mut allowlists, owner, 100 * SECOND);
+            assert_eq!(event.timestamp, 100 * SECOND);
+            let allowlist = allowlists.get(&owner);
+            assert!(std::panic::catch_unwind(|| check_allowlist(allowlist, &other_address, BitcoinNetwork::Regtest, 159 * SECOND)).is_err());
+            check_allowlist(allowlist, &other_address, BitcoinNetwork::Regtest, 160 * SECOND);
+
+            // Enabling it again cancels the disabling, with a cooling-off period at least as long.
+            assert!(std::panic::catch_unwind(|| enable_allowlist(&mut allowlists.clone(), owner, 59, 0)).is_err());
+            enable_allowlist(&mut allowlists, owner, 120, 200 * SECOND);
+            assert_eq!(allowlists[&owner].disabled_from, None);
+        }
+
+        #[test]
+        fn checks_every_destination_and_the_amount_sent_by_a_psbt() {
+            let owner = Principal::from_slice(&[1]);
+            let wallet = test_wallet(owner, 1);
+            let utxos = vec![test_utxo(1, 50_000)];
+            let allowed_address = test_address(3);
+            let dst_address = Address::from_str(&allowed_address).unwrap().assume_checked();
+            let transaction_info = build_transaction_with_fee(&wallet, &utxos, &dst_address, Some(30_000), 1_000, SpendPath::Multisig).unwrap();
+
+            // The change does not count, neither against the allowlist nor the maximum amount.
+            let mut allowlists = HashMap::new();
+            enable_allowlist(&mut allowlists, owner, 0, 0);
+            add_allowed_address(&mut allowlists, owner, allowed_address, BitcoinNetwork::Regtest, 0);
+            check_psbt_outputs(&transaction_info, Some(30_000), allowlists.get(&owner), BitcoinNetwork::Regtest, SECOND);
+            assert!(std::panic::catch_unwind(|| check_psbt_outputs(&transaction_info, Some(29_999), None, BitcoinNetwork::Regtest, SECOND)).is_err());
+
+            // Another output to an address outside the allowlist is rejected.
+            let mut transaction_info = transaction_info;
+            transaction_info.transaction.output.push(TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey: Address::from_str(&test_address(5)).unwrap().assume_checked().script_pubkey(),
+            });
+            assert!(std::panic::catch_unwind(|| check_psbt_outputs(&transaction_info, None, allowlists.get(&owner), BitcoinNetwork::Regtest, SECOND)).is_err());
+            check_psbt_outputs(&transaction_info, None, None, BitcoinNetwork::Regtest, SECOND);
+        }
+
+        #[test]
+        fn pauses_and_resumes_a_wallet_once() {
+            let derivation_path = vec![vec![1]];
+            let mut pause_state = PauseState::default();
+            set_wallet_paused(&mut pause_state, derivation_path.clone(), true);
+            set_wallet_paused(&mut pause_state, derivation_path.clone(), true);
+            assert_eq!(pause_state.paused_wallets, vec![derivation_path.clone()]);
+            assert!(std::panic::catch_unwind(|| check_not_paused(&pause_state, &derivation_path)).is_err());
+            check_not_paused(&pause_state, &vec![vec![2]]);
+
+            set_wallet_paused(&mut pause_state, derivation_path.clone(), false);
+            assert!(pause_state.paused_wallets.is_empty());
+            check_not_paused(&pause_state, &derivation_path);
+        }
+
+        #[test]
+        #[should_panic(expected = "Withdrawals are paused.")]
+        fn global_pause_stops_every_wallet() {
+            let pause_state = PauseState { paused: true, paused_wallets: vec![] };
+            check_not_paused(&pause_state, &vec![vec![1]]);
+        }
+
+        #[test]
+        fn controllers_have_every_role_of_the_operators() {
+            let operator = Principal::from_slice(&[1]);
+            let mut admin_state = AdminState::default();
+            add_operator(&mut admin_state, operator);
+            add_operator(&mut admin_state, operator);
+            assert_eq!(admin_state.operators, vec![operator]);
+
+            assert_eq!(role_of(&admin_state, &operator, false), Some(Role::Operator));
+            assert_eq!(role_of(&admin_state, &operator, true), Some(Role::Controller));
+            assert_eq!(role_of(&admin_state, &Principal::from_slice(&[2]), false), None);
+            assert!(Role::Controller > Role::Operator);
+
+            remove_operator(&mut admin_state, &operator);
+            assert_eq!(role_of(&admin_state, &operator, false), None);
+            assert!(std::panic::catch_unwind(|| add_operator(&mut AdminState::default(), Principal::anonymous())).is_err());
+        }
+
+        #[test]
+        fn mainnet_requires_the_production_key_and_a_request_limit() {
+            check_mainnet_configuration(PRODUCTION_KEY_NAME, Some(100_000));
+            assert!(std::panic::catch_unwind(|| check_mainnet_configuration("test_key_1", Some(100_000))).is_err());
+            assert!(std::panic::catch_unwind(|| check_mainnet_configuration(PRODUCTION_KEY_NAME, None)).is_err());
+        }
+
+        #[test]
+        fn checks_the_protocol_versions_spoken() {
+            assert!(check_protocol_version(MIN_PROTOCOL_VERSION).is_ok());
+            assert!(check_protocol_version(PROTOCOL_VERSION).is_ok());
+            assert!(check_protocol_version(MIN_PROTOCOL_VERSION - 1).is_err());
+            assert!(check_protocol_version(PROTOCOL_VERSION + 1).is_err());
+        }
+
+        #[test]
+        fn negotiates_the_latest_common_protocol_version() {
+            assert_eq!(negotiate_protocol_version(&protocol_versions()), Ok(PROTOCOL_VERSION));
+            // A peer not upgraded yet.
+            assert_eq!(negotiate_protocol_version(&ProtocolVersions { min_version: 1, max_version: 1 }), Ok(1));
+            // A peer upgraded further.
+            assert_eq!(negotiate_protocol_version(&ProtocolVersions { min_version: 1, max_version: PROTOCOL_VERSION + 3 }), Ok(PROTOCOL_VERSION));
+            // A peer that dropped the versions spoken by this canister.
+            assert!(negotiate_protocol_version(&ProtocolVersions { min_version: PROTOCOL_VERSION + 1, max_version: PROTOCOL_VERSION + 3 }).is_err());
+        }
+
+        #[test]
+        fn describes_the_inputs_of_several_wallets() {
+            let (witness_script, other_witness_script) = (test_witness_script(&[test_public_key(1), test_public_key(2)]), test_witness_script(&[test_public_key(3), test_public_key(4)]));
+            let dst_address = Address::p2wsh(&test_witness_script(&[test_public_key(5), test_public_key(6)]), Network::Regtest);
+            let transaction = test_transaction(2, vec![
+                TxOut { script_pubkey: dst_address.script_pubkey(), value: Amount::from_sat(5_000) },
+                TxOut { script_pubkey: ScriptBuf::new_p2wsh(&other_witness_script.wscript_hash()), value: Amount::from_sat(14_000) },
+            ]);
+            let transaction_info = TransactionInfo::new(transaction, vec![
+                (witness_script, Some(vec![vec![1]]), Amount::from_sat(10_000)),
+                (other_witness_script, Some(vec![vec![2]]), Amount::from_sat(10_000)),
+            ]);
+            assert_eq!(transaction_info.sent_amount(), Amount::from_sat(5_000));
+            assert!(std::panic::catch_unwind(|| transaction_info.check_derivation_path(&vec![vec![1]])).is_err());
+
+            // The sighashes are computed again from the inputs of the raw transaction info.
+            let parsed = TransactionInfo::from_raw(transaction_info.to_raw());
+            assert_eq!(test_sig_hashes(&parsed), test_sig_hashes(&transaction_info));
+            let mut raw_transaction_info = transaction_info.to_raw();
+            raw_transaction_info.sig_hashes.swap(0, 1);
+            assert!(std::panic::catch_unwind(|| TransactionInfo::from_raw(raw_transaction_info)).is_err());
+        }
+
+        // A 2-of-2 wallet of the given principal, with the keys of the given secret and the next one.
+        fn test_wallet(principal: Principal, seed: u8) -> UserWallet {
+            let witness_script = test_witness_script(&[test_public_key(seed), test_public_key(seed + 1)]);
+            UserWallet {
+                address: Address::p2wsh(&witness_script, Network::Regtest),
+                witness_script,
+                derivation_path: vec![principal.as_slice().to_vec()],
+                user_public_key: None,
+                recovery_delay: None,
+                policy: None,
+            }
+        }
+
+        fn test_withdrawal(id: u64, owner: Principal, amount: u64) -> BatchedWithdrawal {
+            BatchedWithdrawal {
+                id,
+                bitcoin_network: BitcoinNetwork::Regtest,
+                owner,
+                destination_address: test_address(100),
+                amount,
+                queued_at: 0,
+                status: WithdrawalStatus::Queued,
+            }
+        }
+
+        #[test]
+        fn splits_the_fee_in_proportion_to_the_amounts() {
+            assert_eq!(split_fee(1_000, &[3_000, 1_000]), vec![750, 250]);
+            assert_eq!(split_fee(1_000, &[1, 1, 1]), vec![334, 333, 333]);
+            assert_eq!(split_fee(1_000, &[0, 0]), vec![0, 0]);
+            assert_eq!(split_fee(u64::MAX, &[u64::MAX, u64::MAX]).iter().map(|fee| *fee as u128).sum::<u128>(), u64::MAX as u128);
+        }
+
+        // A batch of a withdrawal of each of two owners, with the given fee.
+        fn test_batch(fee: u64) -> (Vec<BatchedWithdrawal>, TransactionInfo) {
+            let first = Principal::from_slice(&[1]);
+            let second = Principal::from_slice(&[2]);
+            let wallets = vec![
+                (first, test_wallet(first, 1), vec![test_utxo(1, 50_000)]),
+                (second, test_wallet(second, 3), vec![test_utxo(2, 80_000)]),
+            ];
+            let withdrawals = vec![test_withdrawal(0, first, 30_000), test_withdrawal(1, second, 10_000)];
+            let (transaction_info, _) = build_batch_transaction_with_fee(&wallets, &withdrawals, &BTreeMap::new(), fee, BitcoinNetwork::Regtest).unwrap();
+            (withdrawals, transaction_info)
+        }
+
+        #[test]
+        fn accepts_a_batch_where_each_owner_pays_its_share_of_the_fee() {
+            let (withdrawals, transaction_info) = test_batch(2_000);
+            let outputs: Vec<u64> = transaction_info.transaction().output.iter().map(|output| output.value.to_sat()).collect();
+            assert_eq!(outputs, vec![30_000, 10_000, 50_000 - 31_500, 80_000 - 10_500]);
+            assert!(check_batch_transaction(&transaction_info, &withdrawals, &[], BitcoinNetwork::Regtest).is_ok());
+        }
+
+        #[test]
+        fn rejects_a_batch_where_an_owner_pays_the_fee_of_another() {
+            let (withdrawals, mut transaction_info) = test_batch(4_000);
+            // The change of the first owner pays the whole fee, more than its share and the
+            // dust, and the second one gets the rest back.
+            transaction_info.transaction.output[2].value = Amount::from_sat(50_000 - 30_000 - 4_000);
+            transaction_info.transaction.output[3].value = Amount::from_sat(80_000 - 10_000);
+            assert!(check_batch_transaction(&transaction_info, &withdrawals, &[], BitcoinNetwork::Regtest).is_err());
+        }
+
+        #[test]
+        fn rejects_a_batch_without_the_output_of_a_withdrawal() {
+            let (withdrawals, mut transaction_info) = test_batch(2_000);
+            transaction_info.transaction.output[1].value = Amount::from_sat(9_000);
+            assert!(check_batch_transaction(&transaction_info, &withdrawals, &[], BitcoinNetwork::Regtest).is_err());
+            assert!(check_batch_transaction(&transaction_info, &[], &[], BitcoinNetwork::Regtest).is_err());
+        }
+
+        #[test]
+        fn computes_the_balance_on_the_ledger() {
+            assert_eq!(ledger_balance(100, -30, 20), Some(50));
+            assert_eq!(ledger_balance(0, 50, 0), Some(50));
+            assert_eq!(ledger_balance(10, -30, 0), None);
+            assert_eq!(ledger_balance(u64::MAX, 1, 0), None);
+        }
+
+        #[test]
+        fn settlements_only_pay_debts_to_credits() {
+            let creditor = Principal::from_slice(&[1]);
+            let debtor = Principal::from_slice(&[2]);
+            let adjustments = BTreeMap::from([(creditor, 50), (debtor, -50)]);
+
+            let mut settled = adjustments.clone();
+            apply_settlements(&mut settled, &[Settlement { debtor, creditor, amount: 30 }]).unwrap();
+            assert_eq!(settled, BTreeMap::from([(creditor, 20), (debtor, -20)]));
 
-        // Convert signature to DER.
-        vec![
-            vec![0x30, 4 + r.len() as u8 + s.len() as u8, 0x02, r.len() as u8],
-            r,
-            vec![0x02, s.len() as u8],
-            s,
-        ]
-        .into_iter()
-        .flatten()
-        .collect()
+            let mut settled = adjustments.clone();
+            apply_settlements(&mut settled, &[Settlement { debtor, creditor, amount: 50 }]).unwrap();
+            assert!(settled.is_empty());
+
+            let mut settled = adjustments.clone();
+            assert!(apply_settlements(&mut settled, &[Settlement { debtor, creditor, amount: 60 }]).is_err());
+            let mut settled = adjustments;
+            assert!(apply_settlements(&mut settled, &[Settlement { debtor: creditor, creditor: debtor, amount: 10 }]).is_err());
+        }
+
+        #[test]
+        fn rejects_the_settlements_beyond_the_amounts_of_the_ledger() {
+            let creditor = Principal::from_slice(&[1]);
+            let debtor = Principal::from_slice(&[2]);
+            let settlements = [Settlement { debtor, creditor, amount: i64::MAX as u64 + 1 }];
+
+            let mut settled = BTreeMap::from([(creditor, 50), (debtor, -50)]);
+            assert!(apply_settlements(&mut settled, &settlements).is_err());
+
+            let mut ledger = Ledger::default();
+            assert!(book_settlements(&mut ledger, &settlements, "transaction").is_err());
+            assert!(ledger.entries.is_empty() && ledger.adjustments.is_empty());
+        }
+
+        #[test]
+        fn plans_the_settlements_within_the_debts_and_balances() {
+            let creditor = Principal::from_slice(&[1]);
+            let small_debtor = Principal::from_slice(&[2]);
+            let poor_debtor = Principal::from_slice(&[3]);
+            let withdrawing_debtor = Principal::from_slice(&[5]);
+            let wallets = vec![
+                (creditor, test_wallet(creditor, 1), vec![]),
+                (small_debtor, test_wallet(small_debtor, 3), vec![test_utxo(1, 100_000)]),
+                (poor_debtor, test_wallet(poor_debtor, 5), vec![test_utxo(2, 20_000)]),
+                (withdrawing_debtor, test_wallet(withdrawing_debtor, 7), vec![test_utxo(3, 100_000)]),
+            ];
+            let withdrawn = BTreeMap::from([(creditor, 40_000), (withdrawing_debtor, 1_000)]);
+            let adjustments = BTreeMap::from([(creditor, 80_000), (small_debtor, -10_000), (poor_debtor, -50_000), (withdrawing_debtor, -20_000)]);
+
+            // The credit is settled up to the amount withdrawn, by the debtors not withdrawing.
+            assert_eq!(plan_settlements(&wallets, &withdrawn, &adjustments), vec![
+                Settlement { debtor: small_debtor, creditor, amount: 10_000 },
+                Settlement { debtor: poor_debtor, creditor, amount: 20_000 },
+            ]);
+        }
+
+        #[test]
+        fn creditor_without_utxos_withdraws_its_credit() {
+            let creditor = Principal::from_slice(&[1]);
+            let debtor = Principal::from_slice(&[2]);
+            let wallets = vec![
+                (creditor, test_wallet(creditor, 1), vec![]),
+                (debtor, test_wallet(debtor, 3), vec![test_utxo(1, 100_000)]),
+            ];
+            let withdrawals = vec![test_withdrawal(0, creditor, 40_000)];
+            let adjustments = BTreeMap::from([(creditor, 50_000), (debtor, -50_000)]);
+
+            let (transaction_info, settlements) = build_batch_transaction_with_fee(&wallets, &withdrawals, &adjustments, 1_000, BitcoinNetwork::Regtest)
+                .expect("The creditor can withdraw its credit.");
+
+            assert_eq!(settlements, vec![Settlement { debtor, creditor, amount: 41_000 }]);
+            assert_eq!(transaction_info.spent_amount(), Amount::from_sat(41_000));
+            let outputs: Vec<u64> = transaction_info.transaction().output.iter().map(|output| output.value.to_sat()).collect();
+            assert_eq!(outputs, vec![40_000, 59_000]);
+
+            let spendings = check_batch_transaction(&transaction_info, &withdrawals, &settlements, BitcoinNetwork::Regtest).unwrap();
+            assert_eq!(spendings, vec![
+                (creditor, None, 0),
+                (debtor, Some(wallets[1].1.witness_script.clone()), 41_000),
+            ]);
+        }
+
+        #[test]
+        fn creditor_cannot_withdraw_more_than_its_credit() {
+            let creditor = Principal::from_slice(&[1]);
+            let debtor = Principal::from_slice(&[2]);
+            let wallets = vec![
+                (creditor, test_wallet(creditor, 1), vec![]),
+                (debtor, test_wallet(debtor, 3), vec![test_utxo(1, 100_000)]),
+            ];
+            let withdrawals = vec![test_withdrawal(0, creditor, 50_000)];
+            let adjustments = BTreeMap::from([(creditor, 50_000), (debtor, -50_000)]);
+
+            assert!(build_batch_transaction_with_fee(&wallets, &withdrawals, &adjustments, 1_000, BitcoinNetwork::Regtest).is_err());
+        }
+
+        fn sec1_signature_with_leading_zero() -> Vec<u8> {
+            [vec![0x00], vec![0x11; 31], vec![0x22; 32]].concat()
+        }
+
+        #[test]
+        fn converts_a_sec1_signature_to_minimal_der() {
+            let der_signature = sec1_to_der(sec1_signature_with_leading_zero());
+            // r is encoded on 31 bytes, s on 32 bytes.
+            assert_eq!(der_signature.len(), 6 + 31 + 32);
+            let signature = bitcoin::ecdsa::Signature::from_slice(&[der_signature, vec![SIG_HASH_TYPE.to_u32() as u8]].concat()).unwrap();
+            assert_eq!(signature.sig.serialize_compact().to_vec(), sec1_signature_with_leading_zero());
+        }
+
+        #[test]
+        fn parses_a_non_minimal_witness_signature() {
+            let sec1_signature = sec1_signature_with_leading_zero();
+            let witness_signature = [
+                vec![0x30, 68, 0x02, 32],
+                sec1_signature[..32].to_vec(),
+                vec![0x02, 32],
+                sec1_signature[32..].to_vec(),
+                vec![SIG_HASH_TYPE.to_u32() as u8],
+            ].concat();
+            assert!(bitcoin::ecdsa::Signature::from_slice(&witness_signature).is_err());
+            let signature = parse_witness_signature(&witness_signature);
+            assert_eq!(signature.sig.serialize_compact().to_vec(), sec1_signature);
+            assert_eq!(signature.hash_ty, SIG_HASH_TYPE);
+        }
+ 
+        // Spend two UTXOs of the given wallet through the given path, with the signatures
+        // of the given secrets in order, and check that the witnesses of the signed
+        // transaction are valid by consensus and not larger than the fee estimate.
+        fn test_spend_and_verify(wallet: &UserWallet, spend_path: SpendPath, signers: &[u8]) {
+            let dst_address = Address::p2wsh(&test_witness_script(&[test_public_key(10), test_public_key(11)]), Network::Regtest);
+            let utxos = vec![test_utxo(1, 20_000), test_utxo(2, 30_000)];
+            let transaction_info = build_transaction_with_fee(wallet, &utxos, &dst_address, Some(35_000), 1_000, spend_path).unwrap();
+            let estimated_vsize = fake_signatures(&transaction_info).transaction().vsize();
+
+            let mut signed = transaction_info.clone();
+            for (position, signer) in signers.iter().enumerate() {
+                let signature_index = match position + 1 == signers.len() {
+                    true => MultisigIndex::Last,
+                    false => MultisigIndex::First,
+                };
+                signed = insert_signatures(&signed, test_signatures(&transaction_info, *signer), signature_index);
+            }
+
+            let transaction = signed.transaction();
+            assert!(transaction.vsize() <= estimated_vsize);
+            let serialized_transaction = bitcoin::consensus::serialize(transaction);
+            for (index, input) in signed.inputs().iter().enumerate() {
+                bitcoinconsensus::verify(wallet.address.script_pubkey().as_bytes(), input.amount.to_sat(), &serialized_transaction, index)
+                    .unwrap_or_else(|error| panic!("The input {} is not valid: {:?}", index, error));
+            }
+        }
+
+        #[test]
+        fn spends_from_a_2_of_2_wallet() {
+            test_spend_and_verify(&test_wallet(Principal::from_slice(&[1]), 1), SpendPath::Multisig, &[1, 2]);
+        }
+
+        #[test]
+        fn spends_from_a_2_of_3_wallet() {
+            let user_public_key = compress_public_key(&test_public_key(3));
+            let witness_script = test_witness_script(&[test_public_key(1), test_public_key(2), test_public_key(3)]);
+            let wallet = UserWallet {
+                address: Address::p2wsh(&witness_script, Network::Regtest),
+                witness_script,
+                derivation_path: vec![vec![1]],
+                user_public_key: Some(user_public_key),
+                recovery_delay: None,
+                policy: None,
+            };
+            test_spend_and_verify(&wallet, SpendPath::Multisig, &[1, 2]);
+            test_spend_and_verify(&wallet, SpendPath::Multisig, &[3, 1]);
+        }
+
+        #[test]
+        fn spends_from_a_recovery_wallet_through_both_paths() {
+            let wallet = test_recovery_wallet(Principal::from_slice(&[1]), 144);
+            test_spend_and_verify(&wallet, SpendPath::Multisig, &[1, 2]);
+            test_spend_and_verify(&wallet, SpendPath::Recovery, &[3]);
+        }
+
+        #[test]
+        fn spends_from_a_policy_wallet() {
+            let policy = String::from("or(99@thresh(2,pk(custody),pk(fiduciary)),1@and(pk(user),older(144)))");
+            let user_public_key = compress_public_key(&test_public_key(3));
+            let witness_script = build_wallet_witness_script(
+                &compress_public_key(&test_public_key(1)),
+                &compress_public_key(&test_public_key(2)),
+                &Some(user_public_key.clone()),
+                None,
+                &Some(policy.clone()),
+            ).unwrap();
+            let wallet = UserWallet {
+                address: Address::p2wsh(&witness_script, Network::Regtest),
+                witness_script,
+                derivation_path: vec![vec![1]],
+                user_public_key: Some(user_public_key),
+                recovery_delay: None,
+                policy: Some(policy),
+            };
+            test_spend_and_verify(&wallet, SpendPath::Multisig, &[1, 2]);
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,393 @@
+use bitcoin::blockdata::opcodes::all::{OP_CHECKMULTISIG, OP_CHECKSIG, OP_CSV, OP_DROP, OP_ELSE, OP_ENDIF, OP_IF};
+use bitcoin::blockdata::script::{Builder, Instruction};
+use bitcoin::{ecdsa, ScriptBuf, Sequence, Witness};
+use miniscript::policy::Concrete;
+use miniscript::{Miniscript, Segwitv0};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Name of the custody key in the wallet policies.
+pub const CUSTODY_KEY: &str = "custody";
+/// Name of the fiduciary key in the wallet policies.
+pub const FIDUCIARY_KEY: &str = "fiduciary";
+/// Name of the user key in the wallet policies.
+pub const USER_KEY: &str = "user";
+
+/// Miniscript of the 2-of-2 wallets.
+/// The default wallets are not compiled from a policy, so that their witness
+/// script stays the standard multisig one whatever the compiler picks.
+pub const MULTISIG_MINISCRIPT: &str = "multi(2,custody,fiduciary)";
+/// Miniscript of the 2-of-3 self-custody wallets.
+pub const SELF_CUSTODY_MINISCRIPT: &str = "multi(2,custody,fiduciary,user)";
+
+/// Returns the witness script of the wallets where the user key alone can spend the
+/// outputs that have not been moved for the given number of blocks:
+/// IF 2 <custody> <fiduciary> 2 CHECKMULTISIG ELSE <delay> CSV DROP <user> CHECKSIG ENDIF
+/// It is not a miniscript, but it is kept so that the addresses of the existing
+/// recovery wallets do not change.
+pub fn recovery_script(keys: &[(&str, Vec<u8>)], recovery_delay: u16) -> Result<ScriptBuf, String> {
+    let key = |name: &str| -> Result<bitcoin::PublicKey, String> {
+        let (_, key) = keys.iter().find(|(key_name, _)| *key_name == name)
+            .ok_or_else(|| format!("Missing the {} key.", name))?;
+        bitcoin::PublicKey::from_slice(key).map_err(|error| format!("Invalid public key for {}: {}", name, error))
+    };
+    Ok(build_recovery_script(&RecoveryScript {
+        multisig_keys: [key(CUSTODY_KEY)?, key(FIDUCIARY_KEY)?],
+        user_key: key(USER_KEY)?,
+        recovery_delay,
+    }))
+}
+
+// Keys and delay of a recovery witness script, see `recovery_script`.
+struct RecoveryScript {
+    multisig_keys: [bitcoin::PublicKey; 2],
+    user_key: bitcoin::PublicKey,
+    recovery_delay: u16,
+}
+
+// Build the recovery witness script with the given keys and delay.
+fn build_recovery_script(recovery_script: &RecoveryScript) -> ScriptBuf {
+    Builder::new()
+        .push_opcode(OP_IF)
+        .push_int(2)
+        .push_key(&recovery_script.multisig_keys[0])
+        .push_key(&recovery_script.multisig_keys[1])
+        .push_int(2)
+        .push_opcode(OP_CHECKMULTISIG)
+        .push_opcode(OP_ELSE)
+        .push_int(recovery_script.recovery_delay as i64)
+        .push_opcode(OP_CSV)
+        .push_opcode(OP_DROP)
+        .push_key(&recovery_script.user_key)
+        .push_opcode(OP_CHECKSIG)
+        .push_opcode(OP_ENDIF)
+        .into_script()
+}
+
+// Get the keys and delay of the given witness script if it is a recovery witness
+// script, i.e. if it is the one built again from them.
+fn parse_recovery_script(witness_script: &ScriptBuf) -> Option<RecoveryScript> {
+    let instructions: Vec<Instruction> = witness_script.instructions().collect::<Result<_, _>>().ok()?;
+    let public_keys: Vec<bitcoin::PublicKey> = instructions.iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::PushBytes(bytes) if bytes.len() == 33 => bitcoin::PublicKey::from_slice(bytes.as_bytes()).ok(),
+            _ => None,
+        })
+        .collect();
+    let recovery_delay = match instructions.get(7)? {
+        Instruction::PushBytes(bytes) => bitcoin::script::read_scriptint(bytes.as_bytes()).ok()?,
+        Instruction::Op(opcode) => match opcode.classify(bitcoin::opcodes::ClassifyContext::Legacy) {
+            bitcoin::opcodes::Class::PushNum(number) => number as i64,
+            _ => return None,
+        },
+    };
+    if public_keys.len() != 3 {
+        return None;
+    }
+    let recovery_script = RecoveryScript {
+        multisig_keys: [public_keys[0], public_keys[1]],
+        user_key: public_keys[2],
+        recovery_delay: u16::try_from(recovery_delay).ok()?,
+    };
+    match build_recovery_script(&recovery_script) == *witness_script {
+        true => Some(recovery_script),
+        false => None,
+    }
+}
+
+/// Compiles the given policy into a P2WSH witness script, where the names of the
+/// given keys are replaced by the keys themselves. The compiler may check a key
+/// against its hash (`pk_h`), but only its hash would then be in the witness script,
+/// which could not be parsed again: such keys are checked in full (`pk_k`) instead.
+pub fn compile_policy(policy: &str, keys: &[(&str, Vec<u8>)]) -> Result<ScriptBuf, String> {
+    let policy = Concrete::<bitcoin::PublicKey>::from_str(&substitute_keys(policy, keys)?)
+        .map_err(|error| format!("Invalid policy: {}", error))?;
+    let miniscript = policy
+        .compile::<Segwitv0>()
+        .map_err(|error| format!("Failed to compile the policy: {}", error))?;
+    let miniscript = without_key_hashes(&miniscript)?;
+    miniscript
+        .sanity_check()
+        .map_err(|error| format!("The compiled policy is not sane: {}", error))?;
+    Ok(miniscript.encode())
+}
+
+// Replace the key hash checks of the given miniscript by checks of the keys
+// themselves, i.e. `pkh` by `pk` and `pk_h` by `pk_k`, which have the same type.
+fn without_key_hashes(miniscript: &Miniscript<bitcoin::PublicKey, Segwitv0>) -> Result<Miniscript<bitcoin::PublicKey, Segwitv0>, String> {
+    let expression = miniscript.to_string().replace("pkh(", "pk(").replace("pk_h(", "pk_k(");
+    Miniscript::<bitcoin::PublicKey, Segwitv0>::from_str(&expression)
+        .map_err(|error| format!("Failed to check the keys of the compiled policy in full: {}", error))
+}
+
+/// Encodes the given miniscript into a P2WSH witness script, where the names of
+/// the given keys are replaced by the keys themselves.
+pub fn encode_miniscript(miniscript: &str, keys: &[(&str, Vec<u8>)]) -> Result<ScriptBuf, String> {
+    let miniscript = Miniscript::<bitcoin::PublicKey, Segwitv0>::from_str(&substitute_keys(miniscript, keys)?)
+        .map_err(|error| format!("Invalid miniscript: {}", error))?;
+    Ok(miniscript.encode())
+}
+
+// Replaces the names of the given keys by their hexadecimal encoding in the given
+// policy or miniscript expression.
+fn substitute_keys(expression: &str, keys: &[(&str, Vec<u8>)]) -> Result<String, String> {
+    let delimiters = expression
+        .match_indices(['(', ')', ','])
+        .chain(std::iter::once((expression.len(), "")));
+
+    let mut substituted = String::new();
+    let mut start = 0;
+    for (position, delimiter) in delimiters {
+        let token = &expression[start..position];
+        match keys.iter().find(|(name, _)| *name == token.trim()) {
+            Some((name, key)) => {
+                let key = bitcoin::PublicKey::from_slice(key)
+                    .map_err(|error| format!("Invalid public key for {}: {}", name, error))?;
+                substituted.push_str(&key.to_string());
+            },
+            None => substituted.push_str(token),
+        }
+        substituted.push_str(delimiter);
+        start = position + delimiter.len();
+    }
+    Ok(substituted)
+}
+
+// Parses the given P2WSH witness script as a miniscript.
+fn parse_witness_script(witness_script: &ScriptBuf) -> Result<Miniscript<bitcoin::PublicKey, Segwitv0>, String> {
+    Miniscript::<bitcoin::PublicKey, Segwitv0>::parse(witness_script)
+        .map_err(|error| format!("The witness script is not a miniscript: {}", error))
+}
+
+/// Returns the public keys of the given witness script, in the order they appear.
+pub fn public_keys(witness_script: &ScriptBuf) -> Result<Vec<bitcoin::PublicKey>, String> {
+    if let Some(recovery_script) = parse_recovery_script(witness_script) {
+        return Ok(vec![recovery_script.multisig_keys[0], recovery_script.multisig_keys[1], recovery_script.user_key]);
+    }
+    let mut public_keys: Vec<bitcoin::PublicKey> = vec![];
+    for public_key in parse_witness_script(witness_script)?.iter_pk() {
+        if !public_keys.contains(&public_key) {
+            public_keys.push(public_key);
+        }
+    }
+    Ok(public_keys)
+}
+
+/// Builds a placeholder of the largest witness satisfying the given witness script,
+/// with as many elements and at least as many bytes, followed by the witness script.
+/// The size of a transaction with these witnesses is an upper bound of its signed size.
+pub fn placeholder_satisfaction(witness_script: &ScriptBuf) -> Result<Witness, String> {
+    let (size, elements) = max_satisfaction(witness_script)?;
+    let mut witness = vec![vec![]; elements.saturating_sub(1)];
+    if elements > 0 {
+        // The remaining bytes go to the last element, with a length prefix of 1 byte
+        // below 253 bytes, or else 3 bytes.
+        let remaining = size.saturating_sub(elements - 1);
+        let length = match remaining.saturating_sub(1) {
+            length if length < 253 => length,
+            _ => remaining.saturating_sub(3).max(253),
+        };
+        witness.push(vec![255; length]);
+    }
+    witness.push(witness_script.to_bytes());
+    Ok(Witness::from_slice(&witness))
+}
+
+// Get the maximum size of the witness satisfying the given witness script, whatever
+// the spending path, and its maximum number of elements, both excluding the witness
+// script itself. The size of each element includes its length prefix.
+fn max_satisfaction(witness_script: &ScriptBuf) -> Result<(usize, usize), String> {
+    // The multisig path of a recovery witness script, with the empty element, both
+    // signatures and the element selecting the branch, is the largest one.
+    if parse_recovery_script(witness_script).is_some() {
+        return Ok((1 + 2 * 73 + 2, 4));
+    }
+    let miniscript = parse_witness_script(witness_script)?;
+    let size = miniscript
+        .max_satisfaction_size()
+        .map_err(|error| format!("The witness script cannot be satisfied: {}", error))?;
+    let elements = miniscript
+        .max_satisfaction_witness_elements()
+        .map_err(|error| format!("The witness script cannot be satisfied: {}", error))?;
+    // The number of elements includes the witness script.
+    Ok((size, elements.saturating_sub(1)))
+}
+
+/// Builds the witness satisfying the given witness script with the given signatures,
+/// for an input with the given sequence. The cheapest satisfaction is picked.
+pub fn satisfy(
+    witness_script: &ScriptBuf,
+    signatures: HashMap<bitcoin::PublicKey, ecdsa::Signature>,
+    sequence: Sequence,
+) -> Result<Witness, String> {
+    let mut witness = match parse_recovery_script(witness_script) {
+        Some(recovery_script) => satisfy_recovery_script(&recovery_script, &signatures, sequence)?,
+        None => parse_witness_script(witness_script)?
+            .satisfy((signatures, sequence))
+            .map_err(|error| format!("Failed to satisfy the witness script: {}", error))?,
+    };
+    witness.push(witness_script.to_bytes());
+    Ok(Witness::from_slice(&witness))
+}
+
+// Build the witness, without the witness script, satisfying the given recovery witness
+// script with the given signatures: the multisig path if both canisters signed, or else
+// the recovery path once the delay has elapsed.
+fn satisfy_recovery_script(
+    recovery_script: &RecoveryScript,
+    signatures: &HashMap<bitcoin::PublicKey, ecdsa::Signature>,
+    sequence: Sequence,
+) -> Result<Vec<Vec<u8>>, String> {
+    let multisig_signatures: Vec<&ecdsa::Signature> = recovery_script.multisig_keys.iter()
+        .filter_map(|public_key| signatures.get(public_key))
+        .collect();
+    if let [first_signature, last_signature] = multisig_signatures[..] {
+        return Ok(vec![vec![], first_signature.to_vec(), last_signature.to_vec(), vec![1]]);
+    }
+    let delay_elapsed = match sequence.to_relative_lock_time() {
+        Some(bitcoin::relative::LockTime::Blocks(height)) => height.value() >= recovery_script.recovery_delay,
+        _ => false,
+    };
+    match signatures.get(&recovery_script.user_key) {
+        Some(signature) if delay_elapsed => Ok(vec![signature.to_vec(), vec![]]),
+        _ => Err(String::from("Failed to satisfy the witness script: missing signatures or recovery delay not elapsed.")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+
+    fn public_key(seed: u8) -> bitcoin::PublicKey {
+        let secp = Secp256k1::new();
+        bitcoin::PublicKey::new(bitcoin::secp256k1::PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[seed; 32]).unwrap()))
+    }
+
+    fn signature(seed: u8) -> ecdsa::Signature {
+        let secp = Secp256k1::new();
+        let message = Message::from_digest_slice(&[7; 32]).unwrap();
+        ecdsa::Signature {
+            sig: secp.sign_ecdsa(&message, &SecretKey::from_slice(&[seed; 32]).unwrap()),
+            hash_ty: bitcoin::EcdsaSighashType::All,
+        }
+    }
+
+    fn keys() -> Vec<(&'static str, Vec<u8>)> {
+        vec![
+            (CUSTODY_KEY, public_key(1).to_bytes()),
+            (FIDUCIARY_KEY, public_key(2).to_bytes()),
+            (USER_KEY, public_key(3).to_bytes()),
+        ]
+    }
+
+    fn multisig_script() -> ScriptBuf {
+        Builder::new()
+            .push_int(2)
+            .push_key(&public_key(1))
+            .push_key(&public_key(2))
+            .push_int(2)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script()
+    }
+
+    #[test]
+    fn substitutes_the_names_of_the_keys_only() {
+        let substituted = substitute_keys("or(pk(custody),and(pk( user ),older(10)))", &keys()).unwrap();
+        assert_eq!(substituted, format!("or(pk({}),and(pk({}),older(10)))", public_key(1), public_key(3)));
+        assert_eq!(substitute_keys("pk(customer)", &keys()).unwrap(), "pk(customer)");
+    }
+
+    #[test]
+    fn rejects_an_invalid_key() {
+        assert!(substitute_keys("pk(user)", &[(USER_KEY, vec![5; 33])]).is_err());
+    }
+
+    #[test]
+    fn compiles_a_policy_with_all_the_keys() {
+        let witness_script = compile_policy("or(99@thresh(2,pk(custody),pk(fiduciary)),1@and(pk(user),older(144)))", &keys()).unwrap();
+        let public_keys = public_keys(&witness_script).unwrap();
+        for seed in 1..=3 {
+            assert!(public_keys.contains(&public_key(seed)));
+        }
+        assert!(max_satisfaction(&witness_script).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_policy_with_an_unknown_key() {
+        assert!(compile_policy("pk(nobody)", &keys()).is_err());
+    }
+
+    #[test]
+    fn satisfies_the_multisig_with_both_signatures() {
+        let witness_script = multisig_script();
+        let signatures = HashMap::from([(public_key(1), signature(1)), (public_key(2), signature(2))]);
+        let witness = satisfy(&witness_script, signatures, Sequence::MAX).unwrap();
+        let elements: Vec<Vec<u8>> = witness.iter().map(|element| element.to_vec()).collect();
+        assert_eq!(elements, vec![vec![], signature(1).to_vec(), signature(2).to_vec(), witness_script.to_bytes()]);
+    }
+
+    #[test]
+    fn cannot_satisfy_the_multisig_with_one_signature() {
+        let signatures = HashMap::from([(public_key(2), signature(2))]);
+        assert!(satisfy(&multisig_script(), signatures, Sequence::MAX).is_err());
+    }
+
+    #[test]
+    fn keeps_the_recovery_script() {
+        let witness_script = recovery_script(&keys(), 144).unwrap();
+        let expected = Builder::new()
+            .push_opcode(OP_IF)
+            .push_int(2)
+            .push_key(&public_key(1))
+            .push_key(&public_key(2))
+            .push_int(2)
+            .push_opcode(OP_CHECKMULTISIG)
+            .push_opcode(OP_ELSE)
+            .push_int(144)
+            .push_opcode(OP_CSV)
+            .push_opcode(OP_DROP)
+            .push_key(&public_key(3))
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_ENDIF)
+            .into_script();
+        assert_eq!(witness_script, expected);
+        assert_eq!(public_keys(&witness_script).unwrap(), vec![public_key(1), public_key(2), public_key(3)]);
+        assert_eq!(max_satisfaction(&witness_script).unwrap(), (149, 4));
+    }
+
+    #[test]
+    fn satisfies_the_recovery_script_with_both_signatures() {
+        let witness_script = recovery_script(&keys(), 144).unwrap();
+        let signatures = HashMap::from([(public_key(2), signature(2)), (public_key(1), signature(1))]);
+        let witness = satisfy(&witness_script, signatures, Sequence::MAX).unwrap();
+        let elements: Vec<Vec<u8>> = witness.iter().map(|element| element.to_vec()).collect();
+        assert_eq!(elements, vec![vec![], signature(1).to_vec(), signature(2).to_vec(), vec![1], witness_script.to_bytes()]);
+    }
+
+    #[test]
+    fn satisfies_the_recovery_script_with_the_user_key_after_the_delay() {
+        let witness_script = recovery_script(&keys(), 144).unwrap();
+        let signatures = HashMap::from([(public_key(3), signature(3))]);
+        assert!(satisfy(&witness_script, signatures.clone(), Sequence::MAX).is_err());
+        assert!(satisfy(&witness_script, signatures.clone(), Sequence::from_height(143)).is_err());
+        let witness = satisfy(&witness_script, signatures, Sequence::from_height(144)).unwrap();
+        let elements: Vec<Vec<u8>> = witness.iter().map(|element| element.to_vec()).collect();
+        assert_eq!(elements, vec![signature(3).to_vec(), vec![], witness_script.to_bytes()]);
+    }
+
+    #[test]
+    fn builds_a_placeholder_with_the_elements_of_the_largest_witness() {
+        let witness_script = multisig_script();
+        let signatures = HashMap::from([(public_key(1), signature(1)), (public_key(2), signature(2))]);
+        let witness = satisfy(&witness_script, signatures, Sequence::MAX).unwrap();
+        let placeholder = placeholder_satisfaction(&witness_script).unwrap();
+        assert_eq!(placeholder.len(), witness.len());
+        assert!(bitcoin::consensus::serialize(&placeholder).len() >= bitcoin::consensus::serialize(&witness).len());
+
+        let witness_script = recovery_script(&keys(), 144).unwrap();
+        let placeholder = placeholder_satisfaction(&witness_script).unwrap();
+        assert_eq!(placeholder.len(), 5);
+        assert_eq!(placeholder.last().unwrap(), witness_script.as_bytes());
+    }
+}
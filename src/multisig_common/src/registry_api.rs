@@ -0,0 +1,29 @@
+use crate::types::*;
+use candid::Principal;
+use ic_cdk::call;
+
+// The registry canister of the NNS.
+const REGISTRY_CANISTER_ID: &str = "rwlgt-iiaaa-aaaaa-aaaaa-cai";
+
+/// Returns the subnet of the given canister, or the reason why the registry
+/// failed to return it.
+///
+/// Relies on the `get_subnet_for_canister` endpoint of the registry canister.
+pub async fn get_subnet_for_canister(canister_id: Principal) -> Result<Principal, String> {
+    let registry = Principal::from_text(REGISTRY_CANISTER_ID).unwrap();
+    let res: Result<(Result<SubnetForCanister, String>,), _> = call(
+        registry,
+        "get_subnet_for_canister",
+        (GetSubnetForCanisterRequest {
+            principal: Some(canister_id),
+        },),
+    )
+    .await;
+
+    match res {
+        Ok((Ok(SubnetForCanister { subnet_id: Some(subnet_id) }),)) => Ok(subnet_id),
+        Ok((Ok(SubnetForCanister { subnet_id: None }),)) => Err(format!("No subnet found for the canister {}", canister_id)),
+        Ok((Err(message),)) => Err(format!("Failed to get the subnet of the canister {}: {}", canister_id, message)),
+        Err((code, message)) => Err(format!("Failed to get the subnet of the canister {} (code {:?}): {}", canister_id, code, message)),
+    }
+}
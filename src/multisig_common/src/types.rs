@@ -1,14 +1,17 @@
-use candid::{CandidType, Deserialize, Principal};
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
 use serde::Serialize;
+use std::collections::BTreeMap;
 pub use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
 
 #[derive(CandidType, Deserialize)]
 pub struct SendRequest {
     pub destination_address: String,
     pub amount_in_satoshi: u64,
+    pub export_psbt: Option<bool>,
+    pub shared_wallet: Option<SharedWalletId>,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
 pub struct ECDSAPublicKeyReply {
     pub public_key: Vec<u8>,
     pub chain_code: Vec<u8>,
@@ -38,6 +41,16 @@ pub struct ECDSAPublicKey {
     pub key_id: EcdsaKeyId,
 }
 
+#[derive(CandidType, Serialize, Debug)]
+pub struct GetSubnetForCanisterRequest {
+    pub principal: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SubnetForCanister {
+    pub subnet_id: Option<Principal>,
+}
+
 #[derive(CandidType, Serialize, Debug)]
 pub struct SignWithECDSA {
     pub message_hash: Vec<u8>,
@@ -45,9 +58,427 @@ pub struct SignWithECDSA {
     pub key_id: EcdsaKeyId,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
 pub struct RawTransactionInfo {
     pub transaction: Vec<u8>,
     pub witness_script: Vec<u8>,
     pub sig_hashes: Vec<Vec<u8>>,
+    pub input_amounts: Vec<u64>,
+    pub protocol_version: Option<u32>,
+    pub inputs: Option<Vec<RawInputInfo>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct RawInputInfo {
+    pub witness_script: Vec<u8>,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub amount: u64,
+    pub script_type: ScriptType,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptType {
+    P2wsh,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct WalletVerification {
+    pub address: String,
+    pub custody_key_name: String,
+    pub fiduciary_key_name: String,
+    pub custody_public_key: Vec<u8>,
+    pub fiduciary_public_key: Vec<u8>,
+    pub custody_key_matches: bool,
+    pub fiduciary_key_matches: bool,
+    pub witness_script_matches: bool,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct CacheMetrics {
+    pub fiduciary_key_hits: u64,
+    pub fiduciary_key_misses: u64,
+    pub fee_percentiles_hits: u64,
+    pub fee_percentiles_misses: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct InputSigningError {
+    pub input_index: u64,
+    pub message: String,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct SigningSession {
+    pub bitcoin_network: BitcoinNetwork,
+    pub owner: Principal,
+    pub raw_transaction_info: RawTransactionInfo,
+    pub signatures: Vec<Option<Vec<u8>>>,
+    pub export_psbt: bool,
+    pub requester: Option<Principal>,
+    pub proposal: Option<Proposal>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SendRequestReply {
+    pub request_id: u64,
+    pub raw_transaction_info: RawTransactionInfo,
+    pub psbt: Option<Vec<u8>>,
+    pub pending_approvals: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SendRequestError {
+    pub request_id: u64,
+    pub failed_inputs: Vec<InputSigningError>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct Inheritance {
+    pub heir: Principal,
+    pub inactivity_period_seconds: u64,
+    pub last_check_in: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SharedWalletId {
+    pub creator: Principal,
+    pub name: String,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct WalletDescriptor {
+    pub derivation_path: Vec<Vec<u8>>,
+    pub witness_script: Vec<u8>,
+    pub user_public_key: Option<Vec<u8>>,
+    pub recovery_delay: Option<u16>,
+    pub policy: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct SharedWalletDescriptor {
+    pub id: SharedWalletId,
+    pub wallet: WalletDescriptor,
+    pub members: Vec<Principal>,
+    pub threshold: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct WalletDescriptors {
+    pub key_name: String,
+    pub fiduciary_id: Principal,
+    pub user_wallets: Vec<(Principal, WalletDescriptor)>,
+    pub shared_wallets: Vec<SharedWalletDescriptor>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct Proposal {
+    pub wallet: SharedWalletId,
+    pub approvals: Vec<Principal>,
+    pub rejections: Vec<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SharedWalletInfo {
+    pub address: String,
+    pub members: Vec<Principal>,
+    pub threshold: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SendRequestProposal {
+    pub request_id: u64,
+    pub raw_transaction_info: RawTransactionInfo,
+    pub approvals: Vec<Principal>,
+    pub rejections: Vec<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct AllowedAddress {
+    pub address: String,
+    pub active_from: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct Allowlist {
+    pub cooling_off_period_seconds: u64,
+    pub addresses: Vec<AllowedAddress>,
+    pub disabled_from: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum AllowlistEventKind {
+    Enabled { cooling_off_period_seconds: u64 },
+    AddressAdded { address: String, active_from: u64 },
+    AddressRemoved { address: String },
+    Disabled { effective_from: u64 },
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct AllowlistEvent {
+    pub timestamp: u64,
+    pub owner: Principal,
+    pub kind: AllowlistEventKind,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct PauseState {
+    pub paused: bool,
+    pub paused_wallets: Vec<Vec<Vec<u8>>>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Operator,
+    Controller,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum AdminAction {
+    OperatorAdded { operator: Principal },
+    OperatorRemoved { operator: Principal },
+    WithdrawalsPaused,
+    WithdrawalsResumed,
+    WalletPaused { derivation_path: Vec<Vec<u8>>, paused: bool },
+    ConfigurationUpdated { bitcoin_network: BitcoinNetwork, key_name: String, fiduciary_id: Option<Principal>, max_amount_per_request: Option<u64> },
+    CustodyCanisterRegistered { canister_id: Principal, namespaced: Option<bool> },
+    CustodyCanisterUnregistered { canister_id: Principal },
+    PairingRequested { canister_id: Principal },
+    CustodyClientPolicyUpdated { canister_id: Principal, policy: ClientPolicy },
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub timestamp: u64,
+    pub principal: Principal,
+    pub role: Role,
+    pub action: AdminAction,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct AdminState {
+    pub operators: Vec<Principal>,
+    pub audit_log: Vec<AuditEntry>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct WalletSummary {
+    pub derivation_path: Vec<Vec<u8>>,
+    pub address: String,
+    pub paused: bool,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct KeyNameValidation {
+    pub bitcoin_network: BitcoinNetwork,
+    pub key_name: String,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct MainnetReadiness {
+    pub custody_subnet: Option<Principal>,
+    pub fiduciary_subnet: Option<Principal>,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct Pairing {
+    pub peer: Principal,
+    pub protocol_version: u32,
+    pub peer_protocol_version: Option<u32>,
+    pub negotiated_version: Option<u32>,
+    pub paired_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct PairingReply {
+    pub protocol_version: u32,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct ProtocolVersions {
+    pub min_version: u32,
+    pub max_version: u32,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum FiduciaryRequest {
+    PublicKey { bitcoin_network: BitcoinNetwork, derivation_path: Vec<Vec<u8>> },
+    EcdsaKeyName { bitcoin_network: BitcoinNetwork },
+    SignBatch { bitcoin_network: BitcoinNetwork, withdrawals: Vec<BatchedWithdrawal>, raw_transaction_info: RawTransactionInfo, settlements: Option<Vec<Settlement>> },
+    BookTransfer { bitcoin_network: BitcoinNetwork, from: Principal, to: Principal, amount: u64 },
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum FiduciaryResponse {
+    PublicKey { public_key: Vec<u8> },
+    EcdsaKeyName { key_name: String },
+    BatchSent { transaction_id: String },
+    TransferBooked,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct FiduciaryRequestEnvelope {
+    pub protocol_version: u32,
+    pub request: FiduciaryRequest,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct FiduciaryResponseEnvelope {
+    pub protocol_version: u32,
+    pub response: Result<FiduciaryResponse, String>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct ClientPolicy {
+    pub max_amount_per_transaction: Option<u64>,
+    pub daily_quota: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct ClientStats {
+    pub public_keys: u64,
+    pub signed_transactions: u64,
+    pub signed_amount: u64,
+    pub failed_transactions: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct CustodyClient {
+    pub canister_id: Principal,
+    pub namespaced: bool,
+    pub policy: ClientPolicy,
+    pub stats: ClientStats,
+    pub quota_day: u64,
+    pub quota_used: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct BatchedWithdrawal {
+    pub id: u64,
+    pub bitcoin_network: BitcoinNetwork,
+    pub owner: Principal,
+    pub destination_address: String,
+    pub amount: u64,
+    pub queued_at: u64,
+    pub status: WithdrawalStatus,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum WithdrawalStatus {
+    Queued,
+    Processing,
+    Sent { transaction_id: String, fee: u64 },
+    Failed { error: String },
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WithdrawalAuthorization {
+    pub custody_id: Principal,
+    pub bitcoin_network: BitcoinNetwork,
+    pub destination_address: String,
+    pub amount: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Settlement {
+    pub debtor: Principal,
+    pub creditor: Principal,
+    pub amount: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct LedgerEntry {
+    pub id: u64,
+    pub from: Principal,
+    pub to: Principal,
+    pub amount: u64,
+    pub timestamp: u64,
+    pub settlement_transaction_id: Option<String>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct Ledger {
+    pub adjustments: BTreeMap<Principal, i64>,
+    pub entries: Vec<LedgerEntry>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct LedgerBalance {
+    pub owner: Principal,
+    pub address: Option<String>,
+    pub onchain_balance: Option<u64>,
+    pub adjustment: i64,
+    pub balance: Option<u64>,
+    pub pending_withdrawals: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct LedgerReconciliation {
+    pub bitcoin_network: BitcoinNetwork,
+    pub timestamp: u64,
+    pub onchain_total: u64,
+    pub ledger_total: u64,
+    pub balances: Vec<LedgerBalance>,
+    pub errors: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransferAuthorization {
+    pub custody_id: Principal,
+    pub bitcoin_network: BitcoinNetwork,
+    pub to: Principal,
+    pub amount: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransferAllowance {
+    pub custody_id: Principal,
+    pub bitcoin_network: BitcoinNetwork,
+    pub amount: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
 }